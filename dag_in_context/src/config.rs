@@ -1,2 +1,16 @@
 #![allow(dead_code)]
 pub(crate) const FUNCTION_INLINING_ITERATIONS: usize = 2;
+/// With a profile, the most call sites inlined in one pass, hottest first.
+pub(crate) const PROFILED_INLINING_CALL_SITES: usize = 8;
+/// The most specialized copies made of one function in a single pass.
+pub(crate) const FUNCTION_SPECIALIZATIONS_PER_FUNCTION: usize = 4;
+/// The largest allocation, in cells, that scalar replacement turns into values.
+pub(crate) const SCALAR_REPLACEMENT_MAX_CELLS: i64 = 8;
+/// How many iterations of the inner loop each tile covers when tiling a loop nest.
+pub(crate) const LOOP_TILE_SIZE: i64 = 32;
+/// The stride, in elements, assumed for memory accesses whose stride isn't constant,
+/// such as walking down a column of a matrix.
+pub(crate) const UNKNOWN_ACCESS_STRIDE: i64 = 1024;
+/// How many values a loop body can keep in registers. Loops that use more
+/// arguments are split when they can be, see `loop_fusion::loop_splits`.
+pub(crate) const LOOP_REGISTERS: i64 = 16;
@@ -0,0 +1,226 @@
+//! Cost tables map egglog operator names to per-op costs.
+//! They are produced by calibrating against microbenchmarks
+//! (see `eggcc::calibrate`) and loaded by `TableCostModel`.
+//!
+//! The text format has one `<op> <cost>` pair per line.
+//! Blank lines and lines starting with `#` are ignored.
+
+use std::fmt::Display;
+
+use indexmap::IndexMap;
+
+use crate::{
+    greedy_dag_extractor::{Cost, CostModel, DefaultCostModel},
+    schema::Type,
+};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CostTable {
+    costs: IndexMap<String, f64>,
+}
+
+impl CostTable {
+    pub fn new() -> CostTable {
+        CostTable::default()
+    }
+
+    /// Sets the cost of `op`.
+    /// Panics if `op` is not an operator the default cost model knows about,
+    /// or if `cost` is negative or not finite.
+    pub fn insert(&mut self, op: &str, cost: f64) {
+        if let Err(err) = Self::check_entry(op, cost) {
+            panic!("{err}");
+        }
+        self.costs.insert(op.to_string(), cost);
+    }
+
+    pub fn get(&self, op: &str) -> Option<f64> {
+        self.costs.get(op).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, f64)> {
+        self.costs.iter().map(|(op, cost)| (op.as_str(), *cost))
+    }
+
+    /// Tables may only re-weight operators that are already extractable,
+    /// otherwise they would change which terms can be extracted at all.
+    fn check_entry(op: &str, cost: f64) -> Result<(), String> {
+        if DefaultCostModel.get_op_cost(op, None).is_infinite() {
+            return Err(format!("Unknown operator {op} in cost table"));
+        }
+        if !cost.is_finite() || cost < 0. {
+            return Err(format!(
+                "Invalid cost {cost} for operator {op} in cost table"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Builds a cost table from measured cycles per operation.
+    /// Measurements are scaled so that `reference_op` gets the same cost as in
+    /// `DefaultCostModel`. This keeps calibrated costs comparable with the costs
+    /// of operators missing from the table.
+    pub fn from_measurements(
+        measurements: &IndexMap<String, f64>,
+        reference_op: &str,
+    ) -> Result<CostTable, String> {
+        let reference_cycles = measurements
+            .get(reference_op)
+            .copied()
+            .ok_or_else(|| format!("Missing measurement for reference operator {reference_op}"))?;
+        if reference_cycles.is_nan() || reference_cycles <= 0. {
+            return Err(format!(
+                "Reference operator {reference_op} must take a positive number of cycles, \
+                 found {reference_cycles}"
+            ));
+        }
+        let scale = DefaultCostModel
+            .get_op_cost(reference_op, None)
+            .into_inner()
+            / reference_cycles;
+
+        let mut table = CostTable::new();
+        for (op, cycles) in measurements {
+            // noise can make cheap operators look like they take negative time
+            let cost = (cycles * scale).max(0.);
+            Self::check_entry(op, cost)?;
+            table.costs.insert(op.clone(), cost);
+        }
+        Ok(table)
+    }
+
+    pub fn parse(input: &str) -> Result<CostTable, String> {
+        let mut table = CostTable::new();
+        for (line_num, line) in input.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let [op, cost] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+                return Err(format!(
+                    "Expected `<op> <cost>` on line {} of cost table, found `{line}`",
+                    line_num + 1
+                ));
+            };
+            let cost = cost.parse::<f64>().map_err(|err| {
+                format!(
+                    "Could not parse cost `{cost}` on line {} of cost table: {err}",
+                    line_num + 1
+                )
+            })?;
+            Self::check_entry(op, cost)?;
+            table.costs.insert(op.to_string(), cost);
+        }
+        Ok(table)
+    }
+
+    pub fn load(path: &std::path::Path) -> Result<CostTable, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| format!("Could not read cost table {}: {err}", path.display()))?;
+        CostTable::parse(&contents)
+    }
+}
+
+impl Display for CostTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (op, cost) in self.iter() {
+            writeln!(f, "{op} {cost}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A cost model that looks up operator costs in a `CostTable`,
+/// falling back to `DefaultCostModel` for operators missing from the table.
+/// Tables are calibrated on one type per operator, so operators whose
+/// default cost depends on the type they produce (like float `Select`)
+/// also fall back to `DefaultCostModel` for the other types.
+#[derive(Clone, Debug)]
+pub struct TableCostModel {
+    pub table: CostTable,
+}
+
+impl CostModel for TableCostModel {
+    fn get_op_cost(&self, op: &str, ty: Option<&Type>) -> Cost {
+        let default_cost = DefaultCostModel.get_op_cost(op, ty);
+        if default_cost != DefaultCostModel.get_op_cost(op, None) {
+            return default_cost;
+        }
+        match self.table.get(op) {
+            Some(cost) => cost.try_into().unwrap(),
+            None => default_cost,
+        }
+    }
+
+    fn ignore_children(&self, op: &str) -> bool {
+        DefaultCostModel.ignore_children(op)
+    }
+
+    fn access_stride_cost(&self, stride: i64) -> Cost {
+        DefaultCostModel.access_stride_cost(stride)
+    }
+
+    fn loop_live_values_cost(&self, live_values: i64) -> Cost {
+        DefaultCostModel.loop_live_values_cost(live_values)
+    }
+}
+
+#[test]
+fn test_cost_table_roundtrip() {
+    let mut table = CostTable::new();
+    table.insert("Mul", 12.5);
+    table.insert("FDiv", 80.);
+    let parsed = CostTable::parse(&format!("# calibrated\n\n{table}")).unwrap();
+    assert_eq!(parsed, table);
+
+    let cost_model = TableCostModel { table: parsed };
+    assert_eq!(cost_model.get_op_cost("Mul", None).into_inner(), 12.5);
+    assert_eq!(
+        cost_model.get_op_cost("Add", None),
+        DefaultCostModel.get_op_cost("Add", None)
+    );
+    assert!(cost_model.get_op_cost("NotAnOp", None).is_infinite());
+}
+
+#[test]
+fn test_cost_table_keeps_type_aware_costs() {
+    use crate::schema::BaseType;
+
+    let mut table = CostTable::new();
+    table.insert("Select", 3.);
+    let cost_model = TableCostModel { table };
+    let int_ty = Type::Base(BaseType::IntT);
+    let float_ty = Type::Base(BaseType::FloatT);
+    assert_eq!(
+        cost_model.get_op_cost("Select", Some(&int_ty)).into_inner(),
+        3.
+    );
+    assert_eq!(
+        cost_model.get_op_cost("Select", Some(&float_ty)),
+        DefaultCostModel.get_op_cost("Select", Some(&float_ty))
+    );
+}
+
+#[test]
+fn test_cost_table_from_measurements() {
+    let measurements = IndexMap::from([
+        ("Add".to_string(), 0.5),
+        ("Mul".to_string(), 1.5),
+        ("Eq".to_string(), -0.1),
+    ]);
+    let table = CostTable::from_measurements(&measurements, "Add").unwrap();
+    let add_cost = DefaultCostModel.get_op_cost("Add", None).into_inner();
+    assert_eq!(table.get("Add"), Some(add_cost));
+    assert_eq!(table.get("Mul"), Some(add_cost * 3.));
+    assert_eq!(table.get("Eq"), Some(0.));
+
+    assert!(CostTable::from_measurements(&measurements, "Sub").is_err());
+}
+
+#[test]
+fn test_cost_table_rejects_bad_entries() {
+    assert!(CostTable::parse("Mul").is_err());
+    assert!(CostTable::parse("Mul ten").is_err());
+    assert!(CostTable::parse("Mul -1").is_err());
+    assert!(CostTable::parse("NotAnOp 1").is_err());
+}
@@ -0,0 +1,596 @@
+//! An exact extractor, used to improve on the greedy extractor's result.
+//! The greedy extractor picks the cheapest e-node for each e-class bottom-up,
+//! which can be suboptimal when subterms are shared.
+//! This extractor does a branch-and-bound search over which e-node to pick
+//! for every (region, e-class) pair reachable from the function, computing
+//! the exact cost of each choice with sharing taken into account.
+//! The greedy result is the initial bound, and after a time limit
+//! the best solution found so far is returned.
+
+use std::time::{Duration, Instant};
+
+use egglog::Term;
+use egraph_serialize::{ClassId, NodeId};
+use indexmap::{IndexMap, IndexSet};
+use ordered_float::NotNan;
+use rpds::HashTrieMap;
+
+use crate::{
+    greedy_dag_extractor::{
+        enode_children, node_is_extractable, Cost, CostSet, EgraphInfo, EnodeChild, Extractor,
+    },
+    schema::RcExpr,
+};
+
+type RootId = ClassId;
+
+/// Maps (region, eclass) pairs to the node picked for them.
+type Choice = IndexMap<(RootId, ClassId), NodeId>;
+
+/// Running state while evaluating the cost of a choice.
+#[derive(Default)]
+struct Evaluation {
+    /// Total cost of each region that has been evaluated
+    region_costs: IndexMap<RootId, Cost>,
+    /// Regions currently being evaluated, used to find cycles between regions
+    regions_in_progress: IndexSet<RootId>,
+    /// Pairs that have only one extractable node, so there is nothing to decide
+    forced: Vec<((RootId, ClassId), NodeId)>,
+    /// The first reachable pair that has not been decided yet
+    undecided: Option<(RootId, ClassId)>,
+}
+
+struct ExactSearch<'a, 'b, 'c> {
+    extractor: &'b mut Extractor<'a>,
+    info: &'b EgraphInfo<'c>,
+    effectful_paths: Option<&'b IndexMap<ClassId, IndexSet<NodeId>>>,
+    /// Nodes allowed for each pair, in the order they are tried
+    candidates: IndexMap<(RootId, ClassId), Vec<NodeId>>,
+}
+
+impl<'a, 'b, 'c> ExactSearch<'a, 'b, 'c> {
+    /// The nodes that can be picked for `classid` in region `rootid`.
+    /// The node the greedy extractor picked comes first, so the search
+    /// finds the greedy solution first. The rest are sorted by cost.
+    fn candidates(&mut self, rootid: &RootId, classid: &ClassId) -> &Vec<NodeId> {
+        let info = self.info;
+        let key = (rootid.clone(), classid.clone());
+        if !self.candidates.contains_key(&key) {
+            let greedy_choice = self.extractor.chosen_node(rootid, classid);
+            let mut nodes = vec![];
+            for nodeid in info.egraph.classes()[classid].nodes.iter() {
+                if info.node_cost(nodeid).is_infinite() {
+                    continue;
+                }
+                if node_is_extractable(self.extractor, info, rootid, nodeid, self.effectful_paths) {
+                    nodes.push(nodeid.clone());
+                }
+            }
+            nodes.sort_by_key(|nodeid| {
+                (
+                    Some(nodeid) != greedy_choice.as_ref(),
+                    info.node_cost(nodeid),
+                )
+            });
+            self.candidates.insert(key.clone(), nodes);
+        }
+        &self.candidates[&key]
+    }
+
+    /// Computes the cost of everything reachable from `classid` in region `rootid`
+    /// that has not been visited yet. Undecided pairs are counted as free, so
+    /// for a partial choice this is a lower bound on the cost.
+    /// Returns None if the choice contains a cycle or can't be completed.
+    fn class_cost(
+        &mut self,
+        choice: &Choice,
+        eval: &mut Evaluation,
+        rootid: &RootId,
+        classid: &ClassId,
+        visited: &mut IndexSet<ClassId>,
+        on_path: &mut IndexSet<ClassId>,
+    ) -> Option<Cost> {
+        let info = self.info;
+        if visited.contains(classid) {
+            return Some(NotNan::new(0.).unwrap());
+        }
+        if !on_path.insert(classid.clone()) {
+            // cycle within the region
+            return None;
+        }
+
+        let key = (rootid.clone(), classid.clone());
+        let nodeid = match choice.get(&key) {
+            Some(nodeid) => nodeid.clone(),
+            None => {
+                let candidates = self.candidates(rootid, classid);
+                match candidates.as_slice() {
+                    [] => return None,
+                    [only] => {
+                        let only = only.clone();
+                        eval.forced.push((key, only.clone()));
+                        only
+                    }
+                    _ => {
+                        if eval.undecided.is_none() {
+                            eval.undecided = Some(key);
+                        }
+                        on_path.swap_remove(classid);
+                        return Some(NotNan::new(0.).unwrap());
+                    }
+                }
+            }
+        };
+
+        let node = &info.egraph[&nodeid];
+        let mut cost = info.node_cost(&nodeid);
+        if !info.cm.ignore_children(&node.op) {
+            for EnodeChild {
+                child,
+                is_subregion,
+                is_assumption,
+            } in enode_children(info.egraph, node)
+            {
+                if is_assumption {
+                    continue;
+                }
+                if is_subregion {
+                    let region_cost = self.region_cost(choice, eval, &child)?;
+                    cost += info.subregion_cost(&nodeid, region_cost);
+                } else {
+                    cost += self.class_cost(choice, eval, rootid, &child, visited, on_path)?;
+                }
+            }
+        }
+
+        on_path.swap_remove(classid);
+        visited.insert(classid.clone());
+        Some(cost)
+    }
+
+    /// Computes the cost of the region rooted at `rootid`.
+    fn region_cost(
+        &mut self,
+        choice: &Choice,
+        eval: &mut Evaluation,
+        rootid: &RootId,
+    ) -> Option<Cost> {
+        if let Some(cost) = eval.region_costs.get(rootid) {
+            return Some(*cost);
+        }
+        if !eval.regions_in_progress.insert(rootid.clone()) {
+            // cycle between regions
+            return None;
+        }
+        let cost = self.class_cost(
+            choice,
+            eval,
+            rootid,
+            rootid,
+            &mut IndexSet::new(),
+            &mut IndexSet::new(),
+        )?;
+        eval.regions_in_progress.swap_remove(rootid);
+        eval.region_costs.insert(rootid.clone(), cost);
+        Some(cost)
+    }
+
+    /// Builds the term for `classid` in region `rootid` picked by `choice`.
+    fn build_term(
+        &mut self,
+        choice: &Choice,
+        rootid: &RootId,
+        classid: &ClassId,
+        memo: &mut IndexMap<(RootId, ClassId), Term>,
+    ) -> Term {
+        let info = self.info;
+        let key = (rootid.clone(), classid.clone());
+        if let Some(term) = memo.get(&key) {
+            return term.clone();
+        }
+        let nodeid = choice[&key].clone();
+        let node = &info.egraph[&nodeid];
+        let mut children = vec![];
+        if !info.cm.ignore_children(&node.op) {
+            for EnodeChild {
+                child,
+                is_subregion,
+                is_assumption,
+            } in enode_children(info.egraph, node)
+            {
+                let child_term = if is_assumption {
+                    self.extractor.get_dummy_context_term(info, child)
+                } else if is_subregion {
+                    self.build_term(choice, &child, &child, memo)
+                } else {
+                    self.build_term(choice, rootid, &child, memo)
+                };
+                children.push(child_term);
+            }
+        }
+        let term = self.extractor.get_term(info, nodeid, children);
+        memo.insert(key, term.clone());
+        term
+    }
+
+    /// Whether the program picked by the complete choice `choice` is linear.
+    fn is_linear(&mut self, choice: &Choice, func: &str, func_root: &RootId) -> bool {
+        let term = self.build_term(choice, func_root, func_root, &mut IndexMap::new());
+        let res = self.extractor.convert_term_to_expr(self.info, term);
+        match self.extractor.check_function_is_linear(&res) {
+            Ok(()) => true,
+            Err(err) => {
+                log::info!("Skipping a non-linear extraction of {}. {}", func, err);
+                false
+            }
+        }
+    }
+
+    /// The node chosen for every (region root, eclass) pair in the extracted term,
+    /// along with the total cost of the chosen term, like `Extractor::chosen_nodes`.
+    /// `terms` are the terms built by `build_term`.
+    fn chosen_nodes(
+        &mut self,
+        choice: &Choice,
+        terms: &IndexMap<(RootId, ClassId), Term>,
+    ) -> IndexMap<(RootId, ClassId), (NodeId, Cost)> {
+        let mut eval = Evaluation::default();
+        let mut chosen = IndexMap::new();
+        for (rootid, classid) in terms.keys() {
+            let cost = self
+                .class_cost(
+                    choice,
+                    &mut eval,
+                    rootid,
+                    classid,
+                    &mut IndexSet::new(),
+                    &mut IndexSet::new(),
+                )
+                .expect("Best choice should have a cost");
+            let nodeid = choice[&(rootid.clone(), classid.clone())].clone();
+            chosen.insert((rootid.clone(), classid.clone()), (nodeid, cost));
+        }
+        chosen
+    }
+
+    /// The chosen term and its cost (excluding children in the same region)
+    /// for each class in region `rootid`, like `CostSet::costs`.
+    /// `terms` are the terms built by `build_term`.
+    fn costs_in_region(
+        &mut self,
+        choice: &Choice,
+        rootid: &RootId,
+        terms: &IndexMap<(RootId, ClassId), Term>,
+    ) -> HashTrieMap<ClassId, (Term, Cost)> {
+        let info = self.info;
+        let mut eval = Evaluation::default();
+        self.region_cost(choice, &mut eval, rootid)
+            .expect("Best choice should have a cost");
+
+        let mut costs = HashTrieMap::default();
+        for ((term_root, classid), term) in terms {
+            if term_root != rootid {
+                continue;
+            }
+            let nodeid = &choice[&(term_root.clone(), classid.clone())];
+            let node = &info.egraph[nodeid];
+            let mut cost = info.node_cost(nodeid);
+            if !info.cm.ignore_children(&node.op) {
+                for EnodeChild {
+                    child,
+                    is_subregion,
+                    ..
+                } in enode_children(info.egraph, node)
+                {
+                    if is_subregion {
+                        cost += info.subregion_cost(nodeid, eval.region_costs[&child]);
+                    }
+                }
+            }
+            // like the greedy extractor, leave out classes that cost nothing
+            if cost > NotNan::new(0.).unwrap() {
+                costs.insert_mut(classid.clone(), (term.clone(), cost));
+            }
+        }
+        costs
+    }
+}
+
+/// A decision point in the search: the pair being decided,
+/// the nodes to try for it, and the next node to try.
+struct Frame {
+    pair: (RootId, ClassId),
+    candidates: Vec<NodeId>,
+    next: usize,
+    /// Length of the choice before this decision, to undo it when backtracking
+    choice_len: usize,
+}
+
+/// A program found by exact extraction, along with the node chosen for
+/// every (region root, eclass) pair in it (see `Extractor::chosen_nodes`).
+pub(crate) type ExactExtraction = (CostSet, RcExpr, IndexMap<(RootId, ClassId), (NodeId, Cost)>);
+
+/// Searches for a cheaper extraction of `func` than the greedy extractor's result,
+/// which costs `greedy_cost`.
+/// Uses the same restrictions on e-nodes as the greedy extractor (see `node_is_extractable`).
+/// Gives up after `time_limit` and returns the best program found so far.
+/// When `should_maintain_linearity` is set, only linear programs are kept,
+/// so a cheaper program that isn't linear doesn't hide a linear one.
+/// Returns None when nothing cheaper than the greedy result is found.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn extract_exact(
+    func: &str,
+    func_root: ClassId,
+    extractor: &mut Extractor,
+    info: &EgraphInfo,
+    effectful_paths: Option<&IndexMap<ClassId, IndexSet<NodeId>>>,
+    should_maintain_linearity: bool,
+    greedy_cost: Cost,
+    time_limit: Duration,
+) -> Option<ExactExtraction> {
+    log::info!("Searching for an exact extraction of {}.", func);
+    let deadline = Instant::now() + time_limit;
+    let mut search = ExactSearch {
+        extractor,
+        info,
+        effectful_paths,
+        candidates: Default::default(),
+    };
+
+    let mut best: Option<Choice> = None;
+    let mut best_cost = greedy_cost;
+    let mut choice = Choice::default();
+    let mut stack: Vec<Frame> = vec![];
+    let mut timed_out = false;
+    let mut num_explored: usize = 0;
+
+    loop {
+        if Instant::now() > deadline {
+            timed_out = true;
+            break;
+        }
+        num_explored += 1;
+
+        let mut eval = Evaluation::default();
+        let cost = search.region_cost(&choice, &mut eval, &func_root);
+        let undecided = eval.undecided.take();
+        choice.extend(eval.forced.drain(..));
+
+        match (cost, undecided) {
+            // the choice has a cycle, or it is already worse than the best
+            (None, _) => (),
+            (Some(cost), _) if cost >= best_cost => (),
+            // a complete choice that breaks linearity can't be used
+            (Some(_cost), None)
+                if should_maintain_linearity && !search.is_linear(&choice, func, &func_root) => {}
+            (Some(cost), None) => {
+                best_cost = cost;
+                best = Some(choice.clone());
+            }
+            (Some(_cost), Some(pair)) => {
+                let candidates = search.candidates(&pair.0, &pair.1).clone();
+                stack.push(Frame {
+                    pair,
+                    candidates,
+                    next: 0,
+                    choice_len: choice.len(),
+                });
+            }
+        }
+
+        // backtrack to the next untried candidate
+        let mut found_next = false;
+        while let Some(frame) = stack.last_mut() {
+            choice.truncate(frame.choice_len);
+            if frame.next < frame.candidates.len() {
+                choice.insert(frame.pair.clone(), frame.candidates[frame.next].clone());
+                frame.next += 1;
+                found_next = true;
+                break;
+            }
+            stack.pop();
+        }
+        if !found_next {
+            break;
+        }
+    }
+
+    log::info!(
+        "Exact extraction of {} explored {} choices{}.",
+        func,
+        num_explored,
+        if timed_out { " before timing out" } else { "" }
+    );
+
+    // when best is None, the greedy result is the best we found
+    let best = best?;
+
+    let mut terms = IndexMap::new();
+    let term = search.build_term(&best, &func_root, &func_root, &mut terms);
+    let res = search.extractor.convert_term_to_expr(info, term.clone());
+
+    let costs = search.costs_in_region(&best, &func_root, &terms);
+    let chosen = search.chosen_nodes(&best, &terms);
+    Some((
+        CostSet {
+            total: best_cost,
+            costs,
+            term,
+        },
+        res,
+        chosen,
+    ))
+}
+
+/// The cost of the program that picks the nodes in `chosen`,
+/// such as one found by `Extractor::chosen_nodes_in_term`,
+/// with the node costs of `info`.
+/// Returns None if the choice has a cycle or can't be completed.
+pub(crate) fn choice_cost(
+    extractor: &mut Extractor,
+    info: &EgraphInfo,
+    func_root: &ClassId,
+    chosen: &IndexMap<(RootId, ClassId), (NodeId, Cost)>,
+) -> Option<Cost> {
+    let choice = chosen
+        .iter()
+        .map(|(pair, (nodeid, _cost))| (pair.clone(), nodeid.clone()))
+        .collect::<Choice>();
+    let mut search = ExactSearch {
+        extractor,
+        info,
+        effectful_paths: None,
+        candidates: Default::default(),
+    };
+    search.region_cost(&choice, &mut Evaluation::default(), func_root)
+}
+
+#[test]
+fn test_exact_extraction_uses_sharing() {
+    use crate::ast::*;
+    use crate::greedy_dag_extractor::{extract, serialized_egraph, CostModel, TestCostModel};
+    use crate::{print_with_intermediate_vars, prologue};
+
+    let arg_ty = tuplet!(intt(), statet());
+    let shared = div(getat(0), int(3)).with_arg_types(arg_ty.clone(), base(intt()));
+    let cheap_alone = mul(getat(0), getat(0)).with_arg_types(arg_ty.clone(), base(intt()));
+    let cheap_with_sharing =
+        add(shared.clone(), getat(0)).with_arg_types(arg_ty.clone(), base(intt()));
+    let uses_shared = sub(shared, getat(0)).with_arg_types(arg_ty.clone(), base(intt()));
+
+    let decl = format!(
+        "(let cheap-alone {})
+         (let cheap-with-sharing {})
+         (union cheap-alone cheap-with-sharing)",
+        cheap_alone, cheap_with_sharing,
+    );
+
+    let prog = program!(function(
+        "main",
+        arg_ty.clone(),
+        tuplet!(intt(), intt(), statet()),
+        parallel!(cheap_alone, uses_shared, getat(1))
+    ),);
+
+    let string_prog = {
+        let (term, termdag) = prog.to_egglog();
+        let printed = print_with_intermediate_vars(&termdag, term);
+        format!("{}\n{}\n{}", prologue(), decl, printed)
+    };
+
+    let mut egraph = egglog::EGraph::default();
+    egraph.parse_and_run_program(None, &string_prog).unwrap();
+    let (serialized_egraph, unextractables) = serialized_egraph(egraph);
+
+    // greedy picks the Mul, since it is cheaper on its own
+    let (greedy_cost, _, _) = extract(
+        &prog,
+        prog.fns(),
+        serialized_egraph.clone(),
+        unextractables.clone(),
+        &mut egglog::TermDag::default(),
+        TestCostModel,
+        true,
+        false,
+        None,
+    );
+    // the exact extractor picks the Add, sharing the Div with the Sub
+    let (exact_cost, _, _) = extract(
+        &prog,
+        prog.fns(),
+        serialized_egraph,
+        unextractables,
+        &mut egglog::TermDag::default(),
+        TestCostModel,
+        true,
+        false,
+        Some(Duration::from_secs(10)),
+    );
+
+    let mul_cost = TestCostModel.get_op_cost("Mul", None);
+    let add_cost = TestCostModel.get_op_cost("Add", None);
+    let sub_cost = TestCostModel.get_op_cost("Sub", None);
+    let div_cost =
+        TestCostModel.get_op_cost("Div", None) + TestCostModel.get_op_cost("Const", None);
+    assert_eq!(greedy_cost, mul_cost + sub_cost + div_cost);
+    assert_eq!(exact_cost, add_cost + sub_cost + div_cost);
+}
+
+#[test]
+fn test_exact_extraction_skips_non_linear() {
+    use crate::ast::*;
+    use crate::greedy_dag_extractor::{extract_with_paths, get_root, serialized_egraph};
+    use crate::greedy_dag_extractor::{EgraphInfo, Extractor, SizeCostModel};
+    use crate::{print_with_intermediate_vars, prologue};
+
+    let arg_ty = tuplet!(intt(), statet());
+    let int_expr = |e: RcExpr| e.with_arg_types(arg_ty.clone(), base(intt()));
+    let shared = int_expr(div(getat(0), int(3)));
+    let uses_shared = int_expr(sub(shared.clone(), getat(0)));
+    // greedy picks the Muls, since they are cheaper on their own
+    let cheap_alone = int_expr(mul(mul(getat(0), getat(0)), getat(0)));
+    let cheap_with_sharing = int_expr(add(shared.clone(), getat(0)));
+    let linear_alone = int_expr(mul(mul(mul(getat(0), getat(0)), getat(0)), getat(0)));
+    // shares the Div too, but consumes the state without returning it
+    let non_linear = int_expr(tif(
+        ttrue(),
+        parallel!(shared, getat(1)),
+        getat(0),
+        getat(0),
+    ));
+
+    let decl = format!(
+        "(let cheap-alone {cheap_alone})
+         (let cheap-with-sharing {cheap_with_sharing})
+         (union cheap-alone cheap-with-sharing)
+         (let linear-alone {linear_alone})
+         (let non-linear {non_linear})
+         (union linear-alone non-linear)",
+    );
+
+    let prog = program!(function(
+        "main",
+        arg_ty.clone(),
+        tuplet!(intt(), intt(), intt(), statet()),
+        parallel!(cheap_alone, uses_shared, linear_alone, getat(1))
+    ),);
+
+    let string_prog = {
+        let (term, termdag) = prog.to_egglog();
+        let printed = print_with_intermediate_vars(&termdag, term);
+        format!("{}\n{}\n{}", prologue(), decl, printed)
+    };
+
+    let mut egraph = egglog::EGraph::default();
+    egraph.parse_and_run_program(None, &string_prog).unwrap();
+    let (serialized_egraph, unextractables) = serialized_egraph(egraph);
+
+    let root = serialized_egraph
+        .nid_to_cid(&get_root(&serialized_egraph, "main"))
+        .clone();
+    let info = EgraphInfo::new(
+        "main",
+        root.clone(),
+        &SizeCostModel,
+        &serialized_egraph,
+        unextractables,
+    );
+    let mut termdag = egglog::TermDag::default();
+    let mut extractor = Extractor::new(&prog, &mut termdag);
+    let (greedy, _) = extract_with_paths("main", root.clone(), &mut extractor, &info, None);
+
+    // the cheapest program uses the If, but the Add is still cheaper than greedy
+    let (exact, res, _) = extract_exact(
+        "main",
+        root,
+        &mut extractor,
+        &info,
+        None,
+        true,
+        greedy.total,
+        Duration::from_secs(10),
+    )
+    .expect("A linear program is cheaper than greedy");
+    assert!(exact.total < greedy.total);
+    assert!(extractor.check_function_is_linear(&res).is_ok());
+}
@@ -7,12 +7,16 @@ use std::{
     collections::{HashSet, VecDeque},
     f64::INFINITY,
     rc::Rc,
+    time::Duration,
 };
 use strum::IntoEnumIterator;
 
 use crate::{
+    config,
+    exact_extractor::{choice_cost, extract_exact},
     from_egglog::FromEgglog,
-    schema::{Expr, RcExpr, TreeProgram, Type},
+    linearity::LinearityReport,
+    schema::{BaseType, Expr, RcExpr, TreeProgram, Type},
     schema_helpers::Sort,
     typechecker::TypeChecker,
 };
@@ -28,13 +32,25 @@ pub(crate) struct EgraphInfo<'a> {
     pub(crate) roots: Vec<(RootId, NodeId)>,
     pub(crate) cm: &'a dyn CostModel,
     /// Optionally, a loop with (inputs, outputs) can have an estimated number of iterations.
-    /// This is found by looking at LoopNumItersGuess in the database.
+    /// This is found by looking at LoopNumItersGuess in the database,
+    /// or LoopProfiledIters when the program was profiled.
     pub(crate) loop_iteration_estimates: IndexMap<(RootId, RootId), i64>,
+    /// For loops with (inputs, outputs) in a loop nest version, how many loads
+    /// and writes in the body move by each stride, from LoopAccessStrides.
+    pub(crate) loop_access_strides: IndexMap<(RootId, RootId), Vec<(i64, i64)>>,
+    /// For loops with (inputs, outputs) that were split or come from splitting a loop,
+    /// how many arguments the body uses, from LoopLiveValues.
+    pub(crate) loop_live_values: IndexMap<(RootId, RootId), i64>,
     /// A set of names of functions that are unextractable
-    unextractables: IndexSet<String>,
+    pub(crate) unextractables: IndexSet<String>,
     /// A set of (func args) of calls that have been inlined, to indicate we shouldn't
     /// extract the corresponding (Call func args).
-    inlined_calls: IndexSet<(ClassId, ClassId)>,
+    pub(crate) inlined_calls: IndexSet<(ClassId, ClassId)>,
+    /// The type of each Expr eclass, found by looking at HasType in the database.
+    eclass_types: IndexMap<ClassId, Type>,
+    /// Factors that the costs of some nodes are multiplied by.
+    /// Used by `extract_top_k` to steer extraction away from earlier candidates.
+    pub(crate) node_penalties: IndexMap<NodeId, f64>,
 }
 
 pub(crate) struct Extractor<'a> {
@@ -70,9 +86,89 @@ impl<'a> EgraphInfo<'a> {
         self.egraph.nid_to_cid(nid).clone()
     }
 
-    fn get_loop_iteration_estimates(egraph: &EGraph) -> IndexMap<(ClassId, ClassId), i64> {
-        // for every eclass that represents a single i64 in the egraph,
-        // map the eclass to that integer
+    /// The cost of a node, not including its children.
+    pub(crate) fn node_cost(&self, nodeid: &NodeId) -> Cost {
+        let node = &self.egraph[nodeid];
+
+        // special case: when the call is recursive, set super high cost
+        if node.op == "Call" {
+            let func_name = &node.children[0];
+            let func_name_str = &self.egraph[func_name].op;
+            assert!(func_name_str.starts_with('\"') && func_name_str.ends_with('\"'));
+            let func_name_str_without_quotes = &func_name_str[1..func_name_str.len() - 1];
+            if func_name_str_without_quotes == self._func {
+                return NotNan::new(100000000000.0).unwrap();
+            }
+        }
+
+        let ty = self.eclass_types.get(self.egraph.nid_to_cid(nodeid));
+        let cost = match node.op.as_str() {
+            // Operators are e-classes of their own, without a type,
+            // so the part of their cost that depends on the type
+            // is charged to the expression that applies them.
+            "Bop" | "Uop" | "Top" => {
+                let op = &self.egraph[&node.children[0]].op;
+                let untyped = self.cm.get_op_cost(op, None);
+                let typed = self.cm.get_op_cost(op, ty);
+                let surcharge = if untyped.is_finite() {
+                    typed - untyped
+                } else {
+                    typed
+                };
+                self.cm.get_op_cost(&node.op, ty) + surcharge
+            }
+            _ => self.cm.get_op_cost(&node.op, ty),
+        };
+        match self.node_penalties.get(nodeid) {
+            Some(penalty) => cost * *penalty,
+            None => cost,
+        }
+    }
+
+    /// Get the cost of a subregion of `nodeid`, given the total cost of the subregion.
+    /// For DoWhile nodes, use special logic to calculate the cost based on iteration count
+    pub(crate) fn subregion_cost(&self, nodeid: &NodeId, region_total: Cost) -> Cost {
+        let node = self.egraph.nodes.get(nodeid).unwrap();
+
+        if node.op == "DoWhile" {
+            let inputs = self.egraph.nid_to_cid(&node.children[0]);
+            let outputs = self.egraph.nid_to_cid(&node.children[1]);
+
+            let loop_num_iters_guess = self
+                .loop_iteration_estimates
+                .get(&(inputs.clone(), outputs.clone()))
+                .cloned()
+                .unwrap_or(1000);
+
+            let stride_cost = self
+                .loop_access_strides
+                .get(&(inputs.clone(), outputs.clone()))
+                .into_iter()
+                .flatten()
+                .map(|(stride, count)| {
+                    self.cm.access_stride_cost(*stride) * NotNan::new(*count as f64).unwrap()
+                })
+                .sum::<Cost>();
+
+            let live_values_cost = self
+                .loop_live_values
+                .get(&(inputs.clone(), outputs.clone()))
+                .map_or(NotNan::new(0.).unwrap(), |live_values| {
+                    self.cm.loop_live_values_cost(*live_values)
+                });
+
+            self.cm.loop_body_cost(
+                region_total + stride_cost + live_values_cost,
+                loop_num_iters_guess,
+            )
+        } else {
+            region_total
+        }
+    }
+
+    /// For every eclass that represents a single i64 in the egraph,
+    /// map the eclass to that integer
+    fn get_integers(egraph: &EGraph) -> IndexMap<ClassId, i64> {
         let mut integers: IndexMap<ClassId, i64> = IndexMap::default();
         for (nodeid, node) in &egraph.nodes {
             if let Ok(integer) = node.op.parse::<i64>() {
@@ -80,29 +176,160 @@ impl<'a> EgraphInfo<'a> {
                 integers.insert(eclass.clone(), integer);
             }
         }
+        integers
+    }
+
+    fn get_loop_iteration_estimates(egraph: &EGraph) -> IndexMap<(ClassId, ClassId), i64> {
+        let integers = Self::get_integers(egraph);
 
         let mut loop_iteration_estimates = IndexMap::default();
+        let mut profiled_iterations = IndexMap::default();
 
-        // loop over all nodes, finding LoopNumItersGuess nodes
+        // loop over all nodes, finding LoopNumItersGuess and LoopProfiledIters nodes
         for (_nodeid, node) in &egraph.nodes {
-            if node.op == "LoopNumItersGuess" {
-                // assert it has two children
+            let estimates = match node.op.as_str() {
+                "LoopNumItersGuess" => &mut loop_iteration_estimates,
+                "LoopProfiledIters" => &mut profiled_iterations,
+                _ => continue,
+            };
+            // assert it has two children
+            assert_eq!(
+                node.children.len(),
+                2,
+                "{} node has wrong number of children. Node: {:?}",
+                node.op,
+                node
+            );
+            estimates.insert(
+                (
+                    egraph.nid_to_cid(&node.children[0]).clone(),
+                    egraph.nid_to_cid(&node.children[1]).clone(),
+                ),
+                integers[&node.eclass],
+            );
+        }
+
+        // iteration counts observed when profiling take precedence over guesses
+        loop_iteration_estimates.extend(profiled_iterations);
+        loop_iteration_estimates
+    }
+
+    fn get_loop_access_strides(egraph: &EGraph) -> IndexMap<(ClassId, ClassId), Vec<(i64, i64)>> {
+        let integers = Self::get_integers(egraph);
+
+        let mut loop_access_strides: IndexMap<_, Vec<_>> = IndexMap::default();
+        for (_nodeid, node) in &egraph.nodes {
+            if node.op != "LoopAccessStrides" {
+                continue;
+            }
+            let [inputs, outputs, stride] = node.children.as_slice() else {
+                panic!("LoopAccessStrides node has wrong number of children. Node: {node:?}");
+            };
+            loop_access_strides
+                .entry((
+                    egraph.nid_to_cid(inputs).clone(),
+                    egraph.nid_to_cid(outputs).clone(),
+                ))
+                .or_default()
+                .push((integers[egraph.nid_to_cid(stride)], integers[&node.eclass]));
+        }
+        loop_access_strides
+    }
+
+    fn get_loop_live_values(egraph: &EGraph) -> IndexMap<(ClassId, ClassId), i64> {
+        let integers = Self::get_integers(egraph);
+
+        let mut loop_live_values = IndexMap::default();
+        for (_nodeid, node) in &egraph.nodes {
+            if node.op != "LoopLiveValues" {
+                continue;
+            }
+            let [inputs, outputs] = node.children.as_slice() else {
+                panic!("LoopLiveValues node has wrong number of children. Node: {node:?}");
+            };
+            loop_live_values.insert(
+                (
+                    egraph.nid_to_cid(inputs).clone(),
+                    egraph.nid_to_cid(outputs).clone(),
+                ),
+                integers[&node.eclass],
+            );
+        }
+        loop_live_values
+    }
+
+    /// Reads a base type out of an eclass of sort BaseType.
+    fn base_type_of_class(egraph: &EGraph, class: &ClassId) -> Option<BaseType> {
+        egraph.classes()[class].nodes.iter().find_map(|nodeid| {
+            let node = &egraph[nodeid];
+            match (node.op.as_str(), node.children.as_slice()) {
+                ("IntT", []) => Some(BaseType::IntT),
+                ("FloatT", []) => Some(BaseType::FloatT),
+                ("BoolT", []) => Some(BaseType::BoolT),
+                ("StateT", []) => Some(BaseType::StateT),
+                ("PointerT", [inner]) => Some(BaseType::PointerT(Box::new(
+                    Self::base_type_of_class(egraph, egraph.nid_to_cid(inner))?,
+                ))),
+                _ => None,
+            }
+        })
+    }
+
+    /// Reads a list of base types out of an eclass of sort TypeList.
+    fn type_list_of_class(egraph: &EGraph, class: &ClassId) -> Option<Vec<BaseType>> {
+        egraph.classes()[class].nodes.iter().find_map(|nodeid| {
+            let node = &egraph[nodeid];
+            match (node.op.as_str(), node.children.as_slice()) {
+                ("TNil", []) => Some(vec![]),
+                ("TCons", [head, tail]) => {
+                    let mut res = vec![Self::base_type_of_class(egraph, egraph.nid_to_cid(head))?];
+                    res.extend(Self::type_list_of_class(egraph, egraph.nid_to_cid(tail))?);
+                    Some(res)
+                }
+                _ => None,
+            }
+        })
+    }
+
+    /// Reads a type out of an eclass of sort Type.
+    fn type_of_class(egraph: &EGraph, class: &ClassId) -> Option<Type> {
+        egraph.classes()[class].nodes.iter().find_map(|nodeid| {
+            let node = &egraph[nodeid];
+            match (node.op.as_str(), node.children.as_slice()) {
+                ("Base", [base]) => Some(Type::Base(Self::base_type_of_class(
+                    egraph,
+                    egraph.nid_to_cid(base),
+                )?)),
+                ("TupleT", [list]) => Some(Type::TupleT(Self::type_list_of_class(
+                    egraph,
+                    egraph.nid_to_cid(list),
+                )?)),
+                _ => None,
+            }
+        })
+    }
+
+    fn get_eclass_types(egraph: &EGraph) -> IndexMap<ClassId, Type> {
+        let mut eclass_types = IndexMap::default();
+
+        // loop over all nodes, finding HasType nodes
+        for (_nodeid, node) in &egraph.nodes {
+            if node.op == "HasType" {
                 assert_eq!(
                     node.children.len(),
                     2,
-                    "LoopNumItersGuess node has wrong number of children. Node: {:?}",
+                    "HasType node has wrong number of children. Node: {:?}",
                     node
                 );
-                loop_iteration_estimates.insert(
-                    (
-                        egraph.nid_to_cid(&node.children[0]).clone(),
-                        egraph.nid_to_cid(&node.children[1]).clone(),
-                    ),
-                    integers[&node.eclass],
-                );
+                let expr_class = egraph.nid_to_cid(&node.children[0]);
+                let type_class = egraph.nid_to_cid(&node.children[1]);
+                if let Some(ty) = Self::type_of_class(egraph, type_class) {
+                    eclass_types.insert(expr_class.clone(), ty);
+                }
             }
         }
-        loop_iteration_estimates
+
+        eclass_types
     }
 
     fn get_inlined_calls(egraph: &EGraph) -> IndexSet<(ClassId, ClassId)> {
@@ -135,7 +362,10 @@ impl<'a> EgraphInfo<'a> {
         unextractables: IndexSet<String>,
     ) -> Self {
         let loop_iteration_estimates = Self::get_loop_iteration_estimates(egraph);
+        let loop_access_strides = Self::get_loop_access_strides(egraph);
+        let loop_live_values = Self::get_loop_live_values(egraph);
         let inlined_calls = Self::get_inlined_calls(egraph);
+        let eclass_types = Self::get_eclass_types(egraph);
 
         // get all the roots needed
         let mut region_roots = find_reachable(egraph, func_root.clone(), cm, false, true);
@@ -185,7 +415,7 @@ impl<'a> EgraphInfo<'a> {
                 let node = &egraph[enode];
 
                 // skip nodes with infinite cost
-                if cm.get_op_cost(&node.op).is_infinite() {
+                if cm.get_op_cost(&node.op, None).is_infinite() {
                     continue;
                 }
 
@@ -231,7 +461,11 @@ impl<'a> EgraphInfo<'a> {
             parents: parents_sorted,
             roots,
             loop_iteration_estimates,
+            loop_access_strides,
+            loop_live_values,
             inlined_calls,
+            eclass_types,
+            node_penalties: Default::default(),
         }
     }
 }
@@ -273,7 +507,7 @@ impl<'a> Extractor<'a> {
 
     /// Convert the extracted terms to expressions, and also
     /// store their types.
-    fn convert_term_to_expr(&mut self, info: &EgraphInfo, prog: Term) -> RcExpr {
+    pub(crate) fn convert_term_to_expr(&mut self, info: &EgraphInfo, prog: Term) -> RcExpr {
         let mut converter = FromEgglog {
             termdag: self.termdag,
             conversion_cache: Default::default(),
@@ -304,6 +538,70 @@ impl<'a> Extractor<'a> {
         converted_prog
     }
 
+    /// The node picked for `classid` in region `rootid` by the last extraction, if any.
+    pub(crate) fn chosen_node(&self, rootid: &ClassId, classid: &ClassId) -> Option<NodeId> {
+        let index = self.costs.get(rootid)?.get(classid)?;
+        Some(self.term_node(&self.costsets[*index].term))
+    }
+
+    /// The node chosen for every (region root, eclass) pair extracted so far,
+    /// along with the total cost of the chosen term.
+    pub(crate) fn chosen_nodes(&self) -> IndexMap<(ClassId, ClassId), (NodeId, Cost)> {
+        self.costs
+            .iter()
+            .flat_map(|(rootid, classes)| {
+                classes.iter().map(move |(classid, index)| {
+                    let costset = &self.costsets[*index];
+                    (
+                        (rootid.clone(), classid.clone()),
+                        (self.term_node(&costset.term), costset.total),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Like `chosen_nodes`, but only the pairs whose region and chosen node
+    /// are both used by `term`, so pairs the extraction explored but didn't emit are left out.
+    pub(crate) fn chosen_nodes_in_term(
+        &self,
+        term: &Term,
+    ) -> IndexMap<(ClassId, ClassId), (NodeId, Cost)> {
+        let nodes = self.nodes_in_term(term);
+        self.chosen_nodes()
+            .into_iter()
+            .filter(|((rootid, _classid), (nodeid, _cost))| {
+                nodes.contains(nodeid)
+                    && self
+                        .chosen_node(rootid, rootid)
+                        .is_some_and(|root_node| nodes.contains(&root_node))
+            })
+            .collect()
+    }
+
+    /// All the nodes used by `term` and its subterms.
+    pub(crate) fn nodes_in_term(&self, term: &Term) -> IndexSet<NodeId> {
+        let mut nodes = IndexSet::new();
+        let mut seen = IndexSet::new();
+        let mut todo = vec![term.clone()];
+        while let Some(term) = todo.pop() {
+            if !seen.insert(term.clone()) {
+                continue;
+            }
+            if let Some(node) = self.correspondence.get(&term) {
+                nodes.insert(node.clone());
+            }
+            if let Term::App(_head, children) = &term {
+                todo.extend(
+                    children
+                        .iter()
+                        .map(|child| self.termdag.get(*child).clone()),
+                );
+            }
+        }
+        nodes
+    }
+
     pub(crate) fn term_node(&self, term: &Term) -> NodeId {
         self.correspondence
             .get(term)
@@ -311,6 +609,12 @@ impl<'a> Extractor<'a> {
             .clone()
     }
 
+    /// The term for a dummy context node of `class_id`, see `get_dummy_context`.
+    pub(crate) fn get_dummy_context_term(&mut self, info: &EgraphInfo, class_id: ClassId) -> Term {
+        let index = self.get_dummy_context(info, class_id);
+        self.costsets[index].term.clone()
+    }
+
     /// A method for getting a dummy context nodes.
     /// Contexts create cycles, but we don't need to extract them, so we invent an imaginary term here.
     pub(crate) fn get_dummy_context(
@@ -400,7 +704,7 @@ pub fn serialized_egraph(
     (egraph, get_unextractables(&egglog_egraph))
 }
 
-type Cost = NotNan<f64>;
+pub(crate) type Cost = NotNan<f64>;
 type CostSetIndex = usize;
 
 #[derive(Clone, Debug)]
@@ -418,7 +722,12 @@ impl<'a> Extractor<'a> {
     /// Construct a term for this operator with subterms from the cost sets
     /// We also need to add this term to the correspondence map so we can
     /// find its enode id later.
-    fn get_term(&mut self, info: &EgraphInfo, node_id: NodeId, children: Vec<Term>) -> Term {
+    pub(crate) fn get_term(
+        &mut self,
+        info: &EgraphInfo,
+        node_id: NodeId,
+        children: Vec<Term>,
+    ) -> Term {
         let node = &info.egraph[&node_id];
         let op = &node.op;
         let term = if children.is_empty() {
@@ -514,27 +823,6 @@ impl<'a> Extractor<'a> {
         }
     }
 
-    // Get the cost of a subregion
-    // For DoWhile nodes, use special logic to calculate the cost based on iteration count
-    fn subregion_cost(&self, info: &EgraphInfo, nodeid: NodeId, child_set: &CostSet) -> Cost {
-        let node = info.egraph.nodes.get(&nodeid).unwrap();
-
-        if node.op == "DoWhile" {
-            let inputs = info.egraph.nid_to_cid(&node.children[0]);
-            let outputs = info.egraph.nid_to_cid(&node.children[1]);
-
-            let loop_num_iters_guess = info
-                .loop_iteration_estimates
-                .get(&(inputs.clone(), outputs.clone()))
-                .cloned()
-                .unwrap_or(1000);
-
-            child_set.total * NotNan::new(loop_num_iters_guess as f64).unwrap()
-        } else {
-            child_set.total
-        }
-    }
-
     /// Given a node and cost sets for children, calculate the cost set for the node.
     /// This function is cached so that we don't re-calculate cost sets.
     /// If a cycle is detected, we return None.
@@ -571,18 +859,7 @@ impl<'a> Extractor<'a> {
         }
 
         let mut shared_total = NotNan::new(0.).unwrap();
-        let mut unshared_total = info.cm.get_op_cost(&node.op);
-
-        // special case: when the call is recursive, set super high cost
-        if node.op == "Call" {
-            let func_name = &node.children[0];
-            let func_name_str = &info.egraph[func_name].op;
-            assert!(func_name_str.starts_with('\"') && func_name_str.ends_with('\"'));
-            let func_name_str_without_quotes = &func_name_str[1..func_name_str.len() - 1];
-            if func_name_str_without_quotes == info._func {
-                unshared_total = NotNan::new(100000000000.0).unwrap();
-            }
-        }
+        let mut unshared_total = info.node_cost(&nodeid);
 
         let mut costs: HashTrieMap<ClassId, (Term, Cost)> = Default::default();
         let index_of_biggest_child = child_cost_sets
@@ -617,7 +894,7 @@ impl<'a> Extractor<'a> {
             for (index, (child_set, is_region_root)) in child_cost_sets.iter().enumerate() {
                 if *is_region_root {
                     children_terms.push(child_set.term.clone());
-                    unshared_total += self.subregion_cost(info, nodeid.clone(), child_set);
+                    unshared_total += info.subregion_cost(&nodeid, child_set.total);
                 } else {
                     // costs is empty, replace it with the child one
                     if Some(index) == index_of_biggest_child {
@@ -704,6 +981,15 @@ fn node_cost_in_region(
     extractor.calculate_cost_set(node_id, child_cost_sets, info)
 }
 
+/// The result of extracting a single function.
+struct ExtractedFn {
+    cost: CostSet,
+    expr: RcExpr,
+    linearity_report: LinearityReport,
+    /// The e-nodes used by the extracted term
+    nodes: IndexSet<NodeId>,
+}
+
 #[allow(clippy::too_many_arguments)]
 fn extract_fn(
     original_prog: &TreeProgram,
@@ -714,9 +1000,13 @@ fn extract_fn(
     termdag: &mut TermDag,
     cost_model: &impl CostModel,
     should_maintain_linearity: bool,
-) -> (CostSet, RcExpr) {
+    exact_time_limit: Option<Duration>,
+    node_penalties: &IndexMap<NodeId, f64>,
+) -> ExtractedFn {
     log::info!("Building extraction info");
-    let egraph_info = EgraphInfo::new(func, rootid.clone(), cost_model, &egraph, unextractables);
+    let mut egraph_info =
+        EgraphInfo::new(func, rootid.clone(), cost_model, &egraph, unextractables);
+    egraph_info.node_penalties = node_penalties.clone();
     let extractor_not_linear = &mut Extractor::new(original_prog, termdag);
 
     let (cost_res, res) = extract_with_paths(
@@ -727,22 +1017,112 @@ fn extract_fn(
         None,
     );
 
-    if !should_maintain_linearity {
-        (cost_res, res)
+    let extracted = if !should_maintain_linearity {
+        let exact = exact_time_limit.and_then(|time_limit| {
+            extract_exact(
+                func,
+                rootid.clone(),
+                extractor_not_linear,
+                &egraph_info,
+                None,
+                false,
+                cost_res.total,
+                time_limit,
+            )
+        });
+        let (cost_res, res) = match exact {
+            Some((cost_res, res, _chosen)) => (cost_res, res),
+            None => (cost_res, res),
+        };
+        ExtractedFn {
+            nodes: extractor_not_linear.nodes_in_term(&cost_res.term),
+            cost: cost_res,
+            expr: res,
+            linearity_report: LinearityReport::new(func),
+        }
     } else {
         let effectful_nodes_along_path =
             extractor_not_linear.find_effectful_nodes_in_function(&res, &egraph_info);
+        let non_linear_choices = extractor_not_linear.chosen_nodes();
         extractor_not_linear.costs.clear();
         let (cost_res, res) = extract_with_paths(
             func,
-            rootid,
+            rootid.clone(),
             extractor_not_linear,
             &egraph_info,
             Some(&effectful_nodes_along_path),
         );
-        extractor_not_linear.check_function_is_linear(&res).unwrap();
+        if let Err(err) = extractor_not_linear.check_function_is_linear(&res) {
+            let linear_choices = extractor_not_linear.chosen_nodes_in_term(&cost_res.term);
+            let report = extractor_not_linear.linearity_report(
+                func,
+                &egraph_info,
+                &non_linear_choices,
+                &linear_choices,
+                &effectful_nodes_along_path,
+            );
+            panic!("{err}\n{report}");
+        }
+
+        let exact = exact_time_limit.and_then(|time_limit| {
+            extract_exact(
+                func,
+                rootid.clone(),
+                extractor_not_linear,
+                &egraph_info,
+                Some(&effectful_nodes_along_path),
+                true,
+                cost_res.total,
+                time_limit,
+            )
+        });
+        // report on the program that is actually emitted
+        let (cost_res, res, linear_choices) = match exact {
+            Some(exact) => exact,
+            None => {
+                let linear_choices = extractor_not_linear.chosen_nodes_in_term(&cost_res.term);
+                (cost_res, res, linear_choices)
+            }
+        };
+        let report = extractor_not_linear.linearity_report(
+            func,
+            &egraph_info,
+            &non_linear_choices,
+            &linear_choices,
+            &effectful_nodes_along_path,
+        );
+        if !report.is_empty() {
+            log::info!("{report}");
+        }
 
-        (cost_res, res)
+        ExtractedFn {
+            nodes: extractor_not_linear.nodes_in_term(&cost_res.term),
+            cost: cost_res,
+            expr: res,
+            linearity_report: report,
+        }
+    };
+
+    // Penalties only steer extraction, so report the cost without them
+    if node_penalties.is_empty() {
+        return extracted;
+    }
+    let unpenalized_info = EgraphInfo::new(
+        func,
+        rootid.clone(),
+        cost_model,
+        &egraph,
+        egraph_info.unextractables.clone(),
+    );
+    let chosen = extractor_not_linear.chosen_nodes_in_term(&extracted.cost.term);
+    let total = choice_cost(extractor_not_linear, &unpenalized_info, &rootid, &chosen)
+        .expect("Extracted program should have a cost");
+    ExtractedFn {
+        cost: CostSet {
+            total,
+            ..extracted.cost
+        },
+        ..extracted
     }
 }
 
@@ -765,6 +1145,9 @@ fn find_debug_roots(egraph: egraph_serialize::EGraph) -> Vec<(ClassId, String)>
 /// Inputs: a program, serialized egraph, and a set of functions to extract.
 /// Also needs to know a set of unextractable functions and a cost model.
 /// Produces a new program with the functions specified replaced by their extracted versions.
+/// If `exact_time_limit` is given, the greedy result for each function is improved
+/// by the exact extractor (see exact_extractor.rs), spending at most that long per function.
+/// Also returns a report for each function of the e-nodes that linear extraction skipped.
 #[allow(clippy::too_many_arguments)]
 pub fn extract(
     original_prog: &TreeProgram,
@@ -775,25 +1158,152 @@ pub fn extract(
     cost_model: impl CostModel,
     should_maintain_linearity: bool,
     extract_debug_exprs: bool,
-) -> (Cost, TreeProgram) {
+    exact_time_limit: Option<Duration>,
+) -> (Cost, TreeProgram, Vec<LinearityReport>) {
+    let (fn_costs, prog, reports) = extract_with_fn_costs(
+        original_prog,
+        fns,
+        egraph,
+        unextractables,
+        termdag,
+        cost_model,
+        should_maintain_linearity,
+        extract_debug_exprs,
+        exact_time_limit,
+    );
+    (total_cost(&fn_costs), prog, reports)
+}
+
+/// Like `extract`, but returns the cost of each extracted function
+/// instead of the total cost.
+#[allow(clippy::too_many_arguments)]
+pub fn extract_with_fn_costs(
+    original_prog: &TreeProgram,
+    fns: Vec<String>,
+    egraph: egraph_serialize::EGraph,
+    unextractables: IndexSet<String>,
+    termdag: &mut TermDag,
+    cost_model: impl CostModel,
+    should_maintain_linearity: bool,
+    extract_debug_exprs: bool,
+    exact_time_limit: Option<Duration>,
+) -> (IndexMap<String, Cost>, TreeProgram, Vec<LinearityReport>) {
+    let (fn_costs, prog, reports, _nodes) = extract_with_penalties(
+        original_prog,
+        fns,
+        egraph,
+        unextractables,
+        termdag,
+        &cost_model,
+        should_maintain_linearity,
+        extract_debug_exprs,
+        exact_time_limit,
+        &IndexMap::new(),
+    );
+    (fn_costs, prog, reports)
+}
+
+fn total_cost(fn_costs: &IndexMap<String, Cost>) -> Cost {
+    fn_costs
+        .values()
+        .fold(NotNan::new(0.).unwrap(), |total, cost| total + *cost)
+}
+
+/// How much the cost of an e-node is multiplied by each time
+/// an earlier candidate of `extract_top_k` used it.
+const TOP_K_PENALTY: f64 = 2.0;
+
+/// Extracts up to `k` distinct low-cost programs from the egraph.
+/// The first candidate is the program `extract` would return.
+/// Each later candidate is extracted after penalizing the e-nodes
+/// used by the earlier candidates, steering extraction towards different programs.
+/// The cost of each candidate is computed without the penalties,
+/// so the candidates' costs can be compared.
+#[allow(clippy::too_many_arguments)]
+pub fn extract_top_k(
+    original_prog: &TreeProgram,
+    fns: Vec<String>,
+    egraph: egraph_serialize::EGraph,
+    unextractables: IndexSet<String>,
+    termdag: &mut TermDag,
+    cost_model: impl CostModel,
+    should_maintain_linearity: bool,
+    k: usize,
+) -> Vec<(Cost, TreeProgram)> {
+    let mut node_penalties: IndexMap<NodeId, f64> = IndexMap::new();
+    let mut candidates: Vec<(Cost, TreeProgram)> = vec![];
+    for i in 0..k {
+        let (fn_costs, prog, _reports, nodes) = extract_with_penalties(
+            original_prog,
+            fns.clone(),
+            egraph.clone(),
+            unextractables.clone(),
+            termdag,
+            &cost_model,
+            should_maintain_linearity,
+            false,
+            None,
+            &node_penalties,
+        );
+        for node in nodes {
+            *node_penalties.entry(node).or_insert(1.) *= TOP_K_PENALTY;
+        }
+        if candidates.iter().any(|(_cost, existing)| existing == &prog) {
+            log::info!("Top-k extraction round {i} found a duplicate candidate");
+        } else {
+            candidates.push((total_cost(&fn_costs), prog));
+        }
+    }
+    candidates
+}
+
+/// Like `extract_with_fn_costs`, but multiplies the costs of the nodes in `node_penalties`.
+/// Also returns all the nodes used by the extracted functions.
+#[allow(clippy::too_many_arguments)]
+fn extract_with_penalties(
+    original_prog: &TreeProgram,
+    fns: Vec<String>,
+    egraph: egraph_serialize::EGraph,
+    unextractables: IndexSet<String>,
+    termdag: &mut TermDag,
+    cost_model: &impl CostModel,
+    should_maintain_linearity: bool,
+    extract_debug_exprs: bool,
+    exact_time_limit: Option<Duration>,
+    node_penalties: &IndexMap<NodeId, f64>,
+) -> (
+    IndexMap<String, Cost>,
+    TreeProgram,
+    Vec<LinearityReport>,
+    IndexSet<NodeId>,
+) {
     if extract_debug_exprs {
         log::info!("Extracting debug expressions.");
         let debug_roots = find_debug_roots(egraph.clone());
         let mut extracted_fns = vec![];
-        let mut total_cost = NotNan::new(0.).unwrap();
+        let mut fn_costs = IndexMap::new();
+        let mut nodes = IndexSet::new();
         let mut typechecker = TypeChecker::new(original_prog, true);
         for (root, name) in debug_roots {
-            let (cost, extracted) = extract_fn(
+            let ExtractedFn {
+                cost,
+                expr: extracted,
+                nodes: fn_nodes,
+                ..
+            } = extract_fn(
                 original_prog,
                 &name,
                 root,
                 egraph.clone(),
                 unextractables.clone(),
                 termdag,
-                &cost_model,
+                cost_model,
                 false,
+                exact_time_limit,
+                node_penalties,
             );
-            total_cost += cost.total;
+            fn_costs.insert(name.clone(), cost.total);
+            nodes.extend(fn_nodes);
             let output_ty = typechecker
                 .add_arg_types_to_expr(extracted.clone(), &None)
                 .0;
@@ -807,28 +1317,85 @@ pub fn extract(
             entry: extracted_fns[0].clone(),
             functions: extracted_fns[1..].to_vec(),
         };
-        (total_cost, new_prog)
+        (fn_costs, new_prog, vec![], nodes)
     } else {
         let mut new_prog = original_prog.clone();
-        let mut cost = NotNan::new(0.).unwrap();
+        let mut fn_costs = IndexMap::new();
+        let mut reports = vec![];
+        let mut nodes = IndexSet::new();
         for func in fns {
-            let (fn_cost, extracted) = extract_fn(
+            let extracted = extract_fn(
                 &new_prog,
                 &func,
                 egraph.nid_to_cid(&get_root(&egraph, &func)).clone(),
                 egraph.clone(),
                 unextractables.clone(),
                 termdag,
-                &cost_model,
+                cost_model,
                 should_maintain_linearity,
+                exact_time_limit,
+                node_penalties,
             );
-            new_prog.replace_fn(&func, extracted);
-            cost += fn_cost.total;
+            new_prog.replace_fn(&func, extracted.expr);
+            fn_costs.insert(func, extracted.cost.total);
+            reports.push(extracted.linearity_report);
+            nodes.extend(extracted.nodes);
         }
-        (cost, new_prog)
+        (fn_costs, new_prog, reports, nodes)
     }
 }
 
+/// Checks if `nodeid` can be extracted in the region `rootid`.
+/// If effectful paths are present, effectful nodes
+/// are only extractable if they are in effectful_path[rootid].
+pub(crate) fn node_is_extractable(
+    extractor: &mut Extractor,
+    info: &EgraphInfo,
+    rootid: &ClassId,
+    nodeid: &NodeId,
+    effectful_paths: Option<&IndexMap<ClassId, IndexSet<NodeId>>>,
+) -> bool {
+    let classid = info.n2c(nodeid);
+    let node = info.egraph.nodes.get(nodeid).unwrap();
+    if info.unextractables.contains(&node.op) {
+        return false;
+    }
+
+    // Skip inlined calls
+    if node.op == "Call"
+        && info.inlined_calls.contains(&(
+            info.n2c(&node.children[0]).clone(),
+            info.n2c(&node.children[1]).clone(),
+        ))
+    {
+        return false;
+    }
+
+    let sort_of_node = info.get_sort_of_eclass(&classid);
+    // if node is effectful, we only consider it if it is in the effectful path
+    if sort_of_node == "Expr" && effectful_paths.is_some() {
+        let effectful_lookup = extractor.is_eclass_effectful(classid.clone());
+        if effectful_lookup.is_none() && node.op != "Function" {
+            // skip when type is unknown
+            return false;
+        }
+        if let Some(true) = effectful_lookup {
+            let effectful_nodes = effectful_paths.unwrap().get(rootid);
+            if effectful_nodes.is_none() {
+                // skip when this root isn't in effectful_paths
+                return false;
+            }
+
+            // skip nodes not on the path
+            if !effectful_nodes.unwrap().contains(nodeid) {
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
 /// Extract the function specified by `func` from the egraph.
 pub fn extract_with_paths(
     func: &str,
@@ -854,43 +1421,10 @@ pub fn extract_with_paths(
 
     while let Some((rootid, nodeid)) = worklist.pop() {
         let classid = info.n2c(&nodeid);
-        let node = info.egraph.nodes.get(&nodeid).unwrap();
-        if info.unextractables.contains(&node.op) {
-            continue;
-        }
-
-        // Skip inlined calls
-        if node.op == "Call"
-            && info.inlined_calls.contains(&(
-                info.n2c(&node.children[0]).clone(),
-                info.n2c(&node.children[1]).clone(),
-            ))
-        {
+        if !node_is_extractable(extractor, info, &rootid, &nodeid, effectful_paths) {
             continue;
         }
 
-        let sort_of_node = info.get_sort_of_eclass(&classid);
-        // if node is effectful, we only consider it if it is in the effectful path
-        if sort_of_node == "Expr" && effectful_paths.is_some() {
-            let effectful_lookup = extractor.is_eclass_effectful(classid.clone());
-            if effectful_lookup.is_none() && node.op != "Function" {
-                // skip when type is unknown
-                continue;
-            }
-            if let Some(true) = effectful_lookup {
-                let effectful_nodes = effectful_paths.unwrap().get(&rootid);
-                if effectful_nodes.is_none() {
-                    // continue when this root isn't in effectful_paths
-                    continue;
-                }
-
-                // skip nodes not on the path
-                if !effectful_nodes.unwrap().contains(&nodeid) {
-                    continue;
-                }
-            }
-        }
-
         // create a new region_costs map if it doesn't exist
         let region_costs = extractor.costs.entry(rootid.clone()).or_default();
         let lookup = region_costs.get(&classid);
@@ -953,21 +1487,68 @@ pub fn extract_with_paths(
 }
 
 pub trait CostModel {
-    /// TODO: we could do better with type info
-    fn get_op_cost(&self, op: &str) -> Cost;
+    /// The cost of an operator, given the type of its eclass if it is known.
+    /// The type is None for e-classes that are not expressions,
+    /// or when type analysis hasn't found their type.
+    /// Whether a cost is infinite must not depend on the type.
+    fn get_op_cost(&self, op: &str, ty: Option<&Type>) -> Cost;
 
     /// if true, the op's children are ignored in calculating the cost
     fn ignore_children(&self, op: &str) -> bool;
+
+    /// The cost of a loop body with cost `body_cost`
+    /// that is estimated to run `iterations` times.
+    fn loop_body_cost(&self, body_cost: Cost, iterations: i64) -> Cost {
+        body_cost * NotNan::new(iterations as f64).unwrap()
+    }
+
+    /// The extra cost, per iteration, of a load or write in a loop body
+    /// whose address moves by `stride` elements each iteration.
+    fn access_stride_cost(&self, _stride: i64) -> Cost {
+        NotNan::new(0.).unwrap()
+    }
+
+    /// The extra cost, per iteration, of a loop body that uses
+    /// `live_values` of its arguments.
+    fn loop_live_values_cost(&self, _live_values: i64) -> Cost {
+        NotNan::new(0.).unwrap()
+    }
+}
+
+/// Allows picking a cost model at runtime, see `CostModelKind`.
+impl CostModel for Box<dyn CostModel> {
+    fn get_op_cost(&self, op: &str, ty: Option<&Type>) -> Cost {
+        self.as_ref().get_op_cost(op, ty)
+    }
+
+    fn ignore_children(&self, op: &str) -> bool {
+        self.as_ref().ignore_children(op)
+    }
+
+    fn loop_body_cost(&self, body_cost: Cost, iterations: i64) -> Cost {
+        self.as_ref().loop_body_cost(body_cost, iterations)
+    }
+
+    fn access_stride_cost(&self, stride: i64) -> Cost {
+        self.as_ref().access_stride_cost(stride)
+    }
+
+    fn loop_live_values_cost(&self, live_values: i64) -> Cost {
+        self.as_ref().loop_live_values_cost(live_values)
+    }
 }
 
 pub struct DefaultCostModel;
 pub struct TestCostModel;
+/// A cost model for code size, which counts instructions.
+/// Loop bodies are counted once, no matter how many times they run.
+pub struct SizeCostModel;
 
 impl CostModel for TestCostModel {
-    fn get_op_cost(&self, op: &str) -> Cost {
+    fn get_op_cost(&self, op: &str, ty: Option<&Type>) -> Cost {
         match op {
             "Get" => (0.).try_into().unwrap(),
-            _ => DefaultCostModel.get_op_cost(op),
+            _ => DefaultCostModel.get_op_cost(op, ty),
         }
     }
 
@@ -976,10 +1557,65 @@ impl CostModel for TestCostModel {
     }
 }
 
+impl CostModel for SizeCostModel {
+    fn get_op_cost(&self, op: &str, _ty: Option<&Type>) -> Cost {
+        match op {
+            // Leaves
+            "Const" => 1.,
+            "Arg" => 0.,
+            _ if op.parse::<i64>().is_ok() || op.parse::<f64>().is_ok() || op.starts_with('"') => {
+                0.
+            }
+            "true" | "false" | "()" => 0.,
+            // Lists
+            "Empty" | "Single" | "Concat" | "Nil" | "Cons" => 0.,
+            // small cost for get to encourage canonicalization
+            "Get" => 0.01,
+            // Types
+            "IntT" | "BoolT" | "FloatT" | "PointerT" | "StateT" => 0.,
+            "Base" | "TupleT" | "TNil" | "TCons" => 0.,
+            "Int" | "Bool" | "Float" => 0.,
+            // Every operation is one instruction
+            "Add" | "PtrAdd" | "Sub" | "And" | "Or" | "Not" | "Shl" | "Shr" => 1.,
+            "FAdd" | "FSub" | "Fmax" | "Fmin" => 1.,
+            "Mul" | "FMul" | "Div" | "FDiv" => 1.,
+            "Eq" | "LessThan" | "GreaterThan" | "LessEq" | "GreaterEq" => 1.,
+            "Select" | "Smax" | "Smin" => 1.,
+            "FEq" | "FLessThan" | "FGreaterThan" | "FLessEq" | "FGreaterEq" => 1.,
+            "Print" | "Write" | "Load" => 1.,
+            "Alloc" | "Free" => 1.,
+            "Call" => 1.,
+            // Control
+            "Program" | "Function" => 0.,
+            "DoWhile" => 1.,
+            "If" | "Switch" => 1.,
+            // Schema
+            "Bop" | "Uop" | "Top" => 0.,
+            _ => INFINITY,
+        }
+        .try_into()
+        .unwrap()
+    }
+
+    fn ignore_children(&self, op: &str) -> bool {
+        DefaultCostModel.ignore_children(op)
+    }
+
+    fn loop_body_cost(&self, body_cost: Cost, _iterations: i64) -> Cost {
+        body_cost
+    }
+}
+
 impl CostModel for DefaultCostModel {
-    fn get_op_cost(&self, op: &str) -> Cost {
+    fn get_op_cost(&self, op: &str, ty: Option<&Type>) -> Cost {
+        // Loads produce a tuple of the loaded value and the new state,
+        // so look at the first element of tuples too.
+        let produces_float = matches!(ty, Some(Type::Base(BaseType::FloatT)))
+            || matches!(ty, Some(Type::TupleT(types)) if types.first() == Some(&BaseType::FloatT));
         match op {
             // Leaves
+            // float constants are materialized from memory
+            "Const" if produces_float => 5.,
             "Const" => 1.,
             "Arg" => 0.,
             _ if op.parse::<i64>().is_ok() || op.parse::<f64>().is_ok() || op.starts_with('"') => {
@@ -1004,10 +1640,12 @@ impl CostModel for DefaultCostModel {
             "FDiv" => 250.,
             // Comparisons
             "Eq" | "LessThan" | "GreaterThan" | "LessEq" | "GreaterEq" => 10.,
+            "Select" if produces_float => 20.,
             "Select" | "Smax" | "Smin" => 10.,
             "FEq" => 10.,
             "FLessThan" | "FGreaterThan" | "FLessEq" | "FGreaterEq" => 100.,
             // Effects
+            "Load" if produces_float => 60.,
             "Print" | "Write" | "Load" => 50.,
             "Alloc" | "Free" => 100.,
             "Call" => 1000000., // This (very roughly) bounds the size of an expression we inline
@@ -1027,6 +1665,20 @@ impl CostModel for DefaultCostModel {
     fn ignore_children(&self, op: &str) -> bool {
         matches!(op, "InLoop" | "InSwitch" | "InIf" | "InFunc")
     }
+
+    fn access_stride_cost(&self, stride: i64) -> Cost {
+        // Accesses that move by more elements than fit in a 64-byte cache line
+        // miss the cache every iteration
+        let elements_per_line = 8;
+        let lines_touched = stride.unsigned_abs().clamp(1, elements_per_line) - 1;
+        NotNan::new(lines_touched as f64 * 25.).unwrap()
+    }
+
+    fn loop_live_values_cost(&self, live_values: i64) -> Cost {
+        // Values that don't fit in registers are stored and loaded again every iteration
+        let spilled = (live_values - config::LOOP_REGISTERS).max(0);
+        NotNan::new(spilled as f64 * 10.).unwrap()
+    }
 }
 
 /** A data structure to maintain a queue of unique elements.
@@ -1090,10 +1742,10 @@ where
     }
 }
 
-struct EnodeChild {
-    child: ClassId,
-    is_subregion: bool,
-    is_assumption: bool,
+pub(crate) struct EnodeChild {
+    pub(crate) child: ClassId,
+    pub(crate) is_subregion: bool,
+    pub(crate) is_assumption: bool,
 }
 
 impl EnodeChild {
@@ -1108,7 +1760,7 @@ impl EnodeChild {
 
 /// For a given enode, returns a vector of children eclasses.
 /// Also, for each child returns if the child is a region root.
-fn enode_children(
+pub(crate) fn enode_children(
     egraph: &egraph_serialize::EGraph,
     enode: &egraph_serialize::Node,
 ) -> Vec<EnodeChild> {
@@ -1233,7 +1885,7 @@ fn find_reachable(
             }
             for node in &egraph.classes()[&eclass].nodes {
                 // skip nodes with infinite cost
-                if cm.get_op_cost(&egraph[node].op).is_infinite() {
+                if cm.get_op_cost(&egraph[node].op, None).is_infinite() {
                     continue;
                 }
 
@@ -1263,6 +1915,11 @@ fn find_reachable(
 
 #[cfg(test)]
 fn dag_extraction_test(prog: &TreeProgram, expected_cost: NotNan<f64>) {
+    assert_eq!(dag_extraction_cost(prog, TestCostModel), expected_cost);
+}
+
+#[cfg(test)]
+fn dag_extraction_cost(prog: &TreeProgram, cost_model: impl CostModel) -> Cost {
     use crate::{print_with_intermediate_vars, prologue};
     let string_prog = {
         let (term, termdag) = prog.to_egglog();
@@ -1288,12 +1945,46 @@ fn dag_extraction_test(prog: &TreeProgram, expected_cost: NotNan<f64>) {
         serialized_egraph,
         unextractables,
         &mut termdag,
-        TestCostModel,
+        cost_model,
         true,
         false,
+        None,
     );
 
-    assert_eq!(cost_set.0, expected_cost);
+    cost_set.0
+}
+
+/// Like `dag_extraction_cost`, but runs type analysis first
+/// so the cost model sees the type of each expression.
+#[cfg(test)]
+fn typed_dag_extraction_cost(prog: &TreeProgram, cost_model: impl CostModel) -> Cost {
+    use crate::{print_with_intermediate_vars, prologue};
+    let string_prog = {
+        let (term, termdag) = prog.to_egglog();
+        let printed = print_with_intermediate_vars(&termdag, term);
+        format!(
+            "{}\n{printed}\n(run-schedule (saturate (saturate type-helpers) type-analysis))",
+            prologue(),
+        )
+    };
+
+    let mut egraph = egglog::EGraph::default();
+    egraph.parse_and_run_program(None, &string_prog).unwrap();
+    let (serialized_egraph, unextractables) = serialized_egraph(egraph);
+    let mut termdag = TermDag::default();
+
+    extract(
+        prog,
+        prog.fns(),
+        serialized_egraph,
+        unextractables,
+        &mut termdag,
+        cost_model,
+        true,
+        false,
+        None,
+    )
+    .0
 }
 
 /// This only runs extract_without_linearity once
@@ -1391,20 +2082,114 @@ fn test_dag_extract() {
         )
     );
     let cost_model = TestCostModel;
-    let cost_inside_loop = cost_model.get_op_cost("LessThan")
+    let cost_inside_loop = cost_model.get_op_cost("LessThan", None)
     // while the same const is used several times, it is only counted twice
-    + cost_model.get_op_cost("Const")
-    + cost_model.get_op_cost("Add");
+    + cost_model.get_op_cost("Const", None)
+    + cost_model.get_op_cost("Add", None);
 
-    let cost_of_one_func = cost_model.get_op_cost("DoWhile")
+    let cost_of_one_func = cost_model.get_op_cost("DoWhile", None)
         + NotNan::new(1000.).unwrap() * cost_inside_loop
-        + cost_model.get_op_cost("Const")
-        + cost_model.get_op_cost("Add");
+        + cost_model.get_op_cost("Const", None)
+        + cost_model.get_op_cost("Add", None);
     // two of the same function
     let expected_cost = cost_of_one_func * 2.;
     dag_extraction_test(&prog, expected_cost);
 }
 
+#[test]
+fn test_size_cost_model_does_not_scale_loops() {
+    use crate::ast::*;
+
+    let prog = program!(function(
+        "main",
+        tuplet!(intt(), statet()),
+        tuplet!(intt(), statet()),
+        parallel!(
+            get(
+                dowhile(
+                    parallel!(getat(0)),
+                    parallel!(less_than(getat(0), int(10)), add(getat(0), int(1)))
+                ),
+                0
+            ),
+            getat(1)
+        )
+    ),);
+    let cost_model = SizeCostModel;
+    // the loop body is counted once
+    let expected_cost = cost_model.get_op_cost("DoWhile", None)
+        + cost_model.get_op_cost("LessThan", None)
+        + cost_model.get_op_cost("Add", None)
+        + cost_model.get_op_cost("Const", None) * 2.
+        + cost_model.get_op_cost("Get", None) * 4.;
+    let cost = dag_extraction_cost(&prog, cost_model);
+    assert!((cost.into_inner() - expected_cost.into_inner()).abs() < 1e-9);
+}
+
+#[test]
+fn test_eclass_types_reach_cost_model() {
+    use crate::{ast::*, print_with_intermediate_vars, prologue};
+    let prog = program!(function(
+        "main",
+        tuplet!(intt(), statet()),
+        tuplet!(floatt(), statet()),
+        parallel!(fadd(float(1.5), float(2.5)), getat(1))
+    ),);
+    let string_prog = {
+        let (term, termdag) = prog.to_egglog();
+        let printed = print_with_intermediate_vars(&termdag, term);
+        format!(
+            "{}\n{printed}\n(run-schedule (saturate (saturate type-helpers) type-analysis))",
+            prologue(),
+        )
+    };
+    let mut egraph = egglog::EGraph::default();
+    egraph.parse_and_run_program(None, &string_prog).unwrap();
+    let (serialized_egraph, _unextractables) = serialized_egraph(egraph);
+
+    let eclass_types = EgraphInfo::get_eclass_types(&serialized_egraph);
+    let float_consts = serialized_egraph
+        .nodes
+        .iter()
+        .filter(|(_nodeid, node)| node.op == "Const")
+        .map(|(nodeid, _node)| serialized_egraph.nid_to_cid(nodeid))
+        .collect::<Vec<_>>();
+    assert_eq!(float_consts.len(), 2);
+    for class in float_consts {
+        assert_eq!(eclass_types.get(class), Some(&base(floatt())));
+    }
+
+    // float constants are more expensive than int constants
+    assert!(
+        DefaultCostModel.get_op_cost("Const", Some(&base(floatt())))
+            > DefaultCostModel.get_op_cost("Const", Some(&base(intt())))
+    );
+}
+
+#[test]
+fn test_float_select_costs_more() {
+    use crate::ast::*;
+
+    let select_prog = |ty: BaseType| {
+        program!(function(
+            "main",
+            tuplet!(boolt(), ty.clone(), ty.clone(), statet()),
+            tuplet!(ty, statet()),
+            parallel!(select(getat(0), getat(1), getat(2)), getat(3))
+        ),)
+    };
+    let float_cost = typed_dag_extraction_cost(&select_prog(floatt()), DefaultCostModel);
+    let int_cost = typed_dag_extraction_cost(&select_prog(intt()), DefaultCostModel);
+
+    // the Select operator has no type, so its type comes from the Top
+    let difference = DefaultCostModel.get_op_cost("Select", Some(&base(floatt())))
+        - DefaultCostModel.get_op_cost("Select", Some(&base(intt())));
+    assert!(difference > NotNan::new(0.).unwrap());
+    assert!(
+        (float_cost.into_inner() - int_cost.into_inner() - difference.into_inner()).abs() < 1e-9
+    );
+}
+
 #[test]
 fn simple_dag_extract() {
     use crate::ast::*;
@@ -1416,7 +2201,7 @@ fn simple_dag_extract() {
     ),);
     let cost_model = TestCostModel;
 
-    let expected_cost = cost_model.get_op_cost("Const");
+    let expected_cost = cost_model.get_op_cost("Const", None);
     dag_extraction_test(&prog, expected_cost);
 }
 
@@ -1571,7 +2356,7 @@ fn test_validity_of_extraction() {
     assert!(extractor_not_linear.check_function_is_linear(&res).is_err());
 
     // second extraction should succeed
-    extract(
+    let (_cost, _prog, reports) = extract(
         &prog,
         vec!["main".to_string()],
         serialized_egraph,
@@ -1580,7 +2365,73 @@ fn test_validity_of_extraction() {
         TestCostModel,
         true,
         false,
+        None,
+    );
+
+    // the cheap value path was skipped because a descendant is off the state edge path
+    assert_eq!(reports.len(), 1);
+    let skipped_value_path = reports[0]
+        .skipped
+        .iter()
+        .find(|skipped| skipped.skipped.1 == "Get" && skipped.chosen.1 == "Bop")
+        .unwrap_or_else(|| panic!("Expected the cheap value path in report:\n{}", reports[0]));
+    assert!(!skipped_value_path.off_path);
+    assert!(skipped_value_path.skipped.2 < skipped_value_path.chosen.2);
+}
+
+#[test]
+fn test_extract_top_k_finds_distinct_candidates() {
+    use crate::{ast::*, print_with_intermediate_vars, prologue};
+
+    let cheap = add(getat(0), getat(0)).with_arg_types(tuplet!(intt(), statet()), base(intt()));
+    let expensive = mul(getat(0), int(2)).with_arg_types(tuplet!(intt(), statet()), base(intt()));
+    let decl = format!(
+        "(let cheap {})
+         (let expensive {})
+         (union cheap expensive)",
+        cheap, expensive,
+    );
+    // used by every candidate, so it is penalized in later rounds
+    let shared = sub(getat(0), int(1)).with_arg_types(tuplet!(intt(), statet()), base(intt()));
+    let prog = program!(function(
+        "main",
+        tuplet!(intt(), statet()),
+        tuplet!(intt(), intt(), statet()),
+        parallel!(cheap, shared, getat(1))
+    ),);
+
+    let string_prog = {
+        let (term, termdag) = prog.to_egglog();
+        let printed = print_with_intermediate_vars(&termdag, term);
+        format!("{}\n{}\n{}", prologue(), decl, printed)
+    };
+    let mut egraph = egglog::EGraph::default();
+    egraph.parse_and_run_program(None, &string_prog).unwrap();
+    let (serialized_egraph, unextractables) = serialized_egraph(egraph);
+
+    // The Add is picked until it has been penalized enough to cost more than the Mul.
+    // Rounds that pick the same program again don't produce a new candidate.
+    let candidates = extract_top_k(
+        &prog,
+        prog.fns(),
+        serialized_egraph,
+        unextractables,
+        &mut TermDag::default(),
+        TestCostModel,
+        true,
+        3,
     );
+    assert_eq!(candidates.len(), 2);
+    let (first_cost, first) = &candidates[0];
+    let (second_cost, second) = &candidates[1];
+    assert!(first.to_string().contains("Add"));
+    assert!(second.to_string().contains("Mul"));
+
+    // the costs don't include the penalties
+    let cost = |op| TestCostModel.get_op_cost(op, None);
+    let shared_cost = cost("Sub") + cost("Const");
+    assert_eq!(*first_cost, cost("Add") + shared_cost);
+    assert_eq!(*second_cost, cost("Mul") + cost("Const") + shared_cost);
 }
 
 pub(crate) fn has_debug_exprs(serialized_egraph: &egraph_serialize::EGraph) -> bool {
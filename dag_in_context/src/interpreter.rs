@@ -12,6 +12,7 @@ use std::{
 };
 
 use crate::{
+    profile::ProfileData,
     schema::{BinaryOp, Constant, Expr, RcExpr, TernaryOp, TreeProgram, UnaryOp},
     tuplev,
 };
@@ -121,6 +122,8 @@ pub(crate) struct VirtualMachine<'a> {
     eval_cache: HashMap<*const Expr, Value>,
     /// Print log
     log: Vec<String>,
+    /// Loop and call counts, collected when profiling
+    profile: Option<ProfileData>,
 }
 
 /// Represents the result of running a
@@ -145,11 +148,28 @@ pub fn interpret_dag_prog(prog: &TreeProgram, arg: &Value) -> (Value, Vec<String
         memory: HashMap::new(),
         eval_cache: HashMap::new(),
         log: vec![],
+        profile: None,
     };
     let ret_val = vm.interpret_call(&prog.entry.func_name().unwrap(), arg);
     (ret_val, vm.log)
 }
 
+/// Interprets a program like [`interpret_dag_prog`], returning the value
+/// returned by the program and a profile of how many times each
+/// loop iterated and each call site ran.
+pub fn profile_dag_prog(prog: &TreeProgram, arg: &Value) -> (Value, ProfileData) {
+    let mut vm = VirtualMachine {
+        program: prog,
+        next_addr: 0,
+        memory: HashMap::new(),
+        eval_cache: HashMap::new(),
+        log: vec![],
+        profile: Some(ProfileData::new(prog)),
+    };
+    let ret_val = vm.interpret_call(&prog.entry.func_name().unwrap(), arg);
+    (ret_val, vm.profile.unwrap())
+}
+
 /// Interprets an expression, returning the value
 pub fn interpret_expr(expr: &RcExpr, func_arg: &Value) -> BrilState {
     let mut vm = VirtualMachine {
@@ -162,6 +182,7 @@ pub fn interpret_expr(expr: &RcExpr, func_arg: &Value) -> BrilState {
         eval_cache: HashMap::new(),
         memory: HashMap::new(),
         log: vec![],
+        profile: None,
     };
     let value = vm.interpret_expr(expr, func_arg);
     BrilState {
@@ -434,7 +455,9 @@ impl<'a> VirtualMachine<'a> {
 
                 // Because it's a do-while, we always execute the body at least once
                 let mut pred = Const(Constant::Bool(true));
+                let mut iterations = 0;
                 while pred == Const(Constant::Bool(true)) {
+                    iterations += 1;
                     let Tuple(pred_output_val) =
                         self.interpret_region(pred_output, &Tuple(vals.clone()))
                     else {
@@ -449,12 +472,18 @@ impl<'a> VirtualMachine<'a> {
                     pred = pred_output_val[0].clone();
                     vals = pred_output_val[1..].to_vec();
                 }
+                if let Some(profile) = &mut self.profile {
+                    profile.record_loop(expr, iterations);
+                }
                 Tuple(vals)
             }
             Expr::Arg(_ty, _ctx) => arg.clone(),
             Expr::Function(..) => panic!("Function should not be interpreted as an expression"),
             Expr::Call(func_name, e) => {
                 let e_val = self.interpret_expr(e, arg);
+                if let Some(profile) = &mut self.profile {
+                    profile.record_call(expr);
+                }
                 self.interpret_call(func_name, &e_val)
             }
             Expr::Symbolic(_) => panic!("found symbolic"),
@@ -325,3 +325,134 @@ fn context_if_with_state() -> crate::Result {
         vec!["true".to_string()],
     )
 }
+
+#[test]
+fn dead_branch_not_folded() -> crate::Result {
+    // if input < 0 { if 5 < input { input < 3 } else { false } } else { false }
+    // in the inner then branch, input is at least 6 and at most -1, so
+    // input < 3 has a lower bound of true and an upper bound of false
+    let inner = tif(
+        less_than(int(5), getat(0)),
+        parallel!(getat(0)),
+        less_than(getat(0), int(3)),
+        tfalse(),
+    );
+    let outer = tif(
+        less_than(iarg(), int_ty(0, base(intt()))),
+        parallel!(iarg()),
+        inner,
+        tfalse(),
+    );
+
+    let f = function("main", base(intt()), base(boolt()), outer).func_with_arg_types();
+    let prog = f.to_program(base(intt()), base(boolt()));
+    let (with_context, cache) = prog.add_context();
+    let term = with_context.entry.func_body().unwrap();
+
+    // the dead branch is merged away instead of folding
+    // the comparison to both true and false
+    egglog_test(
+        &format!("{with_context}\n{}", cache.get_unions()),
+        &format!(
+            "
+        (check (= {term} (If pred inputs (Const (Bool false) ty1 ctx1) els)))
+        (fail (check (= (Bool true) (Bool false))))"
+        ),
+        vec![with_context],
+        intv(-4),
+        val_bool(false),
+        vec![],
+    )
+}
+
+#[test]
+fn float_lt_interval() -> crate::Result {
+    let e = fless_than(fadd(float(1.0), float(2.0)), float(3.5))
+        .with_arg_types(emptyt(), base(boolt()));
+    interval_test(
+        e.clone(),
+        base(boolt()),
+        val_empty(),
+        val_bool(true),
+        format!("(check (lo-bound {e}) (BoolB true))"),
+    )
+}
+
+#[test]
+fn float_gt_false_interval() -> crate::Result {
+    let e = fgreater_than(fmul(float(-2.0), float(3.0)), float(0.0))
+        .with_arg_types(emptyt(), base(boolt()));
+    interval_test(
+        e.clone(),
+        base(boolt()),
+        val_empty(),
+        val_bool(false),
+        format!("(check (hi-bound {e}) (BoolB false))"),
+    )
+}
+
+#[test]
+fn float_nan_not_folded() -> crate::Result {
+    // 0.0 / 0.0 is NaN, and NaN < 1.0 is false
+    let e = fless_than(fdiv(float(0.0), float(0.0)), float(1.0))
+        .with_arg_types(emptyt(), base(boolt()));
+    interval_test(
+        e.clone(),
+        base(boolt()),
+        val_empty(),
+        val_bool(false),
+        format!("(fail (check (lo-bound {e}) (BoolB true)))"),
+    )
+}
+
+#[test]
+fn pointer_offset_in_bounds() -> crate::Result {
+    let input_type = tuplet!(intt(), statet());
+    let output_type = tuplet!(intt(), statet());
+    let allocated = alloc(0, int(4), getat(1), pointert(intt()));
+    let ptr = get(allocated.clone(), 0);
+    let addr = ptradd(ptr.clone(), getat(0));
+    let written = write(addr.clone(), int(7), get(allocated, 1));
+    let loaded = load(addr, written);
+    let prog = parallel!(get(loaded.clone(), 0), free(ptr, get(loaded, 1)))
+        .with_arg_types(input_type.clone(), output_type.clone());
+
+    // the accessed address is within the allocation of size 4
+    egglog_test(
+        &format!("{prog}"),
+        "
+(check (= addr (Bop (PtrAdd) ptr offset))
+       (= 3 (accessed-offset-hi addr)))",
+        vec![prog.to_program(input_type, output_type)],
+        tuplev!(intv(2), statev()),
+        tuplev!(intv(7), statev()),
+        vec![],
+    )
+}
+
+#[test]
+fn pointer_offset_range_check_not_folded() -> crate::Result {
+    let input_type = tuplet!(intt(), statet());
+    let output_type = tuplet!(boolt(), intt(), statet());
+    let allocated = alloc(0, int(4), getat(1), pointert(intt()));
+    let ptr = get(allocated.clone(), 0);
+    let in_range = less_than(getat(0), int(4)).with_arg_types(input_type.clone(), base(boolt()));
+    let written = write(ptradd(ptr.clone(), getat(0)), int(7), get(allocated, 1));
+    let loaded = load(ptradd(ptr.clone(), int(0)), written);
+    let prog = parallel!(
+        in_range.clone(),
+        get(loaded.clone(), 0),
+        free(ptr, get(loaded, 1))
+    )
+    .with_arg_types(input_type.clone(), output_type.clone());
+
+    // the write bounds its address, not the offset the range check uses
+    egglog_test(
+        &format!("{prog}"),
+        &format!("(fail (check (= (BoolB true) (lo-bound {in_range}))))"),
+        vec![prog.to_program(input_type, output_type)],
+        tuplev!(intv(0), statev()),
+        tuplev!(val_bool(true), intv(7), statev()),
+        vec![],
+    )
+}
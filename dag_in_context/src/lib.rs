@@ -1,29 +1,44 @@
 use clap::ValueEnum;
+use cost_table::{CostTable, TableCostModel};
 use egglog::{Term, TermDag};
-use greedy_dag_extractor::{extract, has_debug_exprs, serialized_egraph, DefaultCostModel};
-use indexmap::IndexMap;
+use greedy_dag_extractor::{
+  extract, extract_top_k, extract_with_fn_costs, has_debug_exprs, serialized_egraph, CostModel,
+  DefaultCostModel, SizeCostModel,
+};
+use indexmap::{IndexMap, IndexSet};
 use interpreter::Value;
+use profile::ProfileData;
 use schedule::{rulesets, CompilerPass};
 use schema::TreeProgram;
-use std::{fmt::Write, i64};
+use std::{fmt::Write, i64, path::PathBuf, time::Duration};
 use to_egglog::TreeToEgglog;
 
 use crate::{
-  add_context::ContextCache, dag2svg::tree_to_svg, interpreter::interpret_dag_prog,
-  optimizations::function_inlining, schedule::parallel_schedule,
+  add_context::ContextCache,
+  dag2svg::tree_to_svg,
+  interpreter::{interpret_dag_prog, profile_dag_prog},
+  optimizations::{
+    dead_region_outputs, function_inlining, function_specialization, loop_fusion, loop_interchange,
+    scalar_replacement, state_stripping,
+  },
+  schedule::parallel_schedule,
+  schema::Expr,
 };
 
 pub mod add_context;
 pub mod ast;
 mod config;
+pub mod cost_table;
 pub mod dag2svg;
 pub mod dag_typechecker;
+mod exact_extractor;
 pub mod from_egglog;
 mod greedy_dag_extractor;
 pub mod interpreter;
 pub(crate) mod interval_analysis;
 mod linearity;
 mod optimizations;
+pub mod profile;
 pub mod schema;
 pub mod schema_helpers;
 mod to_egglog;
@@ -37,6 +52,11 @@ pub mod schedule;
 pub type Result = std::result::Result<(), MainError>;
 
 pub fn prologue() -> String {
+  prologue_with_cost_model(&DefaultCostModel)
+}
+
+/// The prologue, with rules that depend on costs generated for `cost_model`.
+fn prologue_with_cost_model(cost_model: &dyn CostModel) -> String {
   [
     include_str!("schema.egg"),
     include_str!("type_analysis.egg"),
@@ -46,8 +66,6 @@ pub fn prologue() -> String {
     &optimizations::is_resolved::rules().join("\n"),
     &optimizations::body_contains::rules().join("\n"),
     include_str!("optimizations/purity_analysis.egg"),
-    // TODO cond inv code motion with regions
-    //&optimizations::conditional_invariant_code_motion::rules().join("\n"),
     include_str!("utility/add_context.egg"),
     include_str!("utility/context-prop.egg"),
     include_str!("utility/term-subst.egg"),
@@ -58,17 +76,24 @@ pub fn prologue() -> String {
     include_str!("utility/drop_at.egg"),
     include_str!("interval_analysis.egg"),
     include_str!("optimizations/switch_rewrites.egg"),
-    include_str!("optimizations/select.egg"),
+    &optimizations::select::rules(cost_model).join("\n"),
     include_str!("optimizations/peepholes.egg"),
+    include_str!("optimizations/fast_math.egg"),
+    &optimizations::constant_folding::rules().join("\n"),
     &optimizations::memory::rules(),
     include_str!("optimizations/memory.egg"),
     &optimizations::loop_invariant::rules().join("\n"),
+    &optimizations::conditional_invariant_code_motion::rules().join("\n"),
     include_str!("optimizations/loop_simplify.egg"),
     include_str!("optimizations/loop_unroll.egg"),
+    include_str!("optimizations/loop_fusion.egg"),
     include_str!("optimizations/swap_if.egg"),
     include_str!("optimizations/rec_to_loop.egg"),
     include_str!("optimizations/passthrough.egg"),
     include_str!("optimizations/loop_strength_reduction.egg"),
+    include_str!("optimizations/loop_closed_form.egg"),
+    include_str!("optimizations/loop_interchange.egg"),
+    &optimizations::strength_reduction::rules().join("\n"),
     include_str!("optimizations/ivt.egg"),
     include_str!("utility/debug-helper.egg"),
     &rulesets(),
@@ -126,12 +151,17 @@ pub fn print_with_intermediate_vars(termdag: &TermDag, term: Term) -> String {
 // with a schedule `schedule`.
 // If `inline_program` is true, it also inlines calls in `fns`.
 // `inline_program` is the program to inline calls from, allowing us to inline unoptimized function bodies.
+// If `profile` is given, it must be a profile of `program`. Profiled loop trip counts are
+// added to the database, and call sites that never ran are not inlined.
+// Rules that weigh costs use the cost model from `eggcc_config`.
 pub fn build_program(
   program: &TreeProgram,
   inline_program: Option<&TreeProgram>,
   fns: &[String],
   cache: &mut ContextCache,
   schedule: &str,
+  profile: Option<&ProfileData>,
+  eggcc_config: &EggccConfig,
 ) -> String {
   let mut printed = String::new();
 
@@ -151,6 +181,15 @@ pub fn build_program(
       ));
     }
 
+    // Inline the hottest calls in the profile first
+    if let Some(profile) = profile {
+      pairs = function_inlining::hottest_inlining_pairs(
+        pairs,
+        profile,
+        config::PROFILED_INLINING_CALL_SITES,
+      );
+    }
+
     function_inlining::print_function_inlining_pairs(
       pairs,
       &mut printed,
@@ -170,6 +209,71 @@ pub fn build_program(
     // eprintln!("func_var {}\t\t <-> \tterm {:?}", func_var, tree_state.termdag.to_string(&term));
   }
 
+  // Add the profiled number of iterations of each loop
+  if let Some(profile) = profile {
+    for loop_profile in profile.loops() {
+      let Some(iterations) = loop_profile.average_iterations() else {
+        continue;
+      };
+      let Expr::DoWhile(inputs, outputs) = loop_profile.loop_expr.as_ref() else {
+        panic!("Expected DoWhile in loop profile");
+      };
+      // skip loops outside of this batch of functions
+      let loop_term = loop_profile.loop_expr.to_egglog_with(&mut tree_state);
+      if !term_cache.contains_key(&loop_term) {
+        continue;
+      }
+      let inputs_term = inputs.to_egglog_with(&mut tree_state);
+      let inputs_var = print_with_intermediate_helper(
+        &tree_state.termdag,
+        inputs_term,
+        &mut term_cache,
+        &mut printed,
+      );
+      let outputs_term = outputs.to_egglog_with(&mut tree_state);
+      let outputs_var = print_with_intermediate_helper(
+        &tree_state.termdag,
+        outputs_term,
+        &mut term_cache,
+        &mut printed,
+      );
+      writeln!(
+        &mut printed,
+        "(set (LoopProfiledIters {inputs_var} {outputs_var}) {iterations})"
+      )
+      .unwrap();
+    }
+  }
+
+  // Offer interchanged and tiled versions of perfect loop nests
+  let mut nest_versions = vec![];
+  for func in fns {
+    let func = program.get_function(func).unwrap();
+    nest_versions.extend(loop_interchange::loop_nest_versions(func, cache));
+  }
+  let loop_nest_facts = loop_interchange::print_loop_nest_versions(
+    &nest_versions,
+    &mut printed,
+    &mut tree_state,
+    &mut term_cache,
+  );
+
+  // Offer split versions of loops, and find loops that fusion can reorder
+  let mut commuting_loops = vec![];
+  let mut loop_splits = vec![];
+  for func in fns {
+    let func = program.get_function(func).unwrap();
+    commuting_loops.extend(loop_fusion::commuting_loops(func));
+    loop_splits.extend(loop_fusion::loop_splits(func, cache));
+  }
+  let loop_fusion_facts = loop_fusion::print_loop_fusion_facts(
+    &commuting_loops,
+    &loop_splits,
+    &mut printed,
+    &mut tree_state,
+    &mut term_cache,
+  );
+
   let loop_context_unions =
     cache.get_unions_with_sharing(&mut printed, &mut tree_state, &mut term_cache);
 
@@ -188,7 +292,7 @@ pub fn build_program(
 
   // eprintln!("{:#?}", term_cache);
 
-  let prologue = prologue();
+  let prologue = prologue_with_cost_model(&eggcc_config.get_cost_model());
 
   format!(
     "
@@ -204,6 +308,12 @@ pub fn build_program(
     ; Program nodes
     {printed}
 
+    ; Versions of loop nests
+    {loop_nest_facts}
+
+    ; Loop fusion and splitting
+    {loop_fusion_facts}
+
     ; Loop context unions
     {loop_context_unions}
 
@@ -230,13 +340,21 @@ pub fn are_progs_eq(program1: TreeProgram, program2: TreeProgram) -> bool {
 pub fn check_roundtrip_egraph(program: &TreeProgram) {
   let mut termdag = egglog::TermDag::default();
   let fns = program.fns();
-  let egglog_prog = build_program(program, None, &fns, &mut ContextCache::new(), "");
+  let egglog_prog = build_program(
+    program,
+    None,
+    &fns,
+    &mut ContextCache::new(),
+    "",
+    None,
+    &EggccConfig::default(),
+  );
   log::info!("Running egglog program...");
   let mut egraph = egglog::EGraph::default();
   egraph.parse_and_run_program(None, &egglog_prog).unwrap();
 
   let (serialized, unextractables) = serialized_egraph(egraph);
-  let (_res_cost, res) = extract(
+  let (_res_cost, res, _reports) = extract(
     program,
     program.fns(),
     serialized,
@@ -245,6 +363,7 @@ pub fn check_roundtrip_egraph(program: &TreeProgram) {
     DefaultCostModel,
     true,
     false,
+    None,
   );
 
   let (original_with_ctx, _) = program.add_dummy_ctx();
@@ -272,6 +391,28 @@ impl Schedule {
   }
 }
 
+#[derive(Clone, Default, PartialEq, Eq, Debug, ValueEnum)]
+pub enum ExtractionMode {
+  /// Pick the cheapest e-node for each e-class bottom-up.
+  #[default]
+  Greedy,
+  /// Start from the greedy result, then search for the cheapest program
+  /// taking sharing into account, until a time limit.
+  Exact,
+}
+
+#[derive(Clone, Default, PartialEq, Eq, Debug, ValueEnum)]
+pub enum CostModelKind {
+  /// Optimize for speed, weighting loop bodies by their estimated iterations.
+  #[default]
+  Default,
+  /// Optimize for code size, counting instructions.
+  Size,
+  /// Optimize for speed using per-op costs from a calibrated cost table.
+  /// `EggccConfig::cost_table` must be set.
+  Table,
+}
+
 #[derive(Clone, Debug)]
 pub struct EggccConfig {
   pub schedule: Schedule,
@@ -283,9 +424,46 @@ pub struct EggccConfig {
   /// and just return the first program found.
   /// This produces unsound results but is useful for seeing the intermediate extracted result.
   pub linearity: bool,
+  /// When set, the program is run in the interpreter on these arguments before each pass.
+  /// The resulting loop trip counts and call counts guide extraction and inlining.
+  pub profile_args: Option<Vec<Value>>,
+  pub extraction_mode: ExtractionMode,
+  /// How long exact extraction may search for each function
+  /// before returning the best program found so far.
+  pub exact_extraction_time_limit: Duration,
+  /// The cost model used for extraction.
+  pub cost_model: CostModelKind,
+  /// The cost table used by `CostModelKind::Table`.
+  pub cost_table: Option<CostTable>,
+  /// When set, a report of the e-nodes that linear extraction skipped
+  /// in each pass is written to this file.
+  pub linearity_report: Option<PathBuf>,
+  /// How many candidate programs the autotuning run mode
+  /// extracts and times (see `optimize_top_k`).
+  pub autotune_candidates: usize,
+  /// Allow float arithmetic to be reassociated and factored,
+  /// which can change the printed results slightly.
+  /// Adds the `fast-math` pass at the end of the schedule.
+  pub fast_math: bool,
 }
 
 impl EggccConfig {
+  /// The passes to run, including the fast-math pass when enabled.
+  pub fn get_schedule_list(&self) -> Vec<CompilerPass> {
+    let mut schedule_list = self.schedule.get_schedule_list();
+    if self.fast_math {
+      schedule_list.push(schedule::fast_math_pass());
+    }
+    schedule_list
+  }
+
+  /// Whether the first `cutoff` passes end with the fast-math pass.
+  /// It only reassociates float arithmetic, so the optimization pass
+  /// before it is treated as a last pass too.
+  pub(crate) fn ends_with_fast_math_pass(&self, cutoff: usize) -> bool {
+    self.fast_math && cutoff == self.get_schedule_list().len()
+  }
+
   pub fn get_normalized_cutoff(&self, schedule_len: usize) -> usize {
     if self.stop_after_n_passes < 0 {
       (schedule_len as i64 + self.stop_after_n_passes) as usize
@@ -295,6 +473,27 @@ impl EggccConfig {
       self.stop_after_n_passes as usize
     }
   }
+
+  /// The time limit for exact extraction, or None for greedy extraction.
+  pub fn get_exact_time_limit(&self) -> Option<Duration> {
+    match self.extraction_mode {
+      ExtractionMode::Greedy => None,
+      ExtractionMode::Exact => Some(self.exact_extraction_time_limit),
+    }
+  }
+
+  pub(crate) fn get_cost_model(&self) -> Box<dyn CostModel> {
+    match self.cost_model {
+      CostModelKind::Default => Box::new(DefaultCostModel),
+      CostModelKind::Size => Box::new(SizeCostModel),
+      CostModelKind::Table => Box::new(TableCostModel {
+        table: self
+          .cost_table
+          .clone()
+          .expect("The table cost model requires a cost table"),
+      }),
+    }
+  }
 }
 
 impl Default for EggccConfig {
@@ -303,35 +502,125 @@ impl Default for EggccConfig {
       schedule: Schedule::default(),
       stop_after_n_passes: i64::MAX,
       linearity: true,
+      profile_args: None,
+      extraction_mode: ExtractionMode::default(),
+      exact_extraction_time_limit: Duration::from_secs(10),
+      cost_model: CostModelKind::default(),
+      cost_table: None,
+      linearity_report: None,
+      autotune_candidates: 4,
+      fast_math: false,
     }
   }
 }
 
+/// Runs the egglog program for one pass of `schedule` on the functions in `batch`,
+/// returning the serialized egraph and the unextractable functions.
+fn run_pass(
+  res: &TreeProgram,
+  schedule: &CompilerPass,
+  batch: &[String],
+  cache: &mut ContextCache,
+  eggcc_config: &EggccConfig,
+) -> std::result::Result<(egraph_serialize::EGraph, IndexSet<String>), egglog::Error> {
+  // if we are inlining, save the program
+  // TODO we inline on the first pass, but this should be configurable from the schedule
+  let inline_program = match schedule {
+    schedule::CompilerPass::Schedule(_) => None,
+    schedule::CompilerPass::InlineWithSchedule(_) => Some(res.clone()),
+  };
+
+  // profile the program as it is before this pass
+  let profile = eggcc_config.profile_args.as_ref().map(|args| {
+    let mut args = args.clone();
+    args.push(Value::StateV);
+    profile_dag_prog(res, &Value::Tuple(args)).1
+  });
+
+  log::info!("Schedule: {:?}", schedule);
+  // only inline functions on the first pass
+  let egglog_prog = build_program(
+    res,
+    inline_program.as_ref(),
+    batch,
+    cache,
+    schedule.egglog_schedule(),
+    profile.as_ref(),
+    eggcc_config,
+  );
+
+  log::info!("Running egglog program...");
+  let mut egraph = egglog::EGraph::default();
+  egraph.parse_and_run_program(None, &egglog_prog)?;
+
+  Ok(serialized_egraph(egraph))
+}
+
 // It is expected that program has context added
 pub fn optimize(
   program: &TreeProgram,
   cache: &mut ContextCache,
   eggcc_config: &EggccConfig,
 ) -> std::result::Result<TreeProgram, egglog::Error> {
-  let schedule_list = eggcc_config.schedule.get_schedule_list();
+  let cutoff = eggcc_config.get_normalized_cutoff(eggcc_config.get_schedule_list().len());
+  optimize_with_cutoff(program, cache, eggcc_config, cutoff, eggcc_config.linearity)
+}
+
+/// Runs the first `cutoff` passes of the schedule.
+/// Every pass maintains linearity except the last one,
+/// which uses `last_pass_linearity`.
+/// When the last pass is the fast-math pass, the pass before it
+/// uses `last_pass_linearity` as well.
+fn optimize_with_cutoff(
+  program: &TreeProgram,
+  cache: &mut ContextCache,
+  eggcc_config: &EggccConfig,
+  cutoff: usize,
+  last_pass_linearity: bool,
+) -> std::result::Result<TreeProgram, egglog::Error> {
+  let schedule_list = eggcc_config.get_schedule_list();
   let mut res = program.clone();
 
-  let cutoff = eggcc_config.get_normalized_cutoff(schedule_list.len());
+  // The program is only rewritten when some pass runs,
+  // so schedules without passes leave it unchanged.
+  if cutoff > 0 {
+    // Pure functions don't need the state edge, and calls to them
+    // can only move around once they don't take the state.
+    let stripped = state_stripping::strip_state_from_pure_functions(&res);
+    // Allocations that don't escape become values carried next to the state.
+    let replaced =
+      scalar_replacement::scalar_replace_allocations(stripped.as_ref().unwrap_or(&res));
+    if let Some(changed) = replaced.or(stripped) {
+      let (changed, changed_cache) = changed.add_context();
+      res = changed;
+      *cache = changed_cache;
+    }
+  }
+
+  let last_passes = if eggcc_config.ends_with_fast_math_pass(cutoff) {
+    2
+  } else {
+    1
+  };
+  let mut linearity_report = String::new();
   for (i, schedule) in schedule_list[..cutoff].iter().enumerate() {
     let mut should_maintain_linearity = true;
-    if i == cutoff - 1 {
-      should_maintain_linearity = eggcc_config.linearity;
+    if i + last_passes >= cutoff {
+      should_maintain_linearity = last_pass_linearity;
     }
 
     log::info!("Running pass {}...", i);
-    let fns = res.fns();
-
-    // if we are inlining, save the program
-    // TODO we inline on the first pass, but this should be configurable from the schedule
-    let inline_program = match schedule {
-      schedule::CompilerPass::Schedule(_) => None,
-      schedule::CompilerPass::InlineWithSchedule(_) => Some(res.clone()),
+    // Specialize functions for constant arguments on the passes that inline,
+    // so the copies are optimized in the same egraph as the originals
+    let specializations = match schedule {
+      schedule::CompilerPass::Schedule(_) => vec![],
+      schedule::CompilerPass::InlineWithSchedule(_) => {
+        let (specialized, specializations) = function_specialization::specialize_functions(&res);
+        res = specialized;
+        specializations
+      }
     };
+    let fns = res.fns();
 
     // TODO experiment with different batches of optimizing functions together
     // currently we use the whole program
@@ -339,52 +628,101 @@ pub fn optimize(
 
     for batch in batches {
       log::info!("Running pass {} on batch {:?}", i, batch);
-      log::info!("Schedule: {:?}", schedule);
-      // only inline functions on the first pass
-      let egglog_prog = build_program(
-        &res,
-        inline_program.as_ref(),
-        &batch,
-        cache,
-        schedule.egglog_schedule(),
-      );
-
-      log::info!("Running egglog program...");
-      let mut egraph = egglog::EGraph::default();
-      egraph.parse_and_run_program(None, &egglog_prog)?;
-
-      let (serialized, unextractables) = serialized_egraph(egraph);
+      let (serialized, unextractables) = run_pass(&res, schedule, &batch, cache, eggcc_config)?;
 
       let mut termdag = egglog::TermDag::default();
       let has_debug_exprs = has_debug_exprs(&serialized);
       if has_debug_exprs {
         log::info!("Program has debug expressions, extracting them instead of original program.");
       }
-      let (_res_cost, iter_result) = extract(
+      let (fn_costs, iter_result, reports) = extract_with_fn_costs(
         &res,
         batch,
         serialized,
         unextractables,
         &mut termdag,
-        DefaultCostModel,
+        eggcc_config.get_cost_model(),
         should_maintain_linearity,
         has_debug_exprs,
+        eggcc_config.get_exact_time_limit(),
       );
 
+      for report in reports.iter().filter(|report| !report.is_empty()) {
+        write!(linearity_report, "Pass {i}: {report}").unwrap();
+      }
+
       res = iter_result;
 
       if has_debug_exprs {
         log::info!("Program has debug expressions, stopping pass {}.", i);
         return Ok(res);
       }
+
+      res =
+        function_specialization::keep_cheaper_specializations(&res, &specializations, &fn_costs);
+
+      // Extraction keeps every output of a region, even ones nothing reads
+      if let Some(trimmed) = dead_region_outputs::remove_dead_region_outputs(&res) {
+        res = trimmed;
+      }
     }
 
     // now add context to res again for the next pass, since context might be less specific
     res = res.add_context().0;
   }
+
+  if let Some(path) = &eggcc_config.linearity_report {
+    std::fs::write(path, linearity_report).expect("failed to write linearity report");
+  }
   Ok(res)
 }
 
+/// Like `optimize`, but extracts up to `k` distinct low-cost candidates
+/// in the last pass instead of a single program (see `extract_top_k`).
+/// The candidates are returned cheapest first, with context added.
+pub fn optimize_top_k(
+  program: &TreeProgram,
+  cache: &mut ContextCache,
+  eggcc_config: &EggccConfig,
+  k: usize,
+) -> std::result::Result<Vec<TreeProgram>, egglog::Error> {
+  let schedule_list = eggcc_config.get_schedule_list();
+  let cutoff = eggcc_config.get_normalized_cutoff(schedule_list.len());
+  assert!(cutoff > 0, "Top-k extraction needs at least one pass");
+
+  // run all but the last pass as usual, keeping them linear
+  // since the last pass is the one that gets extracted,
+  // unless the last pass is the fast-math pass
+  let res = optimize_with_cutoff(
+    program,
+    cache,
+    eggcc_config,
+    cutoff - 1,
+    !eggcc_config.ends_with_fast_math_pass(cutoff) || eggcc_config.linearity,
+  )?;
+
+  log::info!("Running pass {} with top-{} extraction...", cutoff - 1, k);
+  let fns = res.fns();
+  let (serialized, unextractables) =
+    run_pass(&res, &schedule_list[cutoff - 1], &fns, cache, eggcc_config)?;
+  let candidates = extract_top_k(
+    &res,
+    fns,
+    serialized,
+    unextractables,
+    &mut egglog::TermDag::default(),
+    eggcc_config.get_cost_model(),
+    eggcc_config.linearity,
+    k,
+  );
+  Ok(
+    candidates
+      .into_iter()
+      .map(|(_cost, candidate)| candidate.add_context().0)
+      .collect(),
+  )
+}
+
 fn check_program_gets_type(program: TreeProgram) -> Result {
   let prologue = [
     include_str!("schema.egg"),
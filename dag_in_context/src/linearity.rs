@@ -1,20 +1,89 @@
 //! This file contains helpers for making the extracted
 //! program use memory linearly.
 //! In particular, it finds all the effectful e-nodes in an extracted term that are along the state edge path.
+//! It also reports which e-nodes linear extraction had to skip, see `LinearityReport`.
 
-use std::{collections::HashSet, rc::Rc};
+use std::{collections::HashSet, fmt::Display, rc::Rc};
 
 use egglog::Term;
 use egraph_serialize::{ClassId, NodeId};
 use indexmap::{IndexMap, IndexSet};
 
 use crate::{
-    greedy_dag_extractor::{EgraphInfo, Extractor},
+    greedy_dag_extractor::{node_is_extractable, Cost, EgraphInfo, Extractor},
     schema::{Expr, *},
 };
 
 type EffectfulNodes = IndexMap<ClassId, IndexSet<*const Expr>>;
 
+/// An e-node that was the cheapest choice for its e-class,
+/// but that linear extraction did not choose.
+/// Either the node is not on the state edge path, so choosing it
+/// would duplicate or drop a state edge, or one of its descendants is.
+#[derive(Clone, Debug)]
+pub struct SkippedNode {
+    /// The root of the region the e-class was extracted in
+    pub region: ClassId,
+    pub eclass: ClassId,
+    /// The skipped node, its operator, and the total cost of its term
+    pub skipped: (NodeId, String, Cost),
+    /// True when the skipped node itself is off the state edge path,
+    /// false when it was skipped because of one of its descendants.
+    pub off_path: bool,
+    /// The more expensive node linear extraction chose instead,
+    /// its operator, and the total cost of its term
+    pub chosen: (NodeId, String, Cost),
+}
+
+/// The e-nodes that linear extraction skipped in one function.
+/// When an optimization "doesn't fire", this shows whether
+/// its result was found but blocked by linearity.
+#[derive(Clone, Debug)]
+pub struct LinearityReport {
+    pub func: String,
+    pub skipped: Vec<SkippedNode>,
+}
+
+impl LinearityReport {
+    pub fn new(func: &str) -> LinearityReport {
+        LinearityReport {
+            func: func.to_string(),
+            skipped: vec![],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.skipped.is_empty()
+    }
+}
+
+impl Display for LinearityReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Linear extraction of {} skipped {} cheaper e-nodes",
+            self.func,
+            self.skipped.len()
+        )?;
+        for skipped in &self.skipped {
+            let (skipped_node, skipped_op, skipped_cost) = &skipped.skipped;
+            let (chosen_node, chosen_op, chosen_cost) = &skipped.chosen;
+            let reason = if skipped.off_path {
+                "it is off the state edge path"
+            } else {
+                "a descendant is off the state edge path"
+            };
+            writeln!(
+                f,
+                "  e-class {} in region {}: skipped {skipped_op} ({skipped_node}) with cost {skipped_cost} \
+                 because {reason}, chose {chosen_op} ({chosen_node}) with cost {chosen_cost}",
+                skipped.eclass, skipped.region
+            )?;
+        }
+        Ok(())
+    }
+}
+
 struct Linearity {
     effectful_nodes: EffectfulNodes,
     expr_to_term: IndexMap<*const Expr, Term>,
@@ -191,6 +260,46 @@ impl<'a> Extractor<'a> {
         }
     }
 
+    /// Compares the choices made without linearity (`non_linear_choices`, see `Extractor::chosen_nodes`)
+    /// with the choices in the program linear extraction emitted (`linear_choices`).
+    /// Since the only difference between the two extractions is the state edge path,
+    /// every e-class where linear extraction settled for a more expensive node is reported.
+    /// E-classes that linear extraction didn't extract at all are effectful nodes off the path,
+    /// which have no linear alternative, so they are not reported.
+    pub(crate) fn linearity_report(
+        &mut self,
+        func: &str,
+        info: &EgraphInfo,
+        non_linear_choices: &IndexMap<(ClassId, ClassId), (NodeId, Cost)>,
+        linear_choices: &IndexMap<(ClassId, ClassId), (NodeId, Cost)>,
+        effectful_paths: &IndexMap<ClassId, IndexSet<NodeId>>,
+    ) -> LinearityReport {
+        let mut report = LinearityReport::new(func);
+        for ((rootid, classid), (nodeid, cost)) in non_linear_choices {
+            let Some((chosen_node, chosen_cost)) =
+                linear_choices.get(&(rootid.clone(), classid.clone()))
+            else {
+                continue;
+            };
+            if chosen_node == nodeid || chosen_cost <= cost {
+                continue;
+            }
+            let off_path = !node_is_extractable(self, info, rootid, nodeid, Some(effectful_paths));
+            report.skipped.push(SkippedNode {
+                region: rootid.clone(),
+                eclass: classid.clone(),
+                skipped: (nodeid.clone(), info.egraph[nodeid].op.clone(), *cost),
+                off_path,
+                chosen: (
+                    chosen_node.clone(),
+                    info.egraph[chosen_node].op.clone(),
+                    *chosen_cost,
+                ),
+            });
+        }
+        report
+    }
+
     pub fn check_function_is_linear(&mut self, fun: &RcExpr) -> Result<(), String> {
         let mut reachables = Default::default();
         let mut raw_to_rc = Default::default();
@@ -1,152 +1,178 @@
-/*use crate::schema_helpers::{Constructor, ESort, Purpose};
+use crate::schema_helpers::{Constructor, Purpose};
 use std::iter;
-use strum::IntoEnumIterator;
 
-// TODO implement now that we have if regions
+// These constructors have a matching Term constructor.
+// Call and Alloc are left out since hoisting them out of a branch
+// would need to move the state edge too.
+const PURE_CTORS: [Constructor; 6] = [
+    Constructor::Top,
+    Constructor::Bop,
+    Constructor::Uop,
+    Constructor::Get,
+    Constructor::Concat,
+    Constructor::Single,
+];
 
-fn rules_for_ctor(ctor: Constructor) -> Option<String> {
-    use Constructor::*;
-    if [DoWhile, InContext].contains(&ctor) || ctor.sort() != ESort::Expr {
-        return None;
-    }
-    Some(ctor.filter_map_fields(|varying_field| match varying_field.purpose {
-        Purpose::Static(_) | Purpose::CapturedExpr | Purpose::SubListExpr => None,
-        Purpose::SubExpr  => {
-            let ctor_name = ctor.name();
-            let varying_field_name = varying_field.name;
-            let relation = format!("Same{ctor_name}Ignoring-{varying_field_name}");
-            let mk_pattern = |varying_field_pat: String| ctor.construct(|field| {
-                if field == varying_field {
-                    varying_field_pat.clone()
-                } else {
-                    field.var()
-                }
-            });
-            let ctor_pattern1 = mk_pattern("e1".to_string());
-            let ctor_pattern2 = mk_pattern("e2".to_string());
-            let resulting_switch = mk_pattern(format!("(Switch pred (Map-{ctor_name}-{varying_field_name} exprs))"));
-            let resulting_if = mk_pattern("(If c e1 e2)".to_string());
-            Some(format!(
-                "
-                ; Compute {relation}, which detects opportunities for lifting
-                ; {ctor_name}s through control flow when only the {varying_field_name} field varies
-                (relation {relation} (ListExpr))
-                (rule ((DemandSameIgnoring (Cons {ctor_pattern1} (Nil))))
-                      (({relation} (Cons {ctor_pattern1} (Nil))))
-                      :ruleset always-run)
-                (rule ((DemandSameIgnoring (Cons {ctor_pattern1} (Cons {ctor_pattern2} rest)))
-                       ({relation} (Cons {ctor_pattern2} rest)))
-                      (({relation} (Cons {ctor_pattern1} (Cons {ctor_pattern2} rest))))
-                      :ruleset always-run)
-
-                ; Given a list of {ctor_name}s, return a list of each {ctor_name}'s {varying_field_name} field
-                (function Map-{ctor_name}-{varying_field_name} (ListExpr) ListExpr)
-                (rewrite (Map-{ctor_name}-{varying_field_name} (Nil)) (Nil) :ruleset always-run)
-                (rewrite (Map-{ctor_name}-{varying_field_name} (Cons {ctor_pattern1} rest))
-                         (Cons e1 (Map-{ctor_name}-{varying_field_name} rest))
-                         :ruleset always-run)
-
-                ; Lift {ctor_name} when only {varying_field_name} varies
-                (rule ((ExprIsValid (Switch pred exprs))
-                       ({relation} exprs)
-                       (> (ListExpr-length exprs) 1)
-                       ; Bind non-varying field(s)
-                       (= list (Cons {ctor_pattern1} rest)))
-                      ((union (Switch pred exprs)
-                              {resulting_switch}))
-                      :ruleset conditional-invariant-code-motion)
-
-                (rewrite (If c {ctor_pattern1} {ctor_pattern2})
-                         {resulting_if}
-                         :when ((ExprIsValid (If c {ctor_pattern1} {ctor_pattern2})))
-                         :ruleset conditional-invariant-code-motion)"
-            ))
-        }
-    })
-    .join("\n"))
-}
-
-pub(crate) fn rules() -> Vec<String> {
-    iter::once(
+/// Builds rules like:
+/// ```txt
+/// (rule ((= e1 (Bop _op _x1 _y1))
+///        (= _x-term (BranchCommonTerm _x1 _x2))
+///        (= _y-term (BranchCommonTerm _y1 _y2))
+///        (= e2 (Bop _op _x2 _y2)))
+///       ((set (BranchCommonTerm e1 e2) (TermBop _op _x-term _y-term)))
+///       :ruleset always-run)
+/// ```
+fn common_term_rule_for_ctor(ctor: Constructor) -> String {
+    let pattern = |suffix: &str| {
+        ctor.construct(|field| match field.purpose {
+            Purpose::SubExpr => format!("{}{suffix}", field.var()),
+            _ => field.var(),
+        })
+    };
+    let children = ctor.filter_map_fields(|field| {
+        (field.purpose == Purpose::SubExpr).then(|| {
+            let var = field.var();
+            format!("(= {var}-term (BranchCommonTerm {var}1 {var}2))")
+        })
+    });
+    let term = iter::once(format!("Term{}", ctor.name()))
+        .chain(ctor.fields().iter().map(|field| match field.purpose {
+            Purpose::SubExpr => format!("{}-term", field.var()),
+            _ => field.var(),
+        }))
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!(
         "
-        (ruleset conditional-invariant-code-motion)
-        (relation DemandSameIgnoring (ListExpr))
-        (rule ((DemandSameIgnoring (Cons hd tl))) ((DemandSameIgnoring tl)) :ruleset always-run)
-        (rule ((Switch pred exprs)) ((DemandSameIgnoring exprs)) :ruleset always-run)"
-            .to_string(),
+(rule ((= e1 {pattern1})
+       {children}
+       (= e2 {pattern2}))
+      ((set (BranchCommonTerm e1 e2) ({term})))
+      :ruleset always-run)",
+        pattern1 = pattern("1"),
+        pattern2 = pattern("2"),
+        children = children.join("\n       "),
     )
-    .chain(Constructor::iter().filter_map(rules_for_ctor))
-    .collect::<Vec<_>>()
 }
 
-#[test]
-fn var_names_available() {
-    for ctor in Constructor::iter() {
-        for field in ctor.fields() {
-            for var_name in ["e", "e1", "e2", "rest", "pred", "exprs"] {
-                assert_ne!(field.var(), var_name);
-            }
-        }
-    }
+pub(crate) fn rules() -> Vec<String> {
+    iter::once(include_str!("conditional_invariant_code_motion.egg").to_string())
+        .chain(PURE_CTORS.into_iter().map(common_term_rule_for_ctor))
+        .collect::<Vec<_>>()
 }
 
 #[cfg(test)]
 use crate::ast::*;
 #[cfg(test)]
-use crate::schema::Constant;
+use crate::egglog_test;
 #[cfg(test)]
-use crate::Value;
+use crate::schema::Assumption;
 
 #[test]
-fn test_lift_switch() -> crate::Result {
-    let switch = switch!(int(1); less_than(getat(0), int(7)), less_than(getat(1), int(7)))
-        .with_arg_types(tuplet!(intt(), intt()), base(boolt()));
-    let lifted = less_than(switch!(int(1); getat(0), getat(1)), int(7))
-        .with_arg_types(tuplet!(intt(), intt()), base(boolt()));
-    let build = format!("(ExprIsValid {switch})");
-    let check = format!("(check (= {switch} {lifted}))");
-    crate::egglog_test(
-        &build,
-        &check,
-        vec![
-            switch.to_program(tuplet!(intt(), intt()), base(boolt())),
-            lifted.to_program(tuplet!(intt(), intt()), base(boolt())),
-        ],
-        Value::Tuple(vec![
-            Value::Const(Constant::Int(6)),
-            Value::Const(Constant::Int(8)),
-        ]),
-        Value::Const(Constant::Bool(false)),
+fn test_hoist_if() -> crate::Result {
+    // if (x < 2) { x + x } else { (x + x) * 2 }
+    let prog = tif(
+        less_than(arg(), int(2)),
+        single(arg()),
+        single(add(getat(0), getat(0))),
+        single(mul(add(getat(0), getat(0)), int(2))),
+    )
+    .with_arg_types(base(intt()), tuplet!(intt()));
+    let (build, build_cache) = prog.add_ctx(Assumption::dummy());
+
+    // x + x is computed once, before the if
+    egglog_test(
+        &format!("(let build_ {build})\n{}", build_cache.get_unions()),
+        "
+(check (= build_ (If pred new-inputs thn els))
+       (= 2 (tuple-length new-inputs)))",
+        vec![prog.to_program(base(intt()), tuplet!(intt()))],
+        intv(5),
+        tuplev!(intv(20)),
         vec![],
     )
 }
 
 #[test]
-fn test_lift_if() -> crate::Result {
-    let if_ = tif(
-        getat(2),
-        less_than(getat(0), int(7)),
-        less_than(getat(1), int(7)),
+fn test_hoist_switch() -> crate::Result {
+    let prog = switch!(getat(0), single(getat(1));
+        single(mul(add(getat(0), int(1)), int(2))),
+        single(add(getat(0), int(1))),
+        single(sub(add(getat(0), int(1)), int(3)))
+    )
+    .with_arg_types(tuplet!(intt(), intt()), tuplet!(intt()));
+    let (build, build_cache) = prog.add_ctx(Assumption::dummy());
+
+    egglog_test(
+        &format!("(let build_ {build})\n{}", build_cache.get_unions()),
+        "
+(check (= build_ (Switch pred new-inputs branches))
+       (= 2 (tuple-length new-inputs)))",
+        vec![prog.to_program(tuplet!(intt(), intt()), tuplet!(intt()))],
+        tuplev!(intv(1), intv(5)),
+        tuplev!(intv(6)),
+        vec![],
     )
-    .with_arg_types(tuplet!(intt(), intt(), boolt()), base(boolt()));
-    let lifted = less_than(tif(getat(2), getat(0), getat(1)), int(7))
-        .with_arg_types(tuplet!(intt(), intt(), boolt()), base(boolt()));
-    let build = format!("(ExprIsValid {if_})");
-    let check = format!("(check (= {if_} {lifted}))");
-    crate::egglog_test(
-        &build,
-        &check,
-        vec![
-            if_.to_program(tuplet!(intt(), intt(), boolt()), base(boolt())),
-            lifted.to_program(tuplet!(intt(), intt(), boolt()), base(boolt())),
-        ],
-        Value::Tuple(vec![
-            Value::Const(Constant::Int(6)),
-            Value::Const(Constant::Int(8)),
-            Value::Const(Constant::Bool(false)),
-        ]),
-        Value::Const(Constant::Bool(false)),
+}
+
+#[test]
+fn test_no_hoist_different_branches() -> crate::Result {
+    // the branches compute different things from x
+    let prog = tif(
+        less_than(arg(), int(2)),
+        single(arg()),
+        single(add(getat(0), int(1))),
+        single(sub(getat(0), int(1))),
+    )
+    .with_arg_types(base(intt()), tuplet!(intt()));
+    let (build, build_cache) = prog.add_ctx(Assumption::dummy());
+
+    egglog_test(
+        &format!("(let build_ {build})\n{}", build_cache.get_unions()),
+        "(fail (check (= term (BranchCommonTerm then-expr else-expr))
+                     (= (Expr-size then-expr) 3)))",
+        vec![prog.to_program(base(intt()), tuplet!(intt()))],
+        intv(5),
+        tuplev!(intv(4)),
+        vec![],
+    )
+}
+
+#[test]
+fn test_no_hoist_from_one_branch() -> crate::Result {
+    // if (0 < y) { x / y } else { 0 }
+    // The division only runs when y isn't zero, so it stays in the branch,
+    // even when another if with the same predicate divides in its else branch.
+    let inputs = parallel!(getat(0), getat(1));
+    let prog = tif(
+        less_than(int(0), getat(1)),
+        inputs.clone(),
+        single(div(getat(0), getat(1))),
+        single(int(0)),
+    )
+    .with_arg_types(tuplet!(intt(), intt()), tuplet!(intt()));
+    let other = tif(
+        less_than(int(0), getat(1)),
+        inputs,
+        single(int(1)),
+        single(div(getat(0), getat(1))),
+    )
+    .with_arg_types(tuplet!(intt(), intt()), tuplet!(intt()));
+    let (build, build_cache) = prog.add_ctx(Assumption::dummy());
+    let (other, other_cache) = other.add_ctx(Assumption::dummy());
+
+    egglog_test(
+        &format!(
+            "(let build_ {build})\n{}\n(let other_ {other})\n{}",
+            build_cache.get_unions(),
+            other_cache.get_unions()
+        ),
+        "
+(fail (check (= build_ (If pred new-inputs thn els))
+             (= 3 (tuple-length new-inputs))))",
+        vec![prog.to_program(tuplet!(intt(), intt()), tuplet!(intt()))],
+        tuplev!(intv(7), intv(0)),
+        tuplev!(intv(0)),
         vec![],
     )
 }
-*/
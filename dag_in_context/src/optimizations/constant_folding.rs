@@ -0,0 +1,415 @@
+//! Constant folding for every operator that can be folded.
+//! A folded constant must be the value `interpreter.rs` computes,
+//! so each rule only fires when egglog's primitives compute exactly that value.
+//! Integer arithmetic wraps like the interpreter's, and is computed from
+//! 32-bit halves so that no egglog primitive overflows.
+//! Float rules skip results that aren't finite or are zero.
+
+use crate::schema::{BinaryOp, TernaryOp, UnaryOp};
+use std::iter;
+use strum::IntoEnumIterator;
+
+/// Folds an operator applied to constants.
+/// The operands are bound to `a` and `b`.
+struct Fold {
+    /// constant constructor of the operands, like `Int`
+    operand: &'static str,
+    /// conditions on the operands
+    guards: Vec<&'static str>,
+    /// the folded constant
+    result: &'static str,
+}
+
+fn fold(operand: &'static str, guards: Vec<&'static str>, result: &'static str) -> Fold {
+    Fold {
+        operand,
+        guards,
+        result,
+    }
+}
+
+/// The wrapping result of the 32-bit halves `lo` and `hi`,
+/// where `lo` may carry into `hi`.
+const FROM_HALVES: &str = "(Int (| (<< hi 32) (& lo 4294967295)))";
+
+/// Binds the halves of `a + b`, like `i64::wrapping_add`.
+fn wrapping_add() -> Vec<&'static str> {
+    vec![
+        "(= lo (+ (& a 4294967295) (& b 4294967295)))",
+        "(= hi (& (+ (+ (>> a 32) (>> b 32)) (>> lo 32)) 4294967295))",
+    ]
+}
+
+/// Binds the halves of `a - b`, like `i64::wrapping_sub`.
+/// A negative `lo` borrows from `hi`.
+fn wrapping_sub() -> Vec<&'static str> {
+    vec![
+        "(= lo (- (& a 4294967295) (& b 4294967295)))",
+        "(= hi (& (+ (- (>> a 32) (>> b 32)) (>> lo 32)) 4294967295))",
+    ]
+}
+
+/// Binds the halves of `a * b`, like `i64::wrapping_mul`.
+/// The product of the high halves only affects bits past 64, and the low
+/// halves are multiplied in 16-bit pieces.
+fn wrapping_mul() -> Vec<&'static str> {
+    vec![
+        "(= al (& a 4294967295))",
+        "(= bl (& b 4294967295))",
+        "(= cross (& (+ (& (* (>> a 32) bl) 4294967295) (& (* al (>> b 32)) 4294967295)) 4294967295))",
+        "(= a1 (>> al 16))",
+        "(= a0 (& al 65535))",
+        "(= b1 (>> bl 16))",
+        "(= b0 (& bl 65535))",
+        "(= lo (+ (* a0 b0) (<< (+ (* a1 b0) (* a0 b1)) 16)))",
+        "(= hi (& (+ (+ (* a1 b1) cross) (>> lo 32)) 4294967295))",
+    ]
+}
+
+/// egglog compares floats with a total order where NaN is the largest,
+/// so comparisons are only folded for finite operands.
+fn finite_operands(guards: &[&'static str]) -> Vec<&'static str> {
+    ["(= 0.0 (- a a))", "(= 0.0 (- b b))"]
+        .into_iter()
+        .chain(guards.iter().copied())
+        .collect()
+}
+
+/// Binds the result to `r` and checks it is finite and not zero.
+/// egglog's floats compare -0.0 and 0.0 equal, so a zero result
+/// could become a constant with the wrong sign.
+fn finite_result(compute: &'static str) -> Vec<&'static str> {
+    vec![compute, "(= 0.0 (- r r))", "(!= r 0.0)"]
+}
+
+fn bop_folds(op: &BinaryOp) -> Vec<Fold> {
+    use BinaryOp::*;
+    match op {
+        Add => vec![fold("Int", wrapping_add(), FROM_HALVES)],
+        Sub => vec![fold("Int", wrapping_sub(), FROM_HALVES)],
+        Mul => vec![fold("Int", wrapping_mul(), FROM_HALVES)],
+        // division by zero is an error, and i64::MIN / -1 wraps to i64::MIN
+        Div => vec![
+            fold(
+                "Int",
+                vec!["(!= b 0)", "(> a -9223372036854775808)"],
+                "(Int (/ a b))",
+            ),
+            fold(
+                "Int",
+                vec!["(= a -9223372036854775808)", "(!= b 0)", "(!= b -1)"],
+                "(Int (/ a b))",
+            ),
+            fold(
+                "Int",
+                vec!["(= a -9223372036854775808)", "(= b -1)"],
+                "(Int a)",
+            ),
+        ],
+        // shifting by 64 or more is an error
+        Shl => vec![fold("Int", vec!["(>= b 0)", "(< b 64)"], "(Int (<< a b))")],
+        Shr => vec![fold("Int", vec!["(>= b 0)", "(< b 64)"], "(Int (>> a b))")],
+        Smax => vec![
+            fold("Int", vec!["(> a b)"], "(Int a)"),
+            fold("Int", vec!["(<= a b)"], "(Int b)"),
+        ],
+        Smin => vec![
+            fold("Int", vec!["(< a b)"], "(Int a)"),
+            fold("Int", vec!["(>= a b)"], "(Int b)"),
+        ],
+        Eq => vec![
+            fold("Int", vec!["(= a b)"], "(Bool true)"),
+            fold("Int", vec!["(!= a b)"], "(Bool false)"),
+        ],
+        LessThan => vec![
+            fold("Int", vec!["(< a b)"], "(Bool true)"),
+            fold("Int", vec!["(>= a b)"], "(Bool false)"),
+        ],
+        GreaterThan => vec![
+            fold("Int", vec!["(> a b)"], "(Bool true)"),
+            fold("Int", vec!["(<= a b)"], "(Bool false)"),
+        ],
+        LessEq => vec![
+            fold("Int", vec!["(<= a b)"], "(Bool true)"),
+            fold("Int", vec!["(> a b)"], "(Bool false)"),
+        ],
+        GreaterEq => vec![
+            fold("Int", vec!["(>= a b)"], "(Bool true)"),
+            fold("Int", vec!["(< a b)"], "(Bool false)"),
+        ],
+        FAdd => vec![fold("Float", finite_result("(= r (+ a b))"), "(Float r)")],
+        FSub => vec![fold("Float", finite_result("(= r (- a b))"), "(Float r)")],
+        FMul => vec![fold("Float", finite_result("(= r (* a b))"), "(Float r)")],
+        FDiv => vec![fold("Float", finite_result("(= r (/ a b))"), "(Float r)")],
+        // -0.0 and 0.0 are different values but compare equal,
+        // so equality is checked with <= and >=
+        FEq => vec![
+            fold(
+                "Float",
+                finite_operands(&["(<= a b)", "(>= a b)"]),
+                "(Bool true)",
+            ),
+            fold("Float", finite_operands(&["(< a b)"]), "(Bool false)"),
+            fold("Float", finite_operands(&["(> a b)"]), "(Bool false)"),
+        ],
+        FLessThan => vec![
+            fold("Float", finite_operands(&["(< a b)"]), "(Bool true)"),
+            fold("Float", finite_operands(&["(>= a b)"]), "(Bool false)"),
+        ],
+        FGreaterThan => vec![
+            fold("Float", finite_operands(&["(> a b)"]), "(Bool true)"),
+            fold("Float", finite_operands(&["(<= a b)"]), "(Bool false)"),
+        ],
+        FLessEq => vec![
+            fold("Float", finite_operands(&["(<= a b)"]), "(Bool true)"),
+            fold("Float", finite_operands(&["(> a b)"]), "(Bool false)"),
+        ],
+        FGreaterEq => vec![
+            fold("Float", finite_operands(&["(>= a b)"]), "(Bool true)"),
+            fold("Float", finite_operands(&["(< a b)"]), "(Bool false)"),
+        ],
+        // like the interpreter, return b unless a is strictly greater (or less)
+        Fmax => vec![
+            fold("Float", finite_operands(&["(> a b)"]), "(Float a)"),
+            fold("Float", finite_operands(&["(<= a b)"]), "(Float b)"),
+        ],
+        Fmin => vec![
+            fold("Float", finite_operands(&["(< a b)"]), "(Float a)"),
+            fold("Float", finite_operands(&["(>= a b)"]), "(Float b)"),
+        ],
+        And => vec![fold("Bool", vec![], "(Bool (and a b))")],
+        Or => vec![fold("Bool", vec![], "(Bool (or a b))")],
+        // these use pointers or the state
+        PtrAdd | Load | Print | Free => vec![],
+    }
+}
+
+fn uop_folds(op: &UnaryOp) -> Vec<Fold> {
+    use UnaryOp::*;
+    match op {
+        Not => vec![fold("Bool", vec![], "(Bool (not a))")],
+    }
+}
+
+fn top_rules(op: &TernaryOp) -> Vec<String> {
+    use TernaryOp::*;
+    match op {
+        Select => vec![
+            "(rewrite (Top (Select) (Const (Bool true) ty ctx) thn els) thn :ruleset constant-folding)"
+                .to_string(),
+            "(rewrite (Top (Select) (Const (Bool false) ty ctx) thn els) els :ruleset constant-folding)"
+                .to_string(),
+        ],
+        // writes to memory
+        Write => vec![],
+    }
+}
+
+fn fold_rule(pattern: String, fold: &Fold) -> String {
+    let guards = fold.guards.join("\n       ");
+    let result = fold.result;
+    format!(
+        "
+(rule ((= lhs {pattern})
+       {guards})
+      ((union lhs (Const {result} ty ctx)))
+      :ruleset constant-folding)"
+    )
+}
+
+pub(crate) fn rules() -> Vec<String> {
+    let bop_rules = BinaryOp::iter().flat_map(|op| {
+        bop_folds(&op)
+            .into_iter()
+            .map(|fold| {
+                let operand = fold.operand;
+                let pattern = format!(
+                    "(Bop ({}) (Const ({operand} a) ty ctx) (Const ({operand} b) ty ctx))",
+                    op.name()
+                );
+                fold_rule(pattern, &fold)
+            })
+            .collect::<Vec<_>>()
+    });
+    let uop_rules = UnaryOp::iter().flat_map(|op| {
+        uop_folds(&op)
+            .into_iter()
+            .map(|fold| {
+                let pattern = format!("(Uop ({}) (Const ({} a) ty ctx))", op.name(), fold.operand);
+                fold_rule(pattern, &fold)
+            })
+            .collect::<Vec<_>>()
+    });
+    let top_rules = TernaryOp::iter().flat_map(|op| top_rules(&op));
+    iter::once("(ruleset constant-folding)".to_string())
+        .chain(bop_rules)
+        .chain(uop_rules)
+        .chain(top_rules)
+        .collect()
+}
+
+#[cfg(test)]
+use crate::{ast::*, interpreter::*, prologue, schema::*};
+
+/// A xorshift generator, so the random constants are the same every run.
+#[cfg(test)]
+struct Rng(u64);
+
+#[cfg(test)]
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// An integer in [-bound, bound]
+    fn int(&mut self, bound: i64) -> i64 {
+        (self.next() % (2 * bound as u64 + 1)) as i64 - bound
+    }
+
+    fn nonzero_int(&mut self, bound: i64) -> i64 {
+        match self.int(bound) {
+            0 => 1,
+            i => i,
+        }
+    }
+
+    /// Quarters, so that sums and products are exact and often equal
+    fn float(&mut self) -> f64 {
+        self.int(32) as f64 / 4.0
+    }
+
+    fn nonzero_float(&mut self) -> f64 {
+        self.nonzero_int(32) as f64 / 4.0
+    }
+
+    fn bool(&mut self) -> bool {
+        self.next() % 2 == 0
+    }
+}
+
+#[cfg(test)]
+fn bool_const(b: bool) -> RcExpr {
+    if b {
+        ttrue()
+    } else {
+        tfalse()
+    }
+}
+
+/// Operands the folding rules for `op` apply to
+#[cfg(test)]
+fn random_operands(op: &BinaryOp, rng: &mut Rng) -> Option<(RcExpr, RcExpr)> {
+    use BinaryOp::*;
+    Some(match op {
+        // any operands, so that many of them overflow and wrap
+        Add | Sub | Mul => (int(rng.next() as i64), int(rng.next() as i64)),
+        Div => (int(rng.next() as i64), int(rng.nonzero_int(1 << 10))),
+        Shl | Shr => (int(rng.int(1 << 40)), int((rng.next() % 64) as i64)),
+        Eq | LessThan | GreaterThan | LessEq | GreaterEq | Smax | Smin => {
+            (int(rng.int(2)), int(rng.int(2)))
+        }
+        FDiv => (float(rng.float()), float(rng.nonzero_float())),
+        FAdd | FSub | FMul | FEq | FLessThan | FGreaterThan | FLessEq | FGreaterEq | Fmax
+        | Fmin => (float(rng.float()), float(rng.float())),
+        And | Or => (bool_const(rng.bool()), bool_const(rng.bool())),
+        PtrAdd | Load | Print | Free => return None,
+    })
+}
+
+#[test]
+fn constant_folding_matches_interpreter() -> crate::Result {
+    let mut rng = Rng(0x2545f4914f6cdd1d);
+    let mut exprs = vec![];
+    for op in BinaryOp::iter() {
+        for _ in 0..10 {
+            if let Some((a, b)) = random_operands(&op, &mut rng) {
+                exprs.push(RcExpr::new(Expr::Bop(op.clone(), a, b)));
+            }
+        }
+    }
+    // operands at the edges of the integers
+    for (a, b) in [
+        (i64::MAX, 1),
+        (i64::MIN, -1),
+        (i64::MIN, i64::MIN),
+        (i64::MAX, i64::MAX),
+        (1 << 40, 1 << 40),
+        (-1, i64::MIN),
+    ] {
+        exprs.push(add(int(a), int(b)));
+        exprs.push(sub(int(a), int(b)));
+        exprs.push(mul(int(a), int(b)));
+    }
+    exprs.push(div(int(i64::MIN), int(-1)));
+    for _ in 0..4 {
+        exprs.push(not(bool_const(rng.bool())));
+        exprs.push(select(
+            bool_const(rng.bool()),
+            int(rng.int(10)),
+            int(rng.int(10)),
+        ));
+    }
+
+    let mut build = String::new();
+    let mut check = String::new();
+    for (i, expr) in exprs.iter().enumerate() {
+        let Value::Const(folded) = interpret_expr(expr, &val_empty()).value else {
+            panic!("Expected a constant from {expr:?}");
+        };
+        let ty = match folded {
+            Constant::Int(_) => intt(),
+            Constant::Bool(_) => boolt(),
+            Constant::Float(_) => floatt(),
+        };
+        let expected = match folded {
+            Constant::Int(n) => int(n),
+            Constant::Bool(b) => bool_const(b),
+            Constant::Float(f) => float(f.0),
+        };
+        let expr = expr.clone().with_arg_types(emptyt(), base(ty.clone()));
+        let expected = expected.with_arg_types(emptyt(), base(ty));
+        build.push_str(&format!(
+            "(let e{i} {expr})\n(let expected{i} {expected})\n"
+        ));
+        check.push_str(&format!("(check (= e{i} expected{i}))\n"));
+    }
+
+    let program = format!(
+        "{}\n{build}\n(run-schedule (saturate constant-folding))\n{check}",
+        prologue()
+    );
+    egglog::EGraph::default().parse_and_run_program(None, &program)?;
+    Ok(())
+}
+
+#[test]
+fn constant_folding_skips_errors() -> crate::Result {
+    let exprs = vec![
+        div(int(1), int(0)).with_arg_types(emptyt(), base(intt())),
+        shl(int(1), int(64)).with_arg_types(emptyt(), base(intt())),
+        shr(int(1), int(-1)).with_arg_types(emptyt(), base(intt())),
+        fdiv(float(1.0), float(0.0)).with_arg_types(emptyt(), base(floatt())),
+        fdiv(float(0.0), float(0.0)).with_arg_types(emptyt(), base(floatt())),
+        fmul(float(1e300), float(1e300)).with_arg_types(emptyt(), base(floatt())),
+        // -0.0, which egglog can't tell apart from 0.0
+        fmul(float(-1.0), float(0.0)).with_arg_types(emptyt(), base(floatt())),
+        fadd(float(1.0), float(-1.0)).with_arg_types(emptyt(), base(floatt())),
+    ];
+
+    let mut build = String::new();
+    let mut check = String::new();
+    for (i, expr) in exprs.iter().enumerate() {
+        build.push_str(&format!("(let e{i} {expr})\n"));
+        check.push_str(&format!("(fail (check (= e{i} (Const c ty ctx))))\n"));
+    }
+
+    let program = format!(
+        "{}\n{build}\n(run-schedule (saturate constant-folding))\n{check}",
+        prologue()
+    );
+    egglog::EGraph::default().parse_and_run_program(None, &program)?;
+    Ok(())
+}
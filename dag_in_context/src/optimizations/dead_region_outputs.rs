@@ -0,0 +1,493 @@
+//! Removes the outputs of regions that are never read.
+//! Extraction picks each region's outputs as a whole, so an `If`, `Switch`
+//! or `DoWhile` output that nothing downstream reads is still computed.
+//! We find the outputs that are live, starting from the outputs of each function:
+//! an output of an `If` or `Switch` is live when a live expression reads it,
+//! and an output of a `DoWhile` is also live when its body reads it for the next iteration.
+//! Dead outputs are dropped along with their computations,
+//! and so are the inputs that no branch of an `If` or `Switch` reads.
+//! Every `Get` of a trimmed region or argument is renumbered.
+//! States are never dropped, so effects stay in place.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    iter::once,
+    rc::Rc,
+};
+
+use crate::{
+    ast::{get, parallel_vec},
+    optimizations::function_specialization::tuple_elements,
+    schema::{BaseType, Expr, RcExpr, TreeProgram, Type},
+    typechecker::TypeCache,
+};
+
+/// The region whose argument `Arg` refers to: a function, the body of a loop,
+/// or a branch of an `If` or `Switch`
+#[derive(Clone)]
+struct Scope {
+    region: RcExpr,
+    branch: usize,
+}
+
+type ScopeKey = (*const Expr, usize);
+
+impl Scope {
+    fn key(&self) -> ScopeKey {
+        (Rc::as_ptr(&self.region), self.branch)
+    }
+}
+
+// The position of `i` once the elements not in `live` are removed
+fn position(live: &BTreeSet<usize>, i: usize) -> usize {
+    assert!(live.contains(&i), "Expected index {i} to be live");
+    live.range(..i).count()
+}
+
+fn tuple_types(ty: &Type) -> &[BaseType] {
+    let Type::TupleT(tys) = ty else {
+        panic!("Expected tuple type, got {:?}", ty);
+    };
+    tys
+}
+
+struct DeadOutputRemover<'a> {
+    types: &'a TypeCache,
+    /// The indices demanded of each expression in each scope
+    demanded: HashMap<(ScopeKey, *const Expr), BTreeSet<usize>>,
+    /// The live outputs of each region.
+    /// For a `DoWhile`, these are also its live arguments.
+    live_outputs: HashMap<*const Expr, BTreeSet<usize>>,
+    /// The live inputs of each `If` and `Switch`
+    live_inputs: HashMap<*const Expr, BTreeSet<usize>>,
+    /// The scopes each region is used in
+    uses: HashMap<*const Expr, Vec<Scope>>,
+    /// The regions that are used, in the order they were found
+    regions: Vec<RcExpr>,
+    rewritten: HashMap<(ScopeKey, *const Expr), RcExpr>,
+}
+
+impl DeadOutputRemover<'_> {
+    fn type_of(&self, expr: &RcExpr) -> &Type {
+        self.types
+            .get(&Rc::as_ptr(expr))
+            .unwrap_or_else(|| panic!("Expected type for {:?}", expr))
+    }
+
+    fn all_indices(&self, expr: &RcExpr) -> Vec<usize> {
+        match self.type_of(expr) {
+            Type::TupleT(tys) => (0..tys.len()).collect(),
+            _ => vec![0],
+        }
+    }
+
+    fn state_indices(&self, expr: &RcExpr) -> Vec<usize> {
+        match self.type_of(expr) {
+            Type::TupleT(tys) => (0..tys.len())
+                .filter(|i| tys[*i] == BaseType::StateT)
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    fn demand_all(&mut self, scope: &Scope, expr: &RcExpr) {
+        let indices = self.all_indices(expr);
+        self.demand(scope, expr, indices);
+    }
+
+    // Demands elements of a region's inputs or outputs
+    fn demand_elements(&mut self, scope: &Scope, tuple: &RcExpr, indices: Vec<usize>) {
+        match tuple_elements(tuple) {
+            Some(elements) => {
+                for i in indices {
+                    self.demand_all(scope, &elements[i]);
+                }
+            }
+            None => self.demand(scope, tuple, indices),
+        }
+    }
+
+    fn demand(&mut self, scope: &Scope, expr: &RcExpr, indices: Vec<usize>) {
+        let key = (scope.key(), Rc::as_ptr(expr));
+        let first = !self.demanded.contains_key(&key);
+        let demanded = self.demanded.entry(key).or_default();
+        let new = indices
+            .into_iter()
+            .filter(|i| demanded.insert(*i))
+            .collect::<Vec<_>>();
+        if !first && new.is_empty() {
+            return;
+        }
+
+        match expr.as_ref() {
+            Expr::Arg(..) => self.demand_args(scope, new),
+            Expr::Get(tuple, i) if first => self.demand(scope, tuple, vec![*i]),
+            Expr::DoWhile(inputs, body) => {
+                if first {
+                    self.note_use(expr, scope);
+                    let live = self.live_outputs.entry(Rc::as_ptr(expr)).or_default();
+                    let live = live.iter().copied().collect();
+                    self.demand_elements(scope, inputs, live);
+                    let body_scope = Scope {
+                        region: expr.clone(),
+                        branch: 0,
+                    };
+                    self.demand_elements(&body_scope, body, vec![0]);
+                    let states = self.state_indices(inputs);
+                    self.grow_outputs(expr, states);
+                }
+                self.grow_outputs(expr, new);
+            }
+            Expr::If(pred, inputs, ..) | Expr::Switch(pred, inputs, _) => {
+                if first {
+                    self.note_use(expr, scope);
+                    self.demand(scope, pred, vec![0]);
+                    let live = self.live_inputs.entry(Rc::as_ptr(expr)).or_default();
+                    let live = live.iter().copied().collect();
+                    self.demand_elements(scope, inputs, live);
+                    let states = self.state_indices(expr);
+                    self.grow_outputs(expr, states);
+                }
+                self.grow_outputs(expr, new);
+            }
+            _ if first => {
+                for child in expr.children_exprs() {
+                    self.demand_all(scope, &child);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Demands arguments of a scope
+    fn demand_args(&mut self, scope: &Scope, indices: Vec<usize>) {
+        let region = scope.region.clone();
+        match region.as_ref() {
+            Expr::Function(..) => {}
+            Expr::DoWhile(..) => self.grow_outputs(&region, indices),
+            Expr::If(_, inputs, ..) | Expr::Switch(_, inputs, _) => {
+                let live = self.live_inputs.entry(Rc::as_ptr(&region)).or_default();
+                let new = indices
+                    .into_iter()
+                    .filter(|i| live.insert(*i))
+                    .collect::<Vec<_>>();
+                for scope in self.uses[&Rc::as_ptr(&region)].clone() {
+                    self.demand_elements(&scope, inputs, new.clone());
+                }
+            }
+            _ => panic!("Expected region, got {:?}", region),
+        }
+    }
+
+    // Makes outputs of a region live
+    fn grow_outputs(&mut self, region: &RcExpr, indices: Vec<usize>) {
+        let live = self.live_outputs.entry(Rc::as_ptr(region)).or_default();
+        let new = indices
+            .into_iter()
+            .filter(|i| live.insert(*i))
+            .collect::<Vec<_>>();
+        if new.is_empty() {
+            return;
+        }
+        match region.as_ref() {
+            Expr::DoWhile(inputs, body) => {
+                let body_scope = Scope {
+                    region: region.clone(),
+                    branch: 0,
+                };
+                self.demand_elements(&body_scope, body, new.iter().map(|i| i + 1).collect());
+                for scope in self.uses[&Rc::as_ptr(region)].clone() {
+                    self.demand_elements(&scope, inputs, new.clone());
+                }
+            }
+            Expr::If(_, _, then, els) => {
+                for (branch, output) in [then, els].into_iter().enumerate() {
+                    let scope = Scope {
+                        region: region.clone(),
+                        branch,
+                    };
+                    self.demand_elements(&scope, output, new.clone());
+                }
+            }
+            Expr::Switch(_, _, branches) => {
+                for (branch, output) in branches.iter().enumerate() {
+                    let scope = Scope {
+                        region: region.clone(),
+                        branch,
+                    };
+                    self.demand_elements(&scope, output, new.clone());
+                }
+            }
+            _ => panic!("Expected region, got {:?}", region),
+        }
+    }
+
+    fn note_use(&mut self, region: &RcExpr, scope: &Scope) {
+        if !self.uses.contains_key(&Rc::as_ptr(region)) {
+            self.regions.push(region.clone());
+        }
+        self.uses
+            .entry(Rc::as_ptr(region))
+            .or_default()
+            .push(scope.clone());
+    }
+
+    // Whether any region has a dead input or output
+    fn any_dead(&self) -> bool {
+        let arity = |expr: &RcExpr| tuple_types(self.type_of(expr)).len();
+        self.regions.iter().any(|region| {
+            let live_outputs = &self.live_outputs[&Rc::as_ptr(region)];
+            match region.as_ref() {
+                Expr::DoWhile(inputs, _) => live_outputs.len() < arity(inputs),
+                Expr::If(_, inputs, ..) | Expr::Switch(_, inputs, _) => {
+                    live_outputs.len() < arity(region)
+                        || self.live_inputs[&Rc::as_ptr(region)].len() < arity(inputs)
+                }
+                _ => panic!("Expected region, got {:?}", region),
+            }
+        })
+    }
+
+    // The live arguments of a scope, or None if all of them are
+    fn live_args(&self, scope: &Scope) -> Option<&BTreeSet<usize>> {
+        match scope.region.as_ref() {
+            Expr::Function(..) => None,
+            Expr::DoWhile(..) => Some(&self.live_outputs[&Rc::as_ptr(&scope.region)]),
+            _ => Some(&self.live_inputs[&Rc::as_ptr(&scope.region)]),
+        }
+    }
+
+    fn rewrite(&mut self, scope: &Scope, expr: &RcExpr) -> RcExpr {
+        let key = (scope.key(), Rc::as_ptr(expr));
+        if let Some(res) = self.rewritten.get(&key) {
+            return res.clone();
+        }
+        let trimmed_scope = self.live_args(scope).is_some();
+        let res = match expr.as_ref() {
+            // the type checker fills in the new argument type
+            Expr::Arg(_, ctx) if trimmed_scope => Rc::new(Expr::Arg(Type::Unknown, ctx.clone())),
+            Expr::Const(c, _, ctx) if trimmed_scope => {
+                Rc::new(Expr::Const(c.clone(), Type::Unknown, ctx.clone()))
+            }
+            Expr::Empty(_, ctx) if trimmed_scope => {
+                Rc::new(Expr::Empty(Type::Unknown, ctx.clone()))
+            }
+            Expr::Get(tuple, i) => self.rewrite_get(scope, tuple, *i),
+            Expr::DoWhile(inputs, body) => {
+                let live = self.live_outputs[&Rc::as_ptr(expr)].clone();
+                let body_scope = Scope {
+                    region: expr.clone(),
+                    branch: 0,
+                };
+                let outputs = [0].into_iter().chain(live.iter().map(|i| i + 1)).collect();
+                Rc::new(Expr::DoWhile(
+                    self.filter_tuple(scope, inputs, &live),
+                    self.filter_tuple(&body_scope, body, &outputs),
+                ))
+            }
+            Expr::If(pred, inputs, then, els) => {
+                let live_inputs = self.live_inputs[&Rc::as_ptr(expr)].clone();
+                let live_outputs = self.live_outputs[&Rc::as_ptr(expr)].clone();
+                let mut branch = |branch: usize, output: &RcExpr| {
+                    let scope = Scope {
+                        region: expr.clone(),
+                        branch,
+                    };
+                    self.filter_tuple(&scope, output, &live_outputs)
+                };
+                let (then, els) = (branch(0, then), branch(1, els));
+                Rc::new(Expr::If(
+                    self.rewrite(scope, pred),
+                    self.filter_tuple(scope, inputs, &live_inputs),
+                    then,
+                    els,
+                ))
+            }
+            Expr::Switch(pred, inputs, branches) => {
+                let live_inputs = self.live_inputs[&Rc::as_ptr(expr)].clone();
+                let live_outputs = self.live_outputs[&Rc::as_ptr(expr)].clone();
+                let branches = branches
+                    .iter()
+                    .enumerate()
+                    .map(|(branch, output)| {
+                        let scope = Scope {
+                            region: expr.clone(),
+                            branch,
+                        };
+                        self.filter_tuple(&scope, output, &live_outputs)
+                    })
+                    .collect();
+                Rc::new(Expr::Switch(
+                    self.rewrite(scope, pred),
+                    self.filter_tuple(scope, inputs, &live_inputs),
+                    branches,
+                ))
+            }
+            _ => expr.map_expr_children(|child| self.rewrite(scope, child)),
+        };
+        self.rewritten.insert(key, res.clone());
+        res
+    }
+
+    fn rewrite_get(&mut self, scope: &Scope, tuple: &RcExpr, i: usize) -> RcExpr {
+        let index = match tuple.as_ref() {
+            Expr::Arg(..) => self.live_args(scope).map_or(i, |live| position(live, i)),
+            Expr::DoWhile(..) | Expr::If(..) | Expr::Switch(..) => {
+                position(&self.live_outputs[&Rc::as_ptr(tuple)], i)
+            }
+            _ => i,
+        };
+        get(self.rewrite(scope, tuple), index)
+    }
+
+    // The elements of a region's inputs or outputs at `live`
+    fn filter_tuple(&mut self, scope: &Scope, tuple: &RcExpr, live: &BTreeSet<usize>) -> RcExpr {
+        let elements = match tuple_elements(tuple) {
+            Some(elements) => live
+                .iter()
+                .map(|i| self.rewrite(scope, &elements[*i]))
+                .collect::<Vec<_>>(),
+            None => live
+                .iter()
+                .map(|i| self.rewrite_get(scope, tuple, *i))
+                .collect(),
+        };
+        parallel_vec(elements)
+    }
+}
+
+/// Removes the outputs of `If`, `Switch` and `DoWhile` regions that are never read,
+/// and the inputs of `If` and `Switch` regions that no branch reads.
+/// Returns None when every input and output is live.
+/// The result has argument types, but needs context added again.
+pub fn remove_dead_region_outputs(program: &TreeProgram) -> Option<TreeProgram> {
+    let (program, types) = program.with_arg_types_and_cache();
+    let mut remover = DeadOutputRemover {
+        types: &types,
+        demanded: HashMap::new(),
+        live_outputs: HashMap::new(),
+        live_inputs: HashMap::new(),
+        uses: HashMap::new(),
+        regions: vec![],
+        rewritten: HashMap::new(),
+    };
+    let func_scope = |func: &RcExpr| Scope {
+        region: func.clone(),
+        branch: 0,
+    };
+    for func in once(&program.entry).chain(&program.functions) {
+        let Expr::Function(_, _, _, body) = func.as_ref() else {
+            panic!("Expected function, got {:?}", func);
+        };
+        remover.demand_all(&func_scope(func), body);
+    }
+    if !remover.any_dead() {
+        return None;
+    }
+
+    let mut trim =
+        |func: &RcExpr| func.map_expr_children(|body| remover.rewrite(&func_scope(func), body));
+    let trimmed = TreeProgram {
+        entry: trim(&program.entry),
+        functions: program.functions.iter().map(&mut trim).collect(),
+    };
+    Some(trimmed.with_arg_types())
+}
+
+#[cfg(test)]
+fn find_regions(expr: &RcExpr, regions: &mut Vec<RcExpr>) {
+    if matches!(
+        expr.as_ref(),
+        Expr::DoWhile(..) | Expr::If(..) | Expr::Switch(..)
+    ) {
+        regions.push(expr.clone());
+    }
+    for child in expr.children_exprs() {
+        find_regions(&child, regions);
+    }
+}
+
+#[test]
+fn test_remove_dead_region_outputs() {
+    use crate::ast::*;
+    use crate::interpreter::interpret_dag_prog;
+
+    // no branch reads the second input, and nothing reads the second output
+    let branch = tif(
+        less_than(getat(0), int(5)),
+        parallel!(getat(0), int(3), getat(1)),
+        parallel!(add(getat(0), int(1)), mul(getat(0), int(2)), getat(2)),
+        parallel!(getat(0), int(7), getat(2)),
+    );
+    // the loop carries a sum that is never read after the loop
+    let sum_loop = dowhile(
+        parallel!(int(0), int(0), get(branch.clone(), 0), get(branch, 2)),
+        parallel!(
+            less_than(add(getat(0), int(1)), getat(2)),
+            add(getat(0), int(1)),
+            add(getat(1), mul(getat(0), getat(0))),
+            getat(2),
+            getat(3)
+        ),
+    );
+    let main = function(
+        "main",
+        tuplet!(intt(), statet()),
+        tuplet!(intt(), statet()),
+        parallel!(
+            get(sum_loop.clone(), 0),
+            tprint(get(sum_loop.clone(), 2), get(sum_loop, 3))
+        ),
+    );
+    let program = program!(main,);
+
+    let trimmed = remove_dead_region_outputs(&program).unwrap();
+    let mut regions = vec![];
+    find_regions(&trimmed.entry, &mut regions);
+    for region in regions {
+        match region.as_ref() {
+            Expr::DoWhile(inputs, body) => {
+                assert_eq!(tuple_elements(inputs).unwrap().len(), 3);
+                assert_eq!(tuple_elements(body).unwrap().len(), 4);
+            }
+            Expr::If(_, inputs, then, els) => {
+                assert_eq!(tuple_elements(inputs).unwrap().len(), 2);
+                assert_eq!(tuple_elements(then).unwrap().len(), 2);
+                assert_eq!(tuple_elements(els).unwrap().len(), 2);
+            }
+            _ => panic!("Unexpected region {:?}", region),
+        }
+    }
+
+    for n in [3, 8] {
+        let input = tuplev!(intv(n), statev());
+        assert_eq!(
+            interpret_dag_prog(&trimmed, &input),
+            interpret_dag_prog(&program, &input)
+        );
+    }
+}
+
+#[test]
+fn test_no_dead_region_outputs() {
+    use crate::ast::*;
+
+    let sum_loop = dowhile(
+        parallel!(int(0), int(0), getat(0)),
+        parallel!(
+            less_than(add(getat(0), int(1)), getat(2)),
+            add(getat(0), int(1)),
+            add(getat(1), getat(0)),
+            getat(2)
+        ),
+    );
+    let main = function(
+        "main",
+        tuplet!(intt(), statet()),
+        tuplet!(statet()),
+        single(tprint(get(sum_loop, 1), getat(1))),
+    );
+    let program = program!(main,);
+
+    assert!(remove_dead_region_outputs(&program).is_none());
+}
@@ -0,0 +1,62 @@
+//! Tests for the fast-math ruleset
+#![cfg(test)]
+
+use crate::{egglog_test, schedule::fast_math_pass, Result};
+
+fn floatv(f: f64) -> crate::interpreter::Value {
+    use crate::interpreter::Value;
+    use crate::schema::Constant;
+    use ordered_float::OrderedFloat;
+    Value::Const(Constant::Float(OrderedFloat(f)))
+}
+
+#[test]
+fn fast_math_div_by_constant() -> Result {
+    use crate::ast::*;
+
+    // x / 4.0 ~~> x * 0.25
+    let prog = fdiv(arg(), float(4.0)).with_arg_types(base(floatt()), base(floatt()));
+    let expected = fmul(arg(), float(0.25)).with_arg_types(base(floatt()), base(floatt()));
+
+    egglog_test(
+        &format!("{prog}"),
+        &format!(
+            "{}\n(check (= {prog} {expected}))",
+            fast_math_pass().egglog_schedule()
+        ),
+        vec![
+            prog.to_program(base(floatt()), base(floatt())),
+            expected.to_program(base(floatt()), base(floatt())),
+        ],
+        floatv(10.0),
+        floatv(2.5),
+        vec![],
+    )
+}
+
+#[test]
+fn fast_math_common_factor() -> Result {
+    use crate::ast::*;
+
+    // a * b + a * c ~~> a * (b + c)
+    let ty = tuplet!(floatt(), floatt(), floatt());
+    let prog = fadd(fmul(getat(0), getat(1)), fmul(getat(0), getat(2)))
+        .with_arg_types(ty.clone(), base(floatt()));
+    let expected =
+        fmul(getat(0), fadd(getat(1), getat(2))).with_arg_types(ty.clone(), base(floatt()));
+
+    egglog_test(
+        &format!("{prog}"),
+        &format!(
+            "{}\n(check (= {prog} {expected}))",
+            fast_math_pass().egglog_schedule()
+        ),
+        vec![
+            prog.to_program(ty.clone(), base(floatt())),
+            expected.to_program(ty, base(floatt())),
+        ],
+        tuplev!(floatv(2.0), floatv(3.0), floatv(4.0)),
+        floatv(14.0),
+        vec![],
+    )
+}
@@ -1,4 +1,4 @@
-use std::{rc::Rc, vec};
+use std::{cmp::Reverse, rc::Rc, vec};
 
 use egglog::Term;
 use indexmap::{IndexMap, IndexSet};
@@ -6,6 +6,7 @@ use indexmap::{IndexMap, IndexSet};
 use crate::{
     add_context::ContextCache,
     print_with_intermediate_helper,
+    profile::ProfileData,
     schema::{Expr, RcExpr, TreeProgram},
     to_egglog::TreeToEgglog,
 };
@@ -17,7 +18,7 @@ pub struct CallBody {
 }
 
 // Gets a set of all the calls in the program
-fn get_calls_with_cache(
+pub(crate) fn get_calls_with_cache(
     expr: &RcExpr,
     calls: &mut Vec<RcExpr>,
     seen_exprs: &mut IndexSet<*const Expr>,
@@ -125,6 +126,29 @@ pub fn function_inlining_pairs(
     inlined_calls
 }
 
+/// Keeps the pairs for the `max_call_sites` call sites that ran the most
+/// times in `profile`, hottest first, dropping calls that never ran.
+/// Pairs for calls that aren't in the profile, like the calls
+/// in inlined bodies, are kept after them.
+pub(crate) fn hottest_inlining_pairs(
+    pairs: Vec<CallBody>,
+    profile: &ProfileData,
+    max_call_sites: usize,
+) -> Vec<CallBody> {
+    let (mut profiled, unprofiled): (Vec<_>, Vec<_>) = pairs
+        .into_iter()
+        .map(|pair| (profile.call_count(&pair.call), pair))
+        .partition(|(count, _)| count.is_some());
+    profiled.retain(|(count, _)| *count != Some(0));
+    profiled.sort_by_key(|(count, _)| Reverse(*count));
+    profiled.truncate(max_call_sites);
+    profiled
+        .into_iter()
+        .chain(unprofiled)
+        .map(|(_, pair)| pair)
+        .collect()
+}
+
 // Returns a formatted string of (union call body) for each pair
 pub fn print_function_inlining_pairs(
     function_inlining_pairs: Vec<CallBody>,
@@ -259,3 +283,49 @@ fn test_inf_recursion_function_inlining_pairs() {
         assert_eq!(pairs.len(), iterations);
     }
 }
+
+// With a limit of one call site, only the call that ran the most is inlined
+#[test]
+fn test_hot_calls_inlined_first() {
+    use crate::ast::*;
+    use crate::interpreter::profile_dag_prog;
+
+    let hot_call = call("inc", getat(0));
+    let my_loop = dowhile(
+        parallel!(arg()),
+        parallel!(less_than(hot_call.clone(), int(10)), hot_call),
+    );
+    let program = program!(
+        function(
+            "main",
+            base(intt()),
+            base(intt()),
+            add(call("dec", arg()), get(my_loop, 0)),
+        ),
+        function("inc", base(intt()), base(intt()), add(arg(), int(1))),
+        function("dec", base(intt()), base(intt()), sub(arg(), int(1))),
+    );
+    let (_res, profile) = profile_dag_prog(&program, &intv(0));
+
+    let pairs = function_inlining_pairs(
+        &program,
+        vec!["main".to_string()],
+        1,
+        &mut ContextCache::new(),
+    );
+    let callees = |pairs: &[CallBody]| {
+        pairs
+            .iter()
+            .map(|pair| match pair.call.as_ref() {
+                Expr::Call(callee, _) => callee.clone(),
+                _ => panic!("Expected call"),
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(callees(&pairs), vec!["dec", "inc"]);
+
+    let hottest = hottest_inlining_pairs(pairs.clone(), &profile, 2);
+    assert_eq!(callees(&hottest), vec!["inc", "dec"]);
+    let hottest = hottest_inlining_pairs(pairs, &profile, 1);
+    assert_eq!(callees(&hottest), vec!["inc"]);
+}
@@ -0,0 +1,324 @@
+//! Function specialization: a call that passes constant arguments
+//! is redirected to a copy of the callee with those constants substituted in.
+//! The copy is optimized in the same e-graph as the original function.
+//! After extraction, we keep the copy only if it is cheaper than the original;
+//! otherwise its calls go back to the original function.
+//! Arguments known only to lie in a range are not specialized on:
+//! a copy can't assume a range, since a function body has no way
+//! to state facts about its arguments other than substituting them.
+
+use std::{collections::HashMap, rc::Rc};
+
+use indexmap::{IndexMap, IndexSet};
+
+use crate::{
+    add_context::ContextCache,
+    ast::{arg_ty_ctx, function, get, parallel_vec},
+    config,
+    greedy_dag_extractor::Cost,
+    optimizations::function_inlining::get_calls_with_cache,
+    schema::{Assumption, Constant, Expr, RcExpr, TreeProgram},
+};
+
+pub struct Specialization {
+    /// The function that was copied
+    pub original: String,
+    /// The copy with some arguments replaced by constants
+    pub specialized: String,
+}
+
+// The elements of a tuple built from Empty, Single, and Concat
+pub(crate) fn tuple_elements(tuple: &RcExpr) -> Option<Vec<RcExpr>> {
+    match tuple.as_ref() {
+        Expr::Empty(_, _) => Some(vec![]),
+        Expr::Single(e) => Some(vec![e.clone()]),
+        Expr::Concat(l, r) => {
+            let mut elements = tuple_elements(l)?;
+            elements.extend(tuple_elements(r)?);
+            Some(elements)
+        }
+        _ => None,
+    }
+}
+
+// For each argument of a call, the constant passed, if any
+fn constant_args(args: &RcExpr) -> Option<Vec<Option<Constant>>> {
+    let elements = tuple_elements(args)?;
+    Some(
+        elements
+            .iter()
+            .map(|e| match e.as_ref() {
+                Expr::Const(c, _, _) => Some(c.clone()),
+                _ => None,
+            })
+            .collect(),
+    )
+}
+
+// Copies `func` as a function called `name`, which ignores the arguments
+// that have a constant in `constants` and uses the constant instead.
+// The copy keeps the signature of `func`, so calls can switch between the two.
+fn specialized_function(func: &RcExpr, name: &str, constants: &[Option<Constant>]) -> RcExpr {
+    let input_ty = func.func_input_ty().expect("Func has input type");
+    let ctx = Assumption::InFunc(name.to_string());
+    let arg = arg_ty_ctx(input_ty.clone(), ctx.clone());
+    let substituted = parallel_vec(constants.iter().enumerate().map(|(i, c)| match c {
+        Some(c) => Rc::new(Expr::Const(c.clone(), input_ty.clone(), ctx.clone())),
+        None => get(arg.clone(), i),
+    }));
+    let body = Expr::subst(
+        &substituted,
+        func.func_body().expect("Func has body"),
+        &mut ContextCache::new(),
+    );
+    function(
+        name,
+        input_ty,
+        func.func_output_ty().expect("Func has output type"),
+        body,
+    )
+}
+
+// Renames the callee of each call for which `rename` returns a new name
+fn rename_calls(
+    expr: &RcExpr,
+    rename: &impl Fn(&str, &RcExpr) -> Option<String>,
+    cache: &mut HashMap<*const Expr, RcExpr>,
+) -> RcExpr {
+    if let Some(renamed) = cache.get(&Rc::as_ptr(expr)) {
+        return renamed.clone();
+    }
+    let mapped = expr.map_expr_children(|child| rename_calls(child, rename, cache));
+    let renamed = match mapped.as_ref() {
+        Expr::Call(callee, args) => match rename(callee, args) {
+            Some(new_callee) => Rc::new(Expr::Call(new_callee, args.clone())),
+            None => mapped,
+        },
+        _ => mapped,
+    };
+    cache.insert(Rc::as_ptr(expr), renamed.clone());
+    renamed
+}
+
+/// Adds a specialized copy of a function for each distinct pattern of
+/// constant arguments it is called with, and redirects those calls to the copy.
+/// Only calls whose arguments are a tuple literal are specialized.
+/// Returns the new program, with context added, and the specializations made.
+pub fn specialize_functions(program: &TreeProgram) -> (TreeProgram, Vec<Specialization>) {
+    let mut calls = vec![];
+    let mut seen_exprs = IndexSet::new();
+    for func in program.fns() {
+        get_calls_with_cache(
+            program.get_function(&func).unwrap(),
+            &mut calls,
+            &mut seen_exprs,
+        );
+    }
+
+    let mut names = program.fns().into_iter().collect::<IndexSet<_>>();
+    let mut specialized_names: IndexMap<(String, Vec<Option<Constant>>), String> = IndexMap::new();
+    let mut specialized_fns = vec![];
+    let mut specializations: Vec<Specialization> = vec![];
+    for call in calls {
+        let Expr::Call(callee, args) = call.as_ref() else {
+            panic!("Expected call");
+        };
+        let Some(constants) = constant_args(args) else {
+            continue;
+        };
+        let key = (callee.clone(), constants);
+        if key.1.iter().all(Option::is_none) || specialized_names.contains_key(&key) {
+            continue;
+        }
+        let num_copies = specializations
+            .iter()
+            .filter(|s| &s.original == callee)
+            .count();
+        if num_copies >= config::FUNCTION_SPECIALIZATIONS_PER_FUNCTION {
+            continue;
+        }
+
+        let name = (0..)
+            .map(|i| format!("{callee}_spec{i}"))
+            .find(|name| !names.contains(name))
+            .unwrap();
+        names.insert(name.clone());
+        let func = program.get_function(callee).expect("Callee exists");
+        specialized_fns.push(specialized_function(func, &name, &key.1));
+        specializations.push(Specialization {
+            original: callee.clone(),
+            specialized: name.clone(),
+        });
+        specialized_names.insert(key, name);
+    }
+
+    if specializations.is_empty() {
+        return (program.clone(), specializations);
+    }
+
+    // Calls in the copies are redirected too, so a recursive call
+    // with the same constants stays in the copy.
+    let rename = |callee: &str, args: &RcExpr| {
+        let constants = constant_args(args)?;
+        specialized_names
+            .get(&(callee.to_string(), constants))
+            .cloned()
+    };
+    let mut cache = HashMap::new();
+    let redirected = TreeProgram {
+        entry: rename_calls(&program.entry, &rename, &mut cache),
+        functions: program
+            .functions
+            .iter()
+            .chain(specialized_fns.iter())
+            .map(|func| rename_calls(func, &rename, &mut cache))
+            .collect(),
+    };
+    // Redirecting calls changes the inputs of loops and branches,
+    // so their contexts need to be rebuilt.
+    (redirected.add_context().0, specializations)
+}
+
+/// Undoes the specializations that didn't extract cheaper than their original,
+/// given the extracted cost of each function.
+/// Calls to such a copy go back to the original function, and the copy is removed.
+/// A specialization whose copy or original wasn't extracted in this batch
+/// has no costs to compare, so it is undone too.
+pub(crate) fn keep_cheaper_specializations(
+    program: &TreeProgram,
+    specializations: &[Specialization],
+    fn_costs: &IndexMap<String, Cost>,
+) -> TreeProgram {
+    let reverted = specializations
+        .iter()
+        .filter(
+            |s| match (fn_costs.get(&s.specialized), fn_costs.get(&s.original)) {
+                (Some(specialized), Some(original)) => specialized >= original,
+                _ => true,
+            },
+        )
+        .map(|s| (s.specialized.clone(), s.original.clone()))
+        .collect::<IndexMap<_, _>>();
+    if reverted.is_empty() {
+        return program.clone();
+    }
+
+    let rename = |callee: &str, _args: &RcExpr| reverted.get(callee).cloned();
+    let mut cache = HashMap::new();
+    TreeProgram {
+        entry: rename_calls(&program.entry, &rename, &mut cache),
+        functions: program
+            .functions
+            .iter()
+            .filter(|func| !reverted.contains_key(&func.func_name().expect("Func has name")))
+            .map(|func| rename_calls(func, &rename, &mut cache))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+fn calls_to(program: &TreeProgram, callee: &str) -> usize {
+    let mut calls = vec![];
+    let mut seen_exprs = IndexSet::new();
+    for func in program.fns() {
+        get_calls_with_cache(
+            program.get_function(&func).unwrap(),
+            &mut calls,
+            &mut seen_exprs,
+        );
+    }
+    calls
+        .iter()
+        .filter(|call| matches!(call.as_ref(), Expr::Call(name, _) if name == callee))
+        .count()
+}
+
+#[test]
+fn test_specialize_constant_args() {
+    use crate::ast::*;
+    use crate::interpreter::interpret_dag_prog;
+
+    // scale(x, k) = x * k + k
+    let scale = function(
+        "scale",
+        tuplet!(intt(), intt()),
+        base(intt()),
+        add(mul(getat(0), getat(1)), getat(1)),
+    );
+    // scale(x, 3) + scale(x + 1, 3) + scale(x, x)
+    let main = function(
+        "main",
+        tuplet!(intt()),
+        base(intt()),
+        add(
+            add(
+                call("scale", parallel!(getat(0), int(3))),
+                call("scale", parallel!(add(getat(0), int(1)), int(3))),
+            ),
+            call("scale", parallel!(getat(0), getat(0))),
+        ),
+    );
+    let program = program!(main, scale);
+
+    let (specialized, specializations) = specialize_functions(&program);
+
+    // both calls with k = 3 share a copy
+    assert_eq!(specializations.len(), 1);
+    assert_eq!(specializations[0].original, "scale");
+    assert_eq!(specializations[0].specialized, "scale_spec0");
+    assert_eq!(calls_to(&specialized, "scale_spec0"), 2);
+    assert_eq!(calls_to(&specialized, "scale"), 1);
+
+    let input = tuplev!(intv(2));
+    let expected = interpret_dag_prog(&program, &input).0;
+    assert_eq!(interpret_dag_prog(&specialized, &input).0, expected);
+
+    // the copy isn't cheaper, so its calls go back to the original
+    let fn_costs = IndexMap::from([
+        ("main".to_string(), Cost::new(30.).unwrap()),
+        ("scale".to_string(), Cost::new(5.).unwrap()),
+        ("scale_spec0".to_string(), Cost::new(5.).unwrap()),
+    ]);
+    let reverted = keep_cheaper_specializations(&specialized, &specializations, &fn_costs);
+    assert_eq!(
+        reverted.fns(),
+        vec!["main".to_string(), "scale".to_string()]
+    );
+    assert_eq!(calls_to(&reverted, "scale"), 3);
+    assert_eq!(interpret_dag_prog(&reverted, &input).0, expected);
+
+    // a cheaper copy is kept
+    let fn_costs = IndexMap::from([
+        ("main".to_string(), Cost::new(30.).unwrap()),
+        ("scale".to_string(), Cost::new(5.).unwrap()),
+        ("scale_spec0".to_string(), Cost::new(3.).unwrap()),
+    ]);
+    let kept = keep_cheaper_specializations(&specialized, &specializations, &fn_costs);
+    assert_eq!(calls_to(&kept, "scale_spec0"), 2);
+
+    // without a cost for the copy, the original is kept
+    let fn_costs = IndexMap::from([
+        ("main".to_string(), Cost::new(30.).unwrap()),
+        ("scale".to_string(), Cost::new(5.).unwrap()),
+    ]);
+    let missing = keep_cheaper_specializations(&specialized, &specializations, &fn_costs);
+    assert_eq!(calls_to(&missing, "scale"), 3);
+    assert_eq!(calls_to(&missing, "scale_spec0"), 0);
+}
+
+#[test]
+fn test_no_specialization_without_constants() {
+    use crate::ast::*;
+
+    let inc = function("inc", tuplet!(intt()), base(intt()), add(getat(0), int(1)));
+    let main = function(
+        "main",
+        tuplet!(intt()),
+        base(intt()),
+        call("inc", parallel!(getat(0))),
+    );
+    let program = program!(main, inc);
+
+    let (_specialized, specializations) = specialize_functions(&program);
+    assert!(specializations.is_empty());
+}
@@ -0,0 +1,75 @@
+//! Tests for the loop-closed-form ruleset
+#![cfg(test)]
+
+use crate::{egglog_test, Result};
+
+#[test]
+fn loop_sum_of_counter_closed_form() -> Result {
+    use crate::ast::*;
+    // i = 0; sum = 0; x = 7
+    // do { sum = sum + i; x = x + 3; i = i + 1 } while (i < n)
+    // the exit values of sum, x and i are computed after the loop
+    let lp = dowhile(
+        parallel!(int(0), int(0), int(7), getat(0)),
+        parallel!(
+            less_than(add(getat(0), int(1)), getat(3)),
+            add(getat(0), int(1)),
+            add(getat(1), getat(0)),
+            add(getat(2), int(3)),
+            getat(3)
+        ),
+    );
+    let prog = add(get(lp.clone(), 1), get(lp, 2)).with_arg_types(tuplet!(intt()), base(intt()));
+
+    egglog_test(
+        &format!("{prog}"),
+        "
+(check (= loop (DoWhile inputs outputs))
+       (LoopTripCount loop 0 1 trips)
+       (= (Get loop 0) (Bop (Add) (Get inputs 0) (Bop (Mul) trips c-one)))
+       (= (Get loop 2) (Bop (Add) (Get inputs 2) (Bop (Mul) trips c-three)))
+       (= (Get loop 1)
+          (Bop (Add) (Get inputs 1)
+                     (Bop (Add) (Bop (Mul) trips (Get inputs 0))
+                                (Bop (Mul) c-one (TriangularTrips trips))))))",
+        vec![prog.to_program(tuplet!(intt()), base(intt()))],
+        tuplev!(intv(5)),
+        // 0 + 1 + 2 + 3 + 4, and 7 + 5 * 3
+        intv(32),
+        vec![],
+    )
+}
+
+#[test]
+fn loop_exit_value_checked_before_update() -> Result {
+    use crate::ast::*;
+    // i = 2; sum = 0
+    // do { sum = sum + i; i = i + 2 } while (i < n)
+    // the body runs for i = 2, 4, ..., so the trip count counts the check
+    let lp = dowhile(
+        parallel!(int(2), int(0), getat(0)),
+        parallel!(
+            less_than(getat(0), getat(2)),
+            add(getat(0), int(2)),
+            add(getat(1), getat(0)),
+            getat(2)
+        ),
+    );
+    let prog = get(lp, 1).with_arg_types(tuplet!(intt()), base(intt()));
+
+    egglog_test(
+        &format!("{prog}"),
+        "
+(check (= loop (DoWhile inputs outputs))
+       (LoopTripCount loop 0 2 trips)
+       (= (Get loop 1)
+          (Bop (Add) (Get inputs 1)
+                     (Bop (Add) (Bop (Mul) trips (Get inputs 0))
+                                (Bop (Mul) c-two (TriangularTrips trips))))))",
+        vec![prog.to_program(tuplet!(intt()), base(intt()))],
+        tuplev!(intv(7)),
+        // 2 + 4 + 6 + 8
+        intv(20),
+        vec![],
+    )
+}
@@ -0,0 +1,611 @@
+//! The parts of loop fusion and splitting that egglog rules can't do alone.
+//!
+//! Fusing two loops interleaves their iterations, so `loop_fusion.egg` only fuses
+//! loops that both use the state when `LoopEffectsCommute` says their effects
+//! can be reordered: they only load from and write to pointers into different
+//! allocations, and at most one of them prints.
+//!
+//! Splitting goes the other way. When a loop updates groups of values that
+//! don't read each other, we build a loop for the first group followed by
+//! a loop for the rest, both running the counter and the values the predicate
+//! depends on. Splits are built here, on the program before it is added
+//! to the e-graph, because a body's e-classes can contain equalities that only
+//! hold for the argument values of the original loop.
+//! We only split loops that keep more values live than fit in registers, and
+//! extraction picks a version by `CostModel::loop_live_values_cost`.
+
+use std::rc::Rc;
+
+use egglog::Term;
+use indexmap::{IndexMap, IndexSet};
+
+use crate::{
+    add_context::ContextCache,
+    ast::{arg_ty, dowhile, get, parallel_vec},
+    config,
+    optimizations::{
+        function_specialization::tuple_elements,
+        loop_interchange::{allocation, arg_index, find_loops, passes_through},
+    },
+    print_with_intermediate_helper,
+    schema::{BaseType, BinaryOp, Expr, RcExpr, TernaryOp, Type},
+    to_egglog::TreeToEgglog,
+};
+
+// The arguments that `expr` reads, without looking in the bodies of nested regions
+fn args_read(expr: &RcExpr, num_args: usize, read: &mut IndexSet<usize>) {
+    let mut seen = IndexSet::new();
+    let mut todo = vec![expr.clone()];
+    while let Some(expr) = todo.pop() {
+        if !seen.insert(Rc::as_ptr(&expr)) {
+            continue;
+        }
+        if let Some(k) = arg_index(&expr) {
+            read.insert(k);
+            continue;
+        }
+        match expr.as_ref() {
+            Expr::Arg(..) => read.extend(0..num_args),
+            Expr::DoWhile(inputs, _) => todo.push(inputs.clone()),
+            Expr::If(pred, inputs, _, _) | Expr::Switch(pred, inputs, _) => {
+                todo.extend([pred.clone(), inputs.clone()])
+            }
+            _ => todo.extend(expr.children_exprs()),
+        }
+    }
+}
+
+// How many arguments a loop body with outputs `outputs` uses,
+// other than to pass them through
+fn live_values(outputs: &[RcExpr]) -> usize {
+    let num_args = outputs.len() - 1;
+    let mut read = IndexSet::new();
+    args_read(&outputs[0], num_args, &mut read);
+    for k in 0..num_args {
+        if !passes_through(outputs, k) {
+            args_read(&outputs[k + 1], num_args, &mut read);
+        }
+    }
+    read.len()
+}
+
+// The allocation an address in a loop body points into, when its pointer
+// is an argument that the loop passes through
+fn address_allocation(addr: &RcExpr, inputs: &[RcExpr], outputs: &[RcExpr]) -> Option<i64> {
+    match addr.as_ref() {
+        Expr::Bop(BinaryOp::PtrAdd, ptr, _) => address_allocation(ptr, inputs, outputs),
+        _ => {
+            let k = arg_index(addr)?;
+            if !passes_through(outputs, k) {
+                return None;
+            }
+            allocation(&inputs[k])
+        }
+    }
+}
+
+/// The effects of one loop
+struct LoopEffects {
+    /// The allocations the loop loads from or writes to
+    allocations: IndexSet<i64>,
+    prints: bool,
+}
+
+// The effects of a loop, or None if it has effects other than loads, writes,
+// and prints, or an access whose allocation isn't known
+fn loop_effects(inputs: &[RcExpr], outputs: &[RcExpr]) -> Option<LoopEffects> {
+    let mut effects = LoopEffects {
+        allocations: IndexSet::new(),
+        prints: false,
+    };
+    let mut seen = IndexSet::new();
+    let mut todo = outputs.to_vec();
+    while let Some(expr) = todo.pop() {
+        if !seen.insert(Rc::as_ptr(&expr)) {
+            continue;
+        }
+        match expr.as_ref() {
+            Expr::Bop(BinaryOp::Load, addr, _) | Expr::Top(TernaryOp::Write, addr, _, _) => {
+                effects
+                    .allocations
+                    .insert(address_allocation(addr, inputs, outputs)?);
+            }
+            Expr::Bop(BinaryOp::Print, _, _) => effects.prints = true,
+            Expr::Bop(BinaryOp::Free, _, _)
+            | Expr::Alloc(..)
+            | Expr::Call(..)
+            | Expr::If(..)
+            | Expr::Switch(..)
+            | Expr::DoWhile(..) => return None,
+            _ => {}
+        }
+        todo.extend(expr.children_exprs());
+    }
+    Some(effects)
+}
+
+fn loop_parts(lp: &RcExpr) -> Option<(Vec<RcExpr>, Vec<RcExpr>)> {
+    let Expr::DoWhile(inputs, outputs) = lp.as_ref() else {
+        return None;
+    };
+    Some((tuple_elements(inputs)?, tuple_elements(outputs)?))
+}
+
+/// Finds the pairs of loops in `func` where the second loop takes the state
+/// the first leaves, and the effects of their iterations can be reordered.
+pub(crate) fn commuting_loops(func: &RcExpr) -> Vec<(RcExpr, RcExpr)> {
+    let mut loops = vec![];
+    find_loops(func, &mut loops, &mut IndexSet::new());
+    let mut pairs = vec![];
+    for loop2 in &loops {
+        let Some((inputs2, outputs2)) = loop_parts(loop2) else {
+            continue;
+        };
+        let Some(effects2) = loop_effects(&inputs2, &outputs2) else {
+            continue;
+        };
+        let Expr::DoWhile(_, body2) = loop2.as_ref() else {
+            unreachable!()
+        };
+        let Type::TupleT(types2) = body2.get_arg_type() else {
+            continue;
+        };
+        for (input, ty) in inputs2.iter().zip(types2) {
+            let Expr::Get(loop1, _) = input.as_ref() else {
+                continue;
+            };
+            if ty != BaseType::StateT || !matches!(loop1.as_ref(), Expr::DoWhile(..)) {
+                continue;
+            }
+            let (inputs1, outputs1) = loop_parts(loop1).unwrap();
+            let Some(effects1) = loop_effects(&inputs1, &outputs1) else {
+                continue;
+            };
+            if effects1.allocations.is_disjoint(&effects2.allocations)
+                && !(effects1.prints && effects2.prints)
+            {
+                pairs.push((loop1.clone(), loop2.clone()));
+            }
+        }
+    }
+    pairs
+}
+
+/// A loop split into a loop for one group of the values it updates,
+/// followed by a loop for the rest
+pub(crate) struct LoopSplit {
+    original: RcExpr,
+    first: RcExpr,
+    second: RcExpr,
+    /// The outputs of the original loop, from the second loop, with context
+    pub(crate) split: RcExpr,
+}
+
+// Splits a loop whose arguments have the types `types`, returning the outputs
+// of the original loop from the second loop, and an argument the second loop
+// takes from the first.
+// Both loops take all the arguments, and each passes through the values
+// the other one updates.
+fn split_loop(
+    inputs: &[RcExpr],
+    outputs: &[RcExpr],
+    types: &[BaseType],
+) -> Option<(RcExpr, usize)> {
+    let n = inputs.len();
+    let reads = |k: usize| {
+        let mut read = IndexSet::new();
+        args_read(&outputs[k], n, &mut read);
+        read
+    };
+
+    // the predicate, and the values it depends on, run in both loops
+    let mut control = reads(0);
+    let mut todo = control.iter().copied().collect::<Vec<_>>();
+    while let Some(k) = todo.pop() {
+        for j in reads(k + 1) {
+            if control.insert(j) {
+                todo.push(j);
+            }
+        }
+    }
+    // so they can't have effects
+    if control.iter().any(|k| types[*k] == BaseType::StateT) {
+        return None;
+    }
+
+    // group the other values the loop updates by which ones read each other
+    let updated = (0..n)
+        .filter(|k| !control.contains(k) && !passes_through(outputs, *k))
+        .collect::<Vec<_>>();
+    let mut group = (0..n).collect::<Vec<_>>();
+    fn find(group: &mut [usize], k: usize) -> usize {
+        if group[k] != k {
+            group[k] = find(group, group[k]);
+        }
+        group[k]
+    }
+    for &k in &updated {
+        for j in reads(k + 1) {
+            if updated.contains(&j) {
+                let (a, b) = (find(&mut group, k), find(&mut group, j));
+                group[a.max(b)] = a.min(b);
+            }
+        }
+    }
+    let first_group = find(&mut group, *updated.first()?);
+    let in_first = updated
+        .iter()
+        .copied()
+        .filter(|k| find(&mut group, *k) == first_group)
+        .collect::<IndexSet<_>>();
+    if in_first.len() == updated.len() {
+        return None;
+    }
+
+    let arg = arg_ty(Type::TupleT(types.to_vec()));
+    let first = dowhile(
+        parallel_vec(inputs.to_vec()),
+        parallel_vec(
+            (0..=n)
+                .map(|k| {
+                    if k > 0 && updated.contains(&(k - 1)) && !in_first.contains(&(k - 1)) {
+                        get(arg.clone(), k - 1)
+                    } else {
+                        outputs[k].clone()
+                    }
+                })
+                .collect::<Vec<_>>(),
+        ),
+    );
+    // the second loop starts with the values the first loop leaves in
+    // its group, and the state, which only one of the loops uses
+    let second = dowhile(
+        parallel_vec((0..n).map(|k| {
+            if in_first.contains(&k) || types[k] == BaseType::StateT {
+                get(first.clone(), k)
+            } else {
+                inputs[k].clone()
+            }
+        })),
+        parallel_vec(
+            (0..=n)
+                .map(|k| {
+                    if k > 0 && in_first.contains(&(k - 1)) {
+                        get(arg.clone(), k - 1)
+                    } else {
+                        outputs[k].clone()
+                    }
+                })
+                .collect::<Vec<_>>(),
+        ),
+    );
+    Some((
+        parallel_vec((0..n).map(|k| get(second.clone(), k))),
+        *in_first.get_index(0)?,
+    ))
+}
+
+/// Splits the loops in `func` that keep more values live than fit in registers
+/// and update groups of values that don't read each other.
+/// `func` must have context, and `cache` collects the contexts of the new loops.
+pub(crate) fn loop_splits(func: &RcExpr, cache: &mut ContextCache) -> Vec<LoopSplit> {
+    let mut loops = vec![];
+    find_loops(func, &mut loops, &mut IndexSet::new());
+    loops
+        .iter()
+        .filter_map(|lp| {
+            let (inputs, outputs) = loop_parts(lp)?;
+            if live_values(&outputs) as i64 <= config::LOOP_REGISTERS {
+                return None;
+            }
+            let Expr::DoWhile(_, body) = lp.as_ref() else {
+                unreachable!()
+            };
+            let Type::TupleT(types) = body.get_arg_type() else {
+                return None;
+            };
+            let (split, from_first) = split_loop(&inputs, &outputs, &types)?;
+            let split = split.add_ctx_with_cache(lp.get_ctx().clone(), cache);
+            let Expr::Get(second, _) = tuple_elements(&split)?[0].as_ref() else {
+                unreachable!()
+            };
+            let (second_inputs, _) = loop_parts(second)?;
+            let Expr::Get(first, _) = second_inputs[from_first].as_ref() else {
+                unreachable!()
+            };
+            Some(LoopSplit {
+                original: lp.clone(),
+                first: first.clone(),
+                second: second.clone(),
+                split,
+            })
+        })
+        .collect()
+}
+
+/// Prints the facts about the loops found by `commuting_loops`, and the split
+/// versions of loops from `loop_splits` with how many times their loops run
+/// and how many values they keep live.
+/// Returns the facts to add to the database. The contexts of the new loops
+/// still need the unions from the cache passed to `loop_splits`.
+pub(crate) fn print_loop_fusion_facts(
+    commuting: &[(RcExpr, RcExpr)],
+    splits: &[LoopSplit],
+    printed: &mut String,
+    tree_state: &mut TreeToEgglog,
+    term_cache: &mut IndexMap<Term, String>,
+) -> String {
+    let mut print = |expr: &RcExpr| {
+        let term = expr.to_egglog_with(tree_state);
+        print_with_intermediate_helper(&tree_state.termdag, term, term_cache, printed)
+    };
+    let mut print_loop = |lp: &RcExpr| {
+        let Expr::DoWhile(inputs, outputs) = lp.as_ref() else {
+            panic!("Expected DoWhile");
+        };
+        format!("{} {}", print(inputs), print(outputs))
+    };
+    let mut facts = vec![];
+    for (loop1, loop2) in commuting {
+        facts.push(format!(
+            "(LoopEffectsCommute {} {})",
+            print_loop(loop1),
+            print_loop(loop2)
+        ));
+    }
+    for LoopSplit {
+        original,
+        first,
+        second,
+        ..
+    } in splits
+    {
+        let original_loop = print_loop(original);
+        for lp in [first, second] {
+            facts.push(format!(
+                "(LoopItersPerTile {} {original_loop} 1)",
+                print_loop(lp)
+            ));
+        }
+        for lp in [original, first, second] {
+            let (_, outputs) = loop_parts(lp).unwrap();
+            facts.push(format!(
+                "(set (LoopLiveValues {}) {})",
+                print_loop(lp),
+                live_values(&outputs)
+            ));
+        }
+    }
+    for split in splits {
+        let (original, split) = (print(&split.original), print(&split.split));
+        facts.push(format!("(LoopSplitVariant {original} {split})"));
+    }
+    facts.join("\n")
+}
+
+#[test]
+fn loop_fusion_same_trip_count() -> crate::Result {
+    use crate::ast::*;
+    use crate::egglog_test;
+    // i = 0; sum = 0
+    // do { sum = sum + i; i = i + 1 } while (i < n)
+    // j = 0
+    // do { print j; j = j + 1 } while (j < n)
+    let loop1 = dowhile(
+        parallel!(int(0), int(0), getat(0), getat(1)),
+        parallel!(
+            less_than(add(getat(0), int(1)), getat(2)),
+            add(getat(0), int(1)),
+            add(getat(1), getat(0)),
+            getat(2),
+            getat(3)
+        ),
+    );
+    let loop2 = dowhile(
+        parallel!(int(0), getat(0), get(loop1.clone(), 3)),
+        parallel!(
+            less_than(add(getat(0), int(1)), getat(1)),
+            add(getat(0), int(1)),
+            getat(1),
+            tprint(getat(0), getat(2))
+        ),
+    );
+    let prog = parallel!(get(loop1, 1), get(loop2, 2))
+        .with_arg_types(tuplet!(intt(), statet()), tuplet!(intt(), statet()));
+
+    // the fused loop takes the 4 inputs of loop1 and the 2 pure inputs of loop2
+    egglog_test(
+        &format!("{prog}"),
+        "
+(check (LoopsFused loop1 fused 3)
+       (= fused (DoWhile fused-inputs fused-outputs))
+       (= 6 (tuple-length fused-inputs)))",
+        vec![prog.to_program(tuplet!(intt(), statet()), tuplet!(intt(), statet()))],
+        tuplev!(intv(3), statev()),
+        tuplev!(intv(3), statev()),
+        vec!["0".to_string(), "1".to_string(), "2".to_string()],
+    )
+}
+
+#[test]
+fn loop_fusion_different_trip_count() -> crate::Result {
+    use crate::ast::*;
+    use crate::egglog_test;
+    // the second loop runs one more time than the first
+    let loop1 = dowhile(
+        parallel!(int(0), getat(0), getat(1)),
+        parallel!(
+            less_than(add(getat(0), int(1)), getat(1)),
+            add(getat(0), int(1)),
+            getat(1),
+            getat(2)
+        ),
+    );
+    let loop2 = dowhile(
+        parallel!(int(-1), getat(0), get(loop1.clone(), 2)),
+        parallel!(
+            less_than(add(getat(0), int(1)), getat(1)),
+            add(getat(0), int(1)),
+            getat(1),
+            tprint(getat(0), getat(2))
+        ),
+    );
+    let prog = single(get(loop2, 2)).with_arg_types(tuplet!(intt(), statet()), tuplet!(statet()));
+
+    egglog_test(
+        &format!("{prog}"),
+        "(fail (check (LoopsFused loop1 fused state1)))",
+        vec![prog.to_program(tuplet!(intt(), statet()), tuplet!(statet()))],
+        tuplev!(intv(2), statev()),
+        tuplev!(statev()),
+        vec!["-1".to_string(), "0".to_string(), "1".to_string()],
+    )
+}
+
+// Two loops over n, the first writing i to p[i] and the second writing
+// i + 10 to q[i], with `q_alloc` the allocation q points into.
+// Returns p[2] + q[3] and the state.
+#[cfg(test)]
+fn two_writing_loops(q_alloc: i64) -> RcExpr {
+    use crate::ast::*;
+
+    let mem1 = alloc(0, int(4), getat(1), pointert(intt()));
+    let mem2 = alloc(1, int(4), get(mem1.clone(), 1), pointert(intt()));
+    let q = if q_alloc == 0 {
+        ptradd(get(mem1.clone(), 0), int(0))
+    } else {
+        get(mem2.clone(), 0)
+    };
+    // args: i, p, q, n, state
+    let loop1 = dowhile(
+        parallel!(
+            int(0),
+            get(mem1, 0),
+            q.clone(),
+            getat(0),
+            get(mem2.clone(), 1)
+        ),
+        parallel!(
+            less_than(add(getat(0), int(1)), getat(3)),
+            add(getat(0), int(1)),
+            getat(1),
+            getat(2),
+            getat(3),
+            twrite(ptradd(getat(1), getat(0)), getat(0), getat(4))
+        ),
+    );
+    // args: i, q, n, state
+    let loop2 = dowhile(
+        parallel!(int(0), q, getat(0), get(loop1.clone(), 4)),
+        parallel!(
+            less_than(add(getat(0), int(1)), getat(2)),
+            add(getat(0), int(1)),
+            getat(1),
+            getat(2),
+            twrite(ptradd(getat(1), getat(0)), add(getat(0), int(10)), getat(3))
+        ),
+    );
+    let first = load(ptradd(get(loop1.clone(), 1), int(2)), get(loop2.clone(), 3));
+    let second = load(ptradd(get(loop2, 1), int(3)), get(first.clone(), 1));
+    let freed = free(get(loop1, 1), get(second.clone(), 1));
+    let freed = if q_alloc == 0 {
+        freed
+    } else {
+        free(get(mem2, 0), freed)
+    };
+    parallel!(add(get(first, 0), get(second, 0)), freed)
+        .with_arg_types(tuplet!(intt(), statet()), tuplet!(intt(), statet()))
+}
+
+#[test]
+fn loop_fusion_different_allocations() -> crate::Result {
+    use crate::ast::*;
+    use crate::egglog_test;
+
+    let prog = two_writing_loops(1);
+    let commuting = commuting_loops(&prog);
+    assert_eq!(commuting.len(), 1);
+    let print_loop = |lp: &RcExpr| {
+        let Expr::DoWhile(inputs, outputs) = lp.as_ref() else {
+            panic!("Expected DoWhile");
+        };
+        format!("{inputs} {outputs}")
+    };
+    let (loop1, loop2) = &commuting[0];
+
+    egglog_test(
+        &format!(
+            "{prog}\n(LoopEffectsCommute {} {})",
+            print_loop(loop1),
+            print_loop(loop2)
+        ),
+        "(check (LoopsFused loop1 fused 4))",
+        vec![prog.to_program(tuplet!(intt(), statet()), tuplet!(intt(), statet()))],
+        tuplev!(intv(4), statev()),
+        tuplev!(intv(15), statev()),
+        vec![],
+    )
+}
+
+#[test]
+fn loop_fusion_same_allocation() {
+    // q points into the same allocation as p, so fusing the loops
+    // could reorder a write and a later read of the same address
+    assert!(commuting_loops(&two_writing_loops(0)).is_empty());
+}
+
+#[test]
+fn loop_split_independent_values() {
+    use crate::ast::*;
+    use crate::interpreter::interpret_dag_prog;
+
+    // do { print sum; sum = sum + i; prod = prod * (i + 1); i = i + 1 } while (i < n)
+    // where sum and prod don't read each other
+    let program_with = |lp: RcExpr| {
+        program!(function(
+            "main",
+            tuplet!(intt(), statet()),
+            tuplet!(intt(), statet()),
+            parallel!(add(get(lp.clone(), 1), get(lp.clone(), 2)), get(lp, 4)),
+        ),)
+    };
+    let lp = dowhile(
+        parallel!(int(0), int(0), int(1), getat(0), getat(1)),
+        parallel!(
+            less_than(add(getat(0), int(1)), getat(3)),
+            add(getat(0), int(1)),
+            add(getat(1), getat(0)),
+            mul(getat(2), add(getat(0), int(1))),
+            getat(3),
+            tprint(getat(1), getat(4))
+        ),
+    );
+    let (program, mut cache) = program_with(lp).add_context();
+    let mut loops = vec![];
+    find_loops(&program.entry, &mut loops, &mut IndexSet::new());
+    let (inputs, outputs) = loop_parts(&loops[0]).unwrap();
+    assert_eq!(live_values(&outputs), 5);
+    let types = vec![
+        BaseType::IntT,
+        BaseType::IntT,
+        BaseType::IntT,
+        BaseType::IntT,
+        BaseType::StateT,
+    ];
+    let (split, _) = split_loop(&inputs, &outputs, &types).unwrap();
+    let split = split.add_ctx_with_cache(loops[0].get_ctx().clone(), &mut cache);
+
+    let input = tuplev!(intv(4), statev());
+    let expected = interpret_dag_prog(&program, &input);
+    assert_eq!(
+        expected,
+        (
+            tuplev!(intv(30), statev()),
+            vec!["0", "0", "1", "3"]
+                .into_iter()
+                .map(String::from)
+                .collect()
+        )
+    );
+    assert_eq!(interpret_dag_prog(&program_with(split), &input), expected);
+}
@@ -0,0 +1,973 @@
+//! Loop interchange and tiling for perfect nests of two `DoWhile` loops.
+//! A nest is perfect when the body of the outer loop only runs the inner loop
+//! and counts, and the counters are independent: where the inner counter
+//! starts and stops doesn't depend on the outer counter.
+//!
+//! The addresses of the loads and writes in the inner loop are polynomials
+//! of the two counters and values that don't change in the nest.
+//! When no two accesses to the same address, one of them a write, can be
+//! ordered differently by the interchanged nest, we add the interchanged nest
+//! and a nest with the inner loop split into tiles to the e-graph.
+//! The `loop-interchange` ruleset makes them equal to the original nest,
+//! and extraction picks one by the strides of the accesses in the innermost loop,
+//! see `CostModel::access_stride_cost`.
+
+use std::{collections::BTreeMap, iter::once, rc::Rc};
+
+use egglog::Term;
+use indexmap::{IndexMap, IndexSet};
+
+use crate::{
+    add_context::ContextCache,
+    ast::{add, arg_ty, dowhile, get, int_ty, less_than, parallel_vec, smin},
+    config,
+    optimizations::function_specialization::tuple_elements,
+    print_with_intermediate_helper,
+    schema::{BaseType, BinaryOp, Constant, Expr, RcExpr, TernaryOp, Type},
+    to_egglog::TreeToEgglog,
+};
+
+/// A variable in the polynomial of an address
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Atom {
+    OuterCounter,
+    InnerCounter,
+    /// An argument that the outer loop passes through
+    Invariant(usize),
+    /// An argument of the inner loop computed from invariants,
+    /// in a way that isn't a polynomial
+    Opaque(usize),
+}
+
+/// From each monomial, as a sorted list of atoms, to its coefficient.
+/// Coefficients wrap like the integers in the program.
+type Poly = BTreeMap<Vec<Atom>, i64>;
+
+fn monomial(atoms: Vec<Atom>, coefficient: i64) -> Poly {
+    let mut poly = Poly::new();
+    if coefficient != 0 {
+        poly.insert(atoms, coefficient);
+    }
+    poly
+}
+
+fn poly_add(a: &Poly, b: &Poly, sign: i64) -> Poly {
+    let mut res = a.clone();
+    for (atoms, c) in b {
+        let entry = res.entry(atoms.clone()).or_insert(0);
+        *entry = entry.wrapping_add(c.wrapping_mul(sign));
+    }
+    res.retain(|_, c| *c != 0);
+    res
+}
+
+fn poly_mul(a: &Poly, b: &Poly) -> Poly {
+    let mut res = Poly::new();
+    for (atoms_a, ca) in a {
+        for (atoms_b, cb) in b {
+            let mut atoms = atoms_a.clone();
+            atoms.extend(atoms_b.iter().cloned());
+            atoms.sort();
+            let entry = res.entry(atoms).or_insert(0);
+            *entry = entry.wrapping_add(ca.wrapping_mul(*cb));
+        }
+    }
+    res.retain(|_, c| *c != 0);
+    res
+}
+
+// The coefficient of `atom` in `poly`, or None if `poly` isn't linear in `atom`
+fn coefficient(poly: &Poly, atom: &Atom) -> Option<Poly> {
+    let mut res = Poly::new();
+    for (atoms, c) in poly {
+        match atoms.iter().filter(|a| *a == atom).count() {
+            0 => {}
+            1 => {
+                res.insert(atoms.iter().filter(|a| *a != atom).cloned().collect(), *c);
+            }
+            _ => return None,
+        }
+    }
+    Some(res)
+}
+
+fn as_constant(poly: &Poly) -> Option<i64> {
+    match poly.iter().collect::<Vec<_>>().as_slice() {
+        [] => Some(0),
+        [(atoms, c)] if atoms.is_empty() => Some(**c),
+        _ => None,
+    }
+}
+
+fn is_arg(expr: &RcExpr) -> bool {
+    matches!(expr.as_ref(), Expr::Arg(..))
+}
+
+pub(crate) fn arg_index(expr: &RcExpr) -> Option<usize> {
+    match expr.as_ref() {
+        Expr::Get(arg, i) if is_arg(arg) => Some(*i),
+        _ => None,
+    }
+}
+
+// Whether the loop with outputs `outputs` passes argument `k` through
+pub(crate) fn passes_through(outputs: &[RcExpr], k: usize) -> bool {
+    arg_index(&outputs[k + 1]) == Some(k)
+}
+
+// The polynomial computed by `expr`, where `leaf` gives the polynomial
+// of each argument
+fn poly_of(expr: &RcExpr, leaf: &dyn Fn(usize) -> Option<Poly>) -> Option<Poly> {
+    match expr.as_ref() {
+        Expr::Const(Constant::Int(c), _, _) => Some(monomial(vec![], *c)),
+        Expr::Get(arg, i) if is_arg(arg) => leaf(*i),
+        Expr::Bop(BinaryOp::Add, a, b) => Some(poly_add(&poly_of(a, leaf)?, &poly_of(b, leaf)?, 1)),
+        Expr::Bop(BinaryOp::Sub, a, b) => {
+            Some(poly_add(&poly_of(a, leaf)?, &poly_of(b, leaf)?, -1))
+        }
+        Expr::Bop(BinaryOp::Mul, a, b) => Some(poly_mul(&poly_of(a, leaf)?, &poly_of(b, leaf)?)),
+        _ => None,
+    }
+}
+
+// Whether `expr` computes a value without effects, reading only
+// the arguments `allowed` accepts
+fn pure_over(expr: &RcExpr, allowed: &dyn Fn(usize) -> bool) -> bool {
+    match expr.as_ref() {
+        Expr::Get(arg, i) if is_arg(arg) => allowed(*i),
+        Expr::Const(..) => true,
+        Expr::Bop(BinaryOp::Load | BinaryOp::Print | BinaryOp::Free, _, _) => false,
+        Expr::Bop(_, a, b) => pure_over(a, allowed) && pure_over(b, allowed),
+        Expr::Uop(_, a) => pure_over(a, allowed),
+        Expr::Top(TernaryOp::Select, a, b, c) => {
+            pure_over(a, allowed) && pure_over(b, allowed) && pure_over(c, allowed)
+        }
+        _ => false,
+    }
+}
+
+// The index of the only state in `types`
+fn only_state(types: &[BaseType]) -> Option<usize> {
+    let mut states = types
+        .iter()
+        .enumerate()
+        .filter(|(_, ty)| **ty == BaseType::StateT);
+    match (states.next(), states.next()) {
+        (Some((i, _)), None) => Some(i),
+        _ => None,
+    }
+}
+
+// The allocation that a pointer computed outside the nest points into, if known
+pub(crate) fn allocation(ptr: &RcExpr) -> Option<i64> {
+    match ptr.as_ref() {
+        Expr::Get(alloc, 0) => match alloc.as_ref() {
+            Expr::Alloc(id, ..) => Some(*id),
+            _ => None,
+        },
+        Expr::Bop(BinaryOp::PtrAdd, ptr, _) => allocation(ptr),
+        _ => None,
+    }
+}
+
+enum Bound {
+    Arg(usize),
+    Const(i64),
+}
+
+/// do { ...; i = i + step } while (i < bound)
+/// where the bound is a constant or an argument the loop passes through
+struct Counter {
+    index: usize,
+    step: i64,
+    bound: Bound,
+}
+
+// The counter of a loop with outputs `outputs`, where `resolve` gives
+// the argument an expression in the body is equal to
+fn loop_counter(outputs: &[RcExpr], resolve: &dyn Fn(&RcExpr) -> Option<usize>) -> Option<Counter> {
+    let Expr::Bop(BinaryOp::LessThan, next, bound) = outputs[0].as_ref() else {
+        return None;
+    };
+    let Expr::Bop(BinaryOp::Add, current, step) = next.as_ref() else {
+        return None;
+    };
+    let Expr::Const(Constant::Int(step), _, _) = step.as_ref() else {
+        return None;
+    };
+    let index = resolve(current)?;
+    if *step <= 0 || outputs.get(index + 1) != Some(next) {
+        return None;
+    }
+    let bound = match bound.as_ref() {
+        Expr::Const(Constant::Int(c), _, _) => Bound::Const(*c),
+        _ => {
+            let b = resolve(bound)?;
+            if b == index || resolve(outputs.get(b + 1)?) != Some(b) {
+                return None;
+            }
+            Bound::Arg(b)
+        }
+    };
+    Some(Counter {
+        index,
+        step: *step,
+        bound,
+    })
+}
+
+/// A load or write in the inner loop
+struct Access {
+    write: bool,
+    base: Atom,
+    index: Poly,
+}
+
+// The base pointer and element offset of an address
+fn address(addr: &RcExpr, leaf: &dyn Fn(usize) -> Option<Poly>) -> Option<(Atom, Poly)> {
+    if let Expr::Bop(BinaryOp::PtrAdd, ptr, offset) = addr.as_ref() {
+        let (base, index) = address(ptr, leaf)?;
+        return Some((base, poly_add(&index, &poly_of(offset, leaf)?, 1)));
+    }
+    match poly_of(addr, leaf)?
+        .into_iter()
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        [(atoms, 1)] => match atoms.as_slice() {
+            [base @ (Atom::Invariant(_) | Atom::Opaque(_))] => Some((base.clone(), Poly::new())),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// The loads and writes in a loop body, or None if the body has other effects,
+// control flow, or an access whose address isn't a polynomial
+fn memory_accesses(body: &RcExpr, leaf: &dyn Fn(usize) -> Option<Poly>) -> Option<Vec<Access>> {
+    let mut accesses = vec![];
+    let mut seen = IndexSet::new();
+    let mut todo = vec![body.clone()];
+    while let Some(expr) = todo.pop() {
+        if !seen.insert(Rc::as_ptr(&expr)) {
+            continue;
+        }
+        match expr.as_ref() {
+            Expr::Bop(BinaryOp::Load, addr, _) | Expr::Top(TernaryOp::Write, addr, _, _) => {
+                let (base, index) = address(addr, leaf)?;
+                accesses.push(Access {
+                    write: matches!(expr.as_ref(), Expr::Top(..)),
+                    base,
+                    index,
+                });
+            }
+            Expr::Bop(BinaryOp::Print | BinaryOp::Free, _, _)
+            | Expr::Alloc(..)
+            | Expr::Call(..)
+            | Expr::If(..)
+            | Expr::Switch(..)
+            | Expr::DoWhile(..) => return None,
+            _ => {}
+        }
+        todo.extend(expr.children_exprs());
+    }
+    Some(accesses)
+}
+
+struct LoopNest {
+    outer: RcExpr,
+    inner: RcExpr,
+    outer_inputs: Vec<RcExpr>,
+    outer_types: Vec<BaseType>,
+    outer_counter: Counter,
+    outer_state: usize,
+    /// The inputs of the inner loop, computed from the outer loop's arguments
+    inner_inputs: Vec<RcExpr>,
+    inner_body: RcExpr,
+    inner_counter: Counter,
+    inner_state: usize,
+    /// The polynomial of each argument of the inner loop, if it has one
+    inner_polys: Vec<Option<Poly>>,
+    accesses: Vec<Access>,
+}
+
+// The argument of the outer loop that `expr` is equal to,
+// looking through the arguments the inner loop passes through
+fn resolve_outer(
+    expr: &RcExpr,
+    inner: &RcExpr,
+    inner_inputs: &[RcExpr],
+    inner_outputs: &[RcExpr],
+) -> Option<usize> {
+    match expr.as_ref() {
+        Expr::Get(e, k) if is_arg(e) => Some(*k),
+        Expr::Get(e, k) if Rc::ptr_eq(e, inner) && passes_through(inner_outputs, *k) => {
+            resolve_outer(&inner_inputs[*k], inner, inner_inputs, inner_outputs)
+        }
+        _ => None,
+    }
+}
+
+fn find_nest(outer: &RcExpr) -> Option<LoopNest> {
+    let Expr::DoWhile(outer_inputs, outer_body) = outer.as_ref() else {
+        return None;
+    };
+    let outer_inputs = tuple_elements(outer_inputs)?;
+    let outer_outputs = tuple_elements(outer_body)?;
+    let Type::TupleT(outer_types) = outer_body.get_arg_type() else {
+        return None;
+    };
+    let outer_state = only_state(&outer_types)?;
+
+    // the outer loop's state goes through the inner loop
+    let Expr::Get(inner, inner_state) = outer_outputs[outer_state + 1].as_ref() else {
+        return None;
+    };
+    let Expr::DoWhile(inner_inputs, inner_body) = inner.as_ref() else {
+        return None;
+    };
+    let inner_inputs = tuple_elements(inner_inputs)?;
+    let inner_outputs = tuple_elements(inner_body)?;
+    let Type::TupleT(inner_types) = inner_body.get_arg_type() else {
+        return None;
+    };
+    if only_state(&inner_types)? != *inner_state
+        || arg_index(&inner_inputs[*inner_state]) != Some(outer_state)
+    {
+        return None;
+    }
+
+    let resolve = |e: &RcExpr| resolve_outer(e, inner, &inner_inputs, &inner_outputs);
+    let outer_counter = loop_counter(&outer_outputs, &resolve)?;
+    let inner_counter = loop_counter(&inner_outputs, &arg_index)?;
+    let invariant = |k: usize| k != outer_counter.index && k != outer_state;
+    let stateless = |k: usize| k != outer_state;
+
+    // the outer loop passes everything else through
+    if (0..outer_inputs.len()).any(|k| invariant(k) && resolve(&outer_outputs[k + 1]) != Some(k)) {
+        return None;
+    }
+    // and so does the inner loop
+    if (0..inner_inputs.len()).any(|p| {
+        p != inner_counter.index && p != *inner_state && !passes_through(&inner_outputs, p)
+    }) {
+        return None;
+    }
+    // the inner loop's inputs don't depend on the state,
+    // and where its counter starts and stops doesn't depend on the outer counter
+    if (0..inner_inputs.len())
+        .any(|p| p != *inner_state && !pure_over(&inner_inputs[p], &stateless))
+        || !pure_over(&inner_inputs[inner_counter.index], &invariant)
+        || matches!(inner_counter.bound, Bound::Arg(b) if !pure_over(&inner_inputs[b], &invariant))
+    {
+        return None;
+    }
+
+    let outer_leaf = |k: usize| {
+        if k == outer_counter.index {
+            Some(monomial(vec![Atom::OuterCounter], 1))
+        } else if invariant(k) {
+            Some(monomial(vec![Atom::Invariant(k)], 1))
+        } else {
+            None
+        }
+    };
+    let inner_polys = (0..inner_inputs.len())
+        .map(|p| {
+            if p == inner_counter.index {
+                Some(monomial(vec![Atom::InnerCounter], 1))
+            } else if p == *inner_state {
+                None
+            } else {
+                poly_of(&inner_inputs[p], &outer_leaf).or_else(|| {
+                    pure_over(&inner_inputs[p], &invariant)
+                        .then(|| monomial(vec![Atom::Opaque(p)], 1))
+                })
+            }
+        })
+        .collect::<Vec<_>>();
+    let accesses = memory_accesses(inner_body, &|p| inner_polys[p].clone())?;
+
+    Some(LoopNest {
+        outer: outer.clone(),
+        inner: inner.clone(),
+        outer_inputs,
+        outer_types,
+        outer_counter,
+        outer_state,
+        inner_inputs,
+        inner_body: inner_body.clone(),
+        inner_counter,
+        inner_state: *inner_state,
+        inner_polys,
+        accesses,
+    })
+}
+
+// The arguments of a region with type `ty`
+fn arg_gets(ty: &Type, len: usize) -> Vec<RcExpr> {
+    let arg = arg_ty(ty.clone());
+    (0..len).map(|k| get(arg.clone(), k)).collect()
+}
+
+impl LoopNest {
+    // Whether running the iterations in interchanged order keeps the order
+    // of every two accesses to the same address where one is a write
+    fn can_interchange(&self) -> bool {
+        self.accesses.iter().all(|a| {
+            self.accesses
+                .iter()
+                .all(|b| !(a.write || b.write) || self.keeps_order(a, b))
+        })
+    }
+
+    fn keeps_order(&self, a: &Access, b: &Access) -> bool {
+        if a.base != b.base {
+            // different allocations never overlap
+            let alloc_of = |base: &Atom| match base {
+                Atom::Invariant(k) => allocation(&self.outer_inputs[*k]),
+                _ => None,
+            };
+            return matches!((alloc_of(&a.base), alloc_of(&b.base)), (Some(x), Some(y)) if x != y);
+        }
+        a.index == b.index && self.index_keeps_order(&a.index)
+    }
+
+    // Whether two iterations that reach the same address through `index`
+    // run in the same order in both nests
+    fn index_keeps_order(&self, index: &Poly) -> bool {
+        let (Some(i), Some(j)) = (
+            coefficient(index, &Atom::OuterCounter),
+            coefficient(index, &Atom::InnerCounter),
+        ) else {
+            return false;
+        };
+        let has_counter = |poly: &Poly| {
+            poly.keys()
+                .flatten()
+                .any(|atom| matches!(atom, Atom::OuterCounter | Atom::InnerCounter))
+        };
+        if has_counter(&i) || has_counter(&j) {
+            return false;
+        }
+        let (i, j) = (as_constant(&i), as_constant(&j));
+        match (i, j) {
+            // iterations reach the same address only with the same i, or the same j
+            (Some(0), Some(c)) | (Some(c), Some(0)) => c != 0,
+            // or when i and j both go up or both go down
+            (Some(a), Some(b)) if a.signum() != b.signum() => true,
+            // a row-major index i * rows + j, where j is always less than rows
+            _ => {
+                self.row_major(index, &Atom::OuterCounter, &Atom::InnerCounter)
+                    || self.row_major(index, &Atom::InnerCounter, &Atom::OuterCounter)
+            }
+        }
+    }
+
+    // Whether `index` is `row * len + col + c` or `row * len + c`, where `col`
+    // starts at a non-negative constant and stops before `len`.
+    // If `len` is zero or less, `col` only takes one value.
+    fn row_major(&self, index: &Poly, row: &Atom, col: &Atom) -> bool {
+        let (counter, start) = if *col == Atom::InnerCounter {
+            (
+                &self.inner_counter,
+                &self.inner_inputs[self.inner_counter.index],
+            )
+        } else {
+            (
+                &self.outer_counter,
+                &self.outer_inputs[self.outer_counter.index],
+            )
+        };
+        let len = match (&counter.bound, col) {
+            (Bound::Const(c), _) => monomial(vec![], *c),
+            (Bound::Arg(b), Atom::InnerCounter) => match &self.inner_polys[*b] {
+                Some(poly) => poly.clone(),
+                None => return false,
+            },
+            (Bound::Arg(b), _) => monomial(vec![Atom::Invariant(*b)], 1),
+        };
+        matches!(start.as_ref(), Expr::Const(Constant::Int(s), _, _) if *s >= 0)
+            && coefficient(index, row) == Some(len)
+            && matches!(
+                coefficient(index, col).as_ref().and_then(as_constant),
+                Some(0 | 1)
+            )
+    }
+
+    // How many accesses move by each stride, in elements, per iteration of
+    // the innermost loop, which counts `innermost` by `step`.
+    // In a tile, an access with a large stride still uses each cache line for
+    // the next iteration of the loop around the tile, when that moves by at most an element.
+    fn strides(
+        &self,
+        innermost: (&Atom, i64),
+        around_tile: Option<(&Atom, i64)>,
+    ) -> IndexMap<i64, i64> {
+        let stride = |index: &Poly, (counter, step): (&Atom, i64)| match coefficient(index, counter)
+            .as_ref()
+            .and_then(as_constant)
+        {
+            Some(c) => c.wrapping_mul(step),
+            None => config::UNKNOWN_ACCESS_STRIDE,
+        };
+        let mut strides = IndexMap::new();
+        for access in &self.accesses {
+            let mut s = stride(&access.index, innermost);
+            if let Some(around) = around_tile {
+                if s.abs() > 1 && stride(&access.index, around).abs() <= 1 {
+                    s = 1;
+                }
+            }
+            *strides.entry(s).or_insert(0) += 1;
+        }
+        strides
+    }
+
+    fn outer_counter_step(&self) -> (&Atom, i64) {
+        (&Atom::OuterCounter, self.outer_counter.step)
+    }
+
+    fn inner_counter_step(&self) -> (&Atom, i64) {
+        (&Atom::InnerCounter, self.inner_counter.step)
+    }
+
+    // The type of the outer loop's arguments with `extra` integers after them
+    fn extended_type(&self, extra: usize) -> Type {
+        let mut types = self.outer_types.clone();
+        types.extend((0..extra).map(|_| BaseType::IntT));
+        Type::TupleT(types)
+    }
+
+    // The inputs of the outer loop, followed by the starts of both counters
+    fn extended_inputs(&self) -> RcExpr {
+        let inner_start = Expr::subst(
+            &parallel_vec(self.outer_inputs.clone()),
+            &self.inner_inputs[self.inner_counter.index],
+            &mut ContextCache::new(),
+        );
+        parallel_vec(
+            self.outer_inputs
+                .iter()
+                .cloned()
+                .chain([
+                    self.outer_inputs[self.outer_counter.index].clone(),
+                    inner_start,
+                ])
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    // The outputs of the original nest, from a loop whose first arguments
+    // are the outer loop's
+    fn outputs_of(&self, lp: &RcExpr) -> RcExpr {
+        parallel_vec((0..self.outer_inputs.len()).map(|k| get(lp.clone(), k)))
+    }
+
+    // The bounds of the counters in a region whose first arguments are the outer loop's
+    fn outer_bound(&self, args: &[RcExpr], ty: &Type) -> RcExpr {
+        match self.outer_counter.bound {
+            Bound::Const(c) => int_ty(c, ty.clone()),
+            Bound::Arg(b) => args[b].clone(),
+        }
+    }
+
+    fn inner_bound(&self, args: &[RcExpr], ty: &Type) -> RcExpr {
+        match self.inner_counter.bound {
+            Bound::Const(c) => int_ty(c, ty.clone()),
+            Bound::Arg(b) => Expr::subst(
+                &parallel_vec(args[..self.outer_inputs.len()].to_vec()),
+                &self.inner_inputs[b],
+                &mut ContextCache::new(),
+            ),
+        }
+    }
+
+    // One iteration of the inner loop's body, where the outer loop's arguments
+    // are the first arguments in `args` and the inner counter is `j`
+    fn inner_iteration(&self, args: &[RcExpr], j: &RcExpr) -> RcExpr {
+        let mut cache = ContextCache::new();
+        let outer_args = parallel_vec(args[..self.outer_inputs.len()].to_vec());
+        let inner_args = self
+            .inner_inputs
+            .iter()
+            .enumerate()
+            .map(|(p, input)| {
+                if p == self.inner_counter.index {
+                    j.clone()
+                } else {
+                    Expr::subst(&outer_args, input, &mut cache)
+                }
+            })
+            .collect::<Vec<_>>();
+        Expr::subst(&parallel_vec(inner_args), &self.inner_body, &mut cache)
+    }
+
+    // for j { for i { body } }
+    // The outer loop keeps where i starts at argument n and j at n + 1.
+    fn interchanged(&self) -> RcExpr {
+        let n = self.outer_inputs.len();
+        let (ci, state) = (self.outer_counter.index, self.outer_state);
+        let ty = self.extended_type(2);
+        let outer_args = arg_gets(&ty, n + 2);
+        let inner_args = arg_gets(&ty, n + 2);
+
+        let iteration = self.inner_iteration(&inner_args, &inner_args[n + 1]);
+        let next_i = add(
+            inner_args[ci].clone(),
+            int_ty(self.outer_counter.step, ty.clone()),
+        );
+        let inner_outputs = (0..n + 2).map(|k| {
+            if k == ci {
+                next_i.clone()
+            } else if k == state {
+                get(iteration.clone(), self.inner_state + 1)
+            } else {
+                inner_args[k].clone()
+            }
+        });
+        let inner = dowhile(
+            parallel_vec((0..n + 2).map(|k| outer_args[if k == ci { n } else { k }].clone())),
+            parallel_vec(
+                once(less_than(
+                    next_i.clone(),
+                    self.outer_bound(&inner_args, &ty),
+                ))
+                .chain(inner_outputs)
+                .collect::<Vec<_>>(),
+            ),
+        );
+
+        let next_j = add(
+            outer_args[n + 1].clone(),
+            int_ty(self.inner_counter.step, ty.clone()),
+        );
+        let outer = dowhile(
+            self.extended_inputs(),
+            parallel_vec(
+                once(less_than(
+                    next_j.clone(),
+                    self.inner_bound(&outer_args, &ty),
+                ))
+                .chain((0..n).map(|k| get(inner.clone(), k)))
+                .chain([outer_args[n].clone(), next_j])
+                .collect::<Vec<_>>(),
+            ),
+        );
+        self.outputs_of(&outer)
+    }
+
+    // for each tile of j { for i { for j in the tile { body } } }
+    // The loops keep where i starts at argument n and where the tile starts
+    // at n + 1. The loop in a tile counts j at n + 2.
+    fn tiled(&self) -> RcExpr {
+        let n = self.outer_inputs.len();
+        let (ci, state) = (self.outer_counter.index, self.outer_state);
+        let tile = config::LOOP_TILE_SIZE * self.inner_counter.step;
+        let ty = self.extended_type(2);
+        let tile_ty = self.extended_type(3);
+        let tiles_args = arg_gets(&ty, n + 2);
+        let rows_args = arg_gets(&ty, n + 2);
+        let tile_args = arg_gets(&tile_ty, n + 3);
+
+        let iteration = self.inner_iteration(&tile_args, &tile_args[n + 2]);
+        let next_j = add(
+            tile_args[n + 2].clone(),
+            int_ty(self.inner_counter.step, tile_ty.clone()),
+        );
+        let tile_end = smin(
+            add(tile_args[n + 1].clone(), int_ty(tile, tile_ty.clone())),
+            self.inner_bound(&tile_args, &tile_ty),
+        );
+        let in_tile = dowhile(
+            parallel_vec(
+                rows_args
+                    .iter()
+                    .cloned()
+                    .chain([rows_args[n + 1].clone()])
+                    .collect::<Vec<_>>(),
+            ),
+            parallel_vec(
+                once(less_than(next_j.clone(), tile_end))
+                    .chain((0..n + 3).map(|k| {
+                        if k == state {
+                            get(iteration.clone(), self.inner_state + 1)
+                        } else if k == n + 2 {
+                            next_j.clone()
+                        } else {
+                            tile_args[k].clone()
+                        }
+                    }))
+                    .collect::<Vec<_>>(),
+            ),
+        );
+
+        let next_i = add(
+            rows_args[ci].clone(),
+            int_ty(self.outer_counter.step, ty.clone()),
+        );
+        let rows = dowhile(
+            parallel_vec((0..n + 2).map(|k| tiles_args[if k == ci { n } else { k }].clone())),
+            parallel_vec(
+                once(less_than(next_i.clone(), self.outer_bound(&rows_args, &ty)))
+                    .chain((0..n + 2).map(|k| {
+                        if k == ci {
+                            next_i.clone()
+                        } else if k == state {
+                            get(in_tile.clone(), state)
+                        } else {
+                            rows_args[k].clone()
+                        }
+                    }))
+                    .collect::<Vec<_>>(),
+            ),
+        );
+
+        let next_tile = add(tiles_args[n + 1].clone(), int_ty(tile, ty.clone()));
+        let tiles = dowhile(
+            self.extended_inputs(),
+            parallel_vec(
+                once(less_than(
+                    next_tile.clone(),
+                    self.inner_bound(&tiles_args, &ty),
+                ))
+                .chain((0..n).map(|k| get(rows.clone(), k)))
+                .chain([tiles_args[n].clone(), next_tile])
+                .collect::<Vec<_>>(),
+            ),
+        );
+        self.outputs_of(&tiles)
+    }
+}
+
+/// A perfect loop nest whose accesses allow interchange,
+/// and the versions of it offered to extraction
+pub(crate) struct LoopNestVersions {
+    nest: LoopNest,
+    /// The outputs of the interchanged nest, with context
+    pub(crate) interchanged: RcExpr,
+    /// The outputs of the nest with the inner loop tiled, with context
+    pub(crate) tiled: RcExpr,
+}
+
+pub(crate) fn find_loops(expr: &RcExpr, loops: &mut Vec<RcExpr>, seen: &mut IndexSet<*const Expr>) {
+    if !seen.insert(Rc::as_ptr(expr)) {
+        return;
+    }
+    if let Expr::DoWhile(..) = expr.as_ref() {
+        loops.push(expr.clone());
+    }
+    for child in expr.children_exprs() {
+        find_loops(&child, loops, seen);
+    }
+}
+
+// The loops nested in a version of a nest, from outermost to innermost
+fn loop_chain(version: &RcExpr) -> Vec<RcExpr> {
+    let mut chain = vec![];
+    let mut region = version.clone();
+    loop {
+        let mut loops = vec![];
+        find_loops(&region, &mut loops, &mut IndexSet::new());
+        let Some(lp) = loops.into_iter().find(|lp| !Rc::ptr_eq(lp, &region)) else {
+            return chain;
+        };
+        chain.push(lp.clone());
+        let Expr::DoWhile(_, body) = lp.as_ref() else {
+            unreachable!()
+        };
+        region = body.clone();
+    }
+}
+
+/// Finds the perfect loop nests in `func` that can be interchanged,
+/// and builds their interchanged and tiled versions.
+/// `func` must have context, and `cache` collects the contexts of the new loops.
+pub(crate) fn loop_nest_versions(func: &RcExpr, cache: &mut ContextCache) -> Vec<LoopNestVersions> {
+    let mut loops = vec![];
+    find_loops(func, &mut loops, &mut IndexSet::new());
+    loops
+        .iter()
+        .filter_map(find_nest)
+        .filter(LoopNest::can_interchange)
+        .map(|nest| {
+            let ctx = nest.outer.get_ctx().clone();
+            let interchanged = nest.interchanged().add_ctx_with_cache(ctx.clone(), cache);
+            let tiled = nest.tiled().add_ctx_with_cache(ctx, cache);
+            LoopNestVersions {
+                nest,
+                interchanged,
+                tiled,
+            }
+        })
+        .collect()
+}
+
+/// Prints the versions of each nest, with how many times their loops run
+/// and the strides of the accesses in their innermost loops.
+/// Returns the facts to add to the database. The contexts of the new loops
+/// still need the unions from the cache passed to `loop_nest_versions`.
+pub(crate) fn print_loop_nest_versions(
+    versions: &[LoopNestVersions],
+    printed: &mut String,
+    tree_state: &mut TreeToEgglog,
+    term_cache: &mut IndexMap<Term, String>,
+) -> String {
+    let mut print = |expr: &RcExpr| {
+        let term = expr.to_egglog_with(tree_state);
+        print_with_intermediate_helper(&tree_state.termdag, term, term_cache, printed)
+    };
+    let mut facts = vec![];
+    for LoopNestVersions {
+        nest,
+        interchanged,
+        tiled,
+    } in versions
+    {
+        let mut print_loop = |lp: &RcExpr| {
+            let Expr::DoWhile(inputs, outputs) = lp.as_ref() else {
+                panic!("Expected DoWhile in loop nest");
+            };
+            format!("{} {}", print(inputs), print(outputs))
+        };
+        let outer = print_loop(&nest.outer);
+        let inner = print_loop(&nest.inner);
+        let interchanged_loops = loop_chain(interchanged);
+        let [by_j, by_i] = interchanged_loops.as_slice() else {
+            panic!("Expected two loops in interchanged nest");
+        };
+        let tiled_loops = loop_chain(tiled);
+        let [tiles, rows, in_tile] = tiled_loops.as_slice() else {
+            panic!("Expected three loops in tiled nest");
+        };
+        let tile = config::LOOP_TILE_SIZE;
+        facts.push(format!(
+            "(LoopItersPerTile {} {inner} 1)
+(LoopItersPerTile {} {outer} 1)
+(LoopItersPerTile {} {inner} {tile})
+(LoopItersPerTile {} {outer} 1)
+(LoopItersInTile {} {inner} {tile})",
+            print_loop(by_j),
+            print_loop(by_i),
+            print_loop(tiles),
+            print_loop(rows),
+            print_loop(in_tile),
+        ));
+
+        let strides = [
+            (inner, nest.strides(nest.inner_counter_step(), None)),
+            (
+                print_loop(by_i),
+                nest.strides(nest.outer_counter_step(), None),
+            ),
+            (
+                print_loop(in_tile),
+                nest.strides(nest.inner_counter_step(), Some(nest.outer_counter_step())),
+            ),
+        ];
+        for (lp, strides) in strides {
+            for (stride, count) in strides {
+                facts.push(format!("(set (LoopAccessStrides {lp} {stride}) {count})"));
+            }
+        }
+
+        let nest_var = print(&nest.outer);
+        for version in [interchanged, tiled] {
+            facts.push(format!("(LoopNestVariant {nest_var} {})", print(version)));
+        }
+    }
+    facts.join("\n")
+}
+
+// Loads A[6] and A[13] after a loop nest over a 4x4 matrix A,
+// whose outputs are (i, A, state)
+#[cfg(test)]
+fn matrix_program(nest: RcExpr) -> crate::schema::TreeProgram {
+    use crate::ast::*;
+
+    let ptr = get(nest.clone(), 1);
+    let first = load(ptradd(ptr.clone(), int(6)), get(nest, 2));
+    let second = load(ptradd(ptr.clone(), int(13)), get(first.clone(), 1));
+    let printed = tprint(get(first, 0), get(second.clone(), 1));
+    program!(function(
+        "main",
+        tuplet!(statet()),
+        tuplet!(intt(), statet()),
+        parallel!(get(second, 0), free(ptr, printed)),
+    ),)
+}
+
+// for i in 0..4 { for j in 0..4 { body } }
+// where `state` is the state after the body, computed from the inner loop's
+// arguments (j, i, A, state)
+#[cfg(test)]
+fn matrix_nest(state: RcExpr) -> RcExpr {
+    use crate::ast::*;
+
+    let mem = alloc(0, int(16), getat(0), pointert(intt()));
+    let inner = dowhile(
+        parallel!(int(0), getat(0), getat(1), getat(2)),
+        parallel!(
+            less_than(add(getat(0), int(1)), int(4)),
+            add(getat(0), int(1)),
+            getat(1),
+            getat(2),
+            state,
+        ),
+    );
+    dowhile(
+        parallel!(int(0), get(mem.clone(), 0), get(mem, 1)),
+        parallel!(
+            less_than(add(getat(0), int(1)), int(4)),
+            add(getat(0), int(1)),
+            getat(1),
+            get(inner, 3),
+        ),
+    )
+}
+
+#[test]
+fn test_interchange_row_major_nest() {
+    use crate::ast::*;
+    use crate::interpreter::interpret_dag_prog;
+
+    // A[i * 4 + j] = i * 10 + j
+    let nest = matrix_nest(twrite(
+        ptradd(getat(2), add(mul(getat(1), int(4)), getat(0))),
+        add(mul(getat(1), int(10)), getat(0)),
+        getat(3),
+    ));
+    let (program, _cache) = matrix_program(nest).add_context();
+    let versions = loop_nest_versions(&program.entry, &mut ContextCache::new());
+    assert_eq!(versions.len(), 1);
+
+    let input = tuplev!(statev());
+    let expected = interpret_dag_prog(&program, &input);
+    assert_eq!(expected.1, vec!["12".to_string()]);
+    for version in [&versions[0].interchanged, &versions[0].tiled] {
+        let program = matrix_program(version.clone());
+        assert_eq!(interpret_dag_prog(&program, &input), expected);
+    }
+
+    // walking j in the innermost loop moves by one element
+    let nest = &versions[0].nest;
+    let strides = nest.strides(nest.inner_counter_step(), None);
+    assert_eq!(strides, IndexMap::from([(1, 1)]));
+    let strides = nest.strides(nest.outer_counter_step(), None);
+    assert_eq!(strides, IndexMap::from([(4, 1)]));
+}
+
+#[test]
+fn test_no_interchange_with_dependence() {
+    use crate::ast::*;
+
+    // A[j] = A[j + 1] + i
+    let loaded = load(ptradd(getat(2), add(getat(0), int(1))), getat(3));
+    let nest = matrix_nest(twrite(
+        ptradd(getat(2), getat(0)),
+        add(get(loaded.clone(), 0), getat(1)),
+        get(loaded, 1),
+    ));
+    let (program, _cache) = matrix_program(nest).add_context();
+    assert!(loop_nest_versions(&program.entry, &mut ContextCache::new()).is_empty());
+}
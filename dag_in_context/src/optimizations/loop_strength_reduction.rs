@@ -0,0 +1,34 @@
+//! Tests for the loop-strength-reduction ruleset
+#![cfg(test)]
+
+use crate::{egglog_test, Result};
+
+#[test]
+fn loop_mul_by_invariant() -> Result {
+    use crate::ast::*;
+    // i = 0; accum = 0
+    // do { accum = i * x + accum; i = 1 + i } while (i < n)
+    // i * x is replaced by a new loop variable that is incremented by x
+    let lp = dowhile(
+        parallel!(int(0), int(0), getat(0), getat(1)),
+        parallel!(
+            less_than(getat(0), getat(3)),
+            add(int(1), getat(0)),
+            add(mul(getat(0), getat(2)), getat(1)),
+            getat(2),
+            getat(3)
+        ),
+    );
+    let prog = get(lp, 1).with_arg_types(tuplet!(intt(), intt()), base(intt()));
+
+    egglog_test(
+        &format!("{prog}"),
+        "
+(check (= loop (DoWhile inputs outputs))
+       (= 5 (tuple-length inputs)))",
+        vec![prog.to_program(tuplet!(intt(), intt()), base(intt()))],
+        tuplev!(intv(5), intv(4)),
+        intv(50),
+        vec![],
+    )
+}
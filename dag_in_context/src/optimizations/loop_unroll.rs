@@ -1,5 +1,4 @@
-// TODO enable this test once saturation issue with peeling is resolved
-/*#[test]
+#[test]
 fn loop_peel_once() -> crate::Result {
     use crate::ast::*;
     use crate::egglog_test;
@@ -25,7 +24,38 @@ fn loop_peel_once() -> crate::Result {
         tuplev!(intv(2)),
         vec![],
     )
-}*/
+}
+
+#[test]
+fn loop_peel_removes_dead_loop() -> crate::Result {
+    use crate::ast::*;
+    use crate::egglog_test;
+    // do { i = i + 1 } while (i < 1)
+    // runs exactly once, so after peeling the first iteration
+    // interval analysis proves the remaining loop never runs
+    let prog = dowhile(
+        parallel!(int(0)),
+        parallel!(
+            less_than(add(getat(0), int(1)), int(1)),
+            add(getat(0), int(1))
+        ),
+    )
+    .with_arg_types(base(intt()), tuplet!(intt()));
+
+    let expected = parallel!(int(1)).with_arg_types(base(intt()), tuplet!(intt()));
+
+    egglog_test(
+        &format!("{prog}"),
+        &format!("(check (= {prog} {expected}))"),
+        vec![
+            prog.to_program(base(intt()), tuplet!(intt())),
+            expected.to_program(base(intt()), tuplet!(intt())),
+        ],
+        intv(0),
+        tuplev!(intv(1)),
+        vec![],
+    )
+}
 
 #[test]
 fn loop_unroll_simple() -> crate::Result {
@@ -57,3 +87,154 @@ fn loop_unroll_simple() -> crate::Result {
         vec![],
     )
 }
+
+#[test]
+fn loop_unroll_symbolic_bound() -> crate::Result {
+    use crate::ast::*;
+    use crate::egglog_test;
+    // i = 0
+    // do { i = i + 1 } while (i < n)
+    let prog = dowhile(
+        parallel!(int(0), getat(0)),
+        parallel!(
+            less_than(add(getat(0), int(1)), getat(1)),
+            add(getat(0), int(1)),
+            getat(1)
+        ),
+    )
+    .with_arg_types(base(intt()), tuplet!(intt(), intt()));
+
+    // the loop becomes a guarded remainder loop that runs after the unrolled loop
+    egglog_test(
+        &format!("{prog}"),
+        &format!(
+            "
+(check (= {prog} (If rem-cond rem-inputs (DoWhile rem-loop-inputs rem-outputs) rem-else))
+       (= 0 (LoopUnrollBudget rem-loop-inputs rem-outputs)))"
+        ),
+        vec![prog.to_program(base(intt()), tuplet!(intt(), intt()))],
+        intv(10),
+        tuplev!(intv(10), intv(10)),
+        vec![],
+    )
+}
+
+#[test]
+fn loop_unroll_decrementing() -> crate::Result {
+    use crate::ast::*;
+    use crate::egglog_test;
+    // i = n
+    // do { i = i - 1 } while (0 < i)
+    let prog = dowhile(
+        parallel!(getat(0), int(0)),
+        parallel!(
+            less_than(getat(1), sub(getat(0), int(1))),
+            sub(getat(0), int(1)),
+            getat(1)
+        ),
+    )
+    .with_arg_types(base(intt()), tuplet!(intt(), intt()));
+
+    egglog_test(
+        &format!("{prog}"),
+        &format!(
+            "
+(check (= {prog} (If rem-cond rem-inputs (DoWhile rem-loop-inputs rem-outputs) rem-else))
+       (= 0 (LoopUnrollBudget rem-loop-inputs rem-outputs)))"
+        ),
+        vec![prog.to_program(base(intt()), tuplet!(intt(), intt()))],
+        intv(10),
+        tuplev!(intv(0), intv(0)),
+        vec![],
+    )
+}
+
+#[test]
+fn loop_unroll_less_eq() -> crate::Result {
+    use crate::ast::*;
+    use crate::egglog_test;
+    // i = 0
+    // do { i = i + 1 } while (i <= n)
+    let prog = dowhile(
+        parallel!(int(0), getat(0)),
+        parallel!(
+            less_eq(add(getat(0), int(1)), getat(1)),
+            add(getat(0), int(1)),
+            getat(1)
+        ),
+    )
+    .with_arg_types(base(intt()), tuplet!(intt(), intt()));
+
+    egglog_test(
+        &format!("{prog}"),
+        &format!(
+            "
+(check (= {prog} (If rem-cond rem-inputs (DoWhile rem-loop-inputs rem-outputs) rem-else))
+       (= 0 (LoopUnrollBudget rem-loop-inputs rem-outputs)))"
+        ),
+        vec![prog.to_program(base(intt()), tuplet!(intt(), intt()))],
+        intv(10),
+        tuplev!(intv(11), intv(10)),
+        vec![],
+    )
+}
+
+#[test]
+fn loop_unroll_greater_than() -> crate::Result {
+    use crate::ast::*;
+    use crate::egglog_test;
+    // i = n
+    // do { i = i - 2 } while (i > 0)
+    let prog = dowhile(
+        parallel!(getat(0), int(0)),
+        parallel!(
+            greater_than(sub(getat(0), int(2)), getat(1)),
+            sub(getat(0), int(2)),
+            getat(1)
+        ),
+    )
+    .with_arg_types(base(intt()), tuplet!(intt(), intt()));
+
+    egglog_test(
+        &format!("{prog}"),
+        &format!(
+            "
+(check (= {prog} (If rem-cond rem-inputs (DoWhile rem-loop-inputs rem-outputs) rem-else))
+       (= 0 (LoopUnrollBudget rem-loop-inputs rem-outputs)))"
+        ),
+        vec![prog.to_program(base(intt()), tuplet!(intt(), intt()))],
+        intv(11),
+        tuplev!(intv(-1), intv(0)),
+        vec![],
+    )
+}
+
+#[test]
+fn loop_iters_decrementing_rounds_up() -> crate::Result {
+    use crate::ast::*;
+    use crate::egglog_test;
+    // i = 25
+    // do { i = i - 4 } while (0 < i)
+    // runs 7 times, since the last iteration steps past 0
+    let prog = dowhile(
+        parallel!(int(25)),
+        parallel!(
+            less_than(int(0), sub(getat(0), int(4))),
+            sub(getat(0), int(4))
+        ),
+    )
+    .with_arg_types(emptyt(), tuplet!(intt()));
+
+    egglog_test(
+        &format!("{prog}"),
+        &format!(
+            "
+(check (= {prog} (DoWhile inputs outputs))
+       (= 7 (LoopNumItersGuess inputs outputs)))"
+        ),
+        vec![prog.to_program(emptyt(), tuplet!(intt()))],
+        val_empty(),
+        tuplev!(intv(-3)),
+        vec![],
+    )
+}
@@ -370,8 +370,9 @@ use crate::Value;
 use main_error::MainError;
 
 #[cfg(test)]
-// TODO we don't use memory in the main schedule yet
-// so here enable it for tests
+// The main schedule only runs a few rounds of memory, which isn't always
+// enough for the points-to analysis of nested loops to reach a fixpoint,
+// so run some more rounds in tests
 fn memory_egglog_test(
     build: &str,
     check: &str,
@@ -384,7 +385,6 @@ fn memory_egglog_test(
         build,
         &format!(
             "
-    ;; TODO we don't run memory in the main loop right now
     (run-schedule
         (repeat 6
         (saturate
@@ -473,7 +473,6 @@ fn load_after_write_without_alias() -> crate::Result {
         &format!("{f}"),
         &format!(
             "
-        ;; TODO we don't run memory in the main loop right now
         (run-schedule
           (repeat 6
             (saturate
@@ -544,7 +543,6 @@ fn simple_loop_swap() -> crate::Result {
         &format!("{f}"),
         &format!(
             "
-        ;; TODO we don't run memory in the main loop right now
         (run-schedule
           (repeat 6
             (saturate
@@ -678,7 +676,6 @@ fn redundant_load_elim() -> crate::Result {
         &format!("{f}"),
         &format!(
             "
-        ;; TODO we don't run memory in the main loop right now
         (run-schedule
           (repeat 6
             (saturate
@@ -694,3 +691,77 @@ fn redundant_load_elim() -> crate::Result {
         vec![],
     )
 }
+
+#[test]
+fn dead_store_elim() -> crate::Result {
+    use crate::ast::*;
+    // ptr = alloc int 1;
+    // write ptr 2;
+    // write ptr 3;
+    // free ptr
+    // =>
+    // ptr = alloc int 1;
+    // free ptr
+    let state = getat(0);
+    let ptr_and_state = alloc(0, int(1), state, pointert(intt()));
+    let ptr = get(ptr_and_state.clone(), 0);
+    let allocated = get(ptr_and_state, 1);
+    let state = write(ptr.clone(), int(2), allocated.clone());
+    let state = write(ptr.clone(), int(3), state);
+    let res = free(ptr.clone(), state).with_arg_types(tuplet!(statet()), Type::Base(statet()));
+    let expected = free(ptr, allocated).with_arg_types(tuplet!(statet()), Type::Base(statet()));
+    let f = function("main", tuplet!(statet()), Type::Base(statet()), res.clone())
+        .func_with_arg_types();
+    memory_egglog_test(
+        &format!("{f}"),
+        &format!("(check (= {res} {expected}))"),
+        vec![],
+        val_empty(),
+        val_empty(),
+        vec![],
+    )
+}
+
+#[test]
+fn dead_store_elim_without_alias() -> crate::Result {
+    use crate::ast::*;
+    // p = alloc int 1;
+    // q = alloc int 1;
+    // write p 1;
+    // write q 2;
+    // write p 3;
+    // free p;
+    // free q
+    // =>
+    // p = alloc int 1;
+    // q = alloc int 1;
+    // write q 2;
+    // free p;
+    // free q
+    //
+    // This relies on the alias analysis to work.
+    let state = getat(0);
+    let p_and_state = alloc(0, int(1), state, pointert(intt()));
+    let p = get(p_and_state.clone(), 0);
+    let state = get(p_and_state, 1);
+    let q_and_state = alloc(1, int(1), state, pointert(intt()));
+    let q = get(q_and_state.clone(), 0);
+    let allocated = get(q_and_state, 1);
+    let state = write(p.clone(), int(1), allocated.clone());
+    let state = write(q.clone(), int(2), state);
+    let state = write(p.clone(), int(3), state);
+    let state = free(p.clone(), state);
+    let res = free(q.clone(), state).with_arg_types(tuplet!(statet()), Type::Base(statet()));
+    let expected = free(q.clone(), free(p, write(q, int(2), allocated)))
+        .with_arg_types(tuplet!(statet()), Type::Base(statet()));
+    let f = function("main", tuplet!(statet()), Type::Base(statet()), res.clone())
+        .func_with_arg_types();
+    memory_egglog_test(
+        &format!("{f}"),
+        &format!("(check (= {res} {expected}))"),
+        vec![],
+        val_empty(),
+        val_empty(),
+        vec![],
+    )
+}
@@ -1,11 +1,24 @@
 pub mod body_contains;
 pub mod conditional_invariant_code_motion;
+pub mod constant_folding;
+pub mod dead_region_outputs;
+mod fast_math;
 pub mod function_inlining;
+pub mod function_specialization;
 pub mod is_resolved;
 pub mod is_valid;
+mod loop_closed_form;
+pub mod loop_fusion;
+pub mod loop_interchange;
 pub mod loop_invariant;
+mod loop_strength_reduction;
 pub mod loop_unroll;
 pub mod memory;
 pub mod passthrough;
+mod rec_to_loop;
 mod peepholes;
+pub mod scalar_replacement;
+pub mod select;
+pub mod state_stripping;
+pub mod strength_reduction;
 pub mod switch_rewrites;
@@ -0,0 +1,82 @@
+//! Tests for the rec-to-loop ruleset
+#![cfg(test)]
+
+use crate::{egglog_test, Result};
+
+#[test]
+fn rec_to_loop_fmax_accumulator() -> Result {
+    use crate::ast::*;
+    use crate::interpreter::Value;
+    use crate::schema::Constant;
+    use ordered_float::OrderedFloat;
+
+    // maxf(n) = n > 0 ? fmax(maxf(n - 1), 2.5) : 0.0
+    let rec = call("maxf", parallel!(sub(getat(0), int(1)), getat(1)));
+    let (build, cache) = function(
+        "maxf",
+        tuplet!(intt(), statet()),
+        tuplet!(floatt(), statet()),
+        tif(
+            less_than(int(0), getat(0)),
+            arg(),
+            parallel!(fmax(get(rec.clone(), 0), float(2.5)), get(rec, 1)),
+            parallel!(float(0.0), getat(1)),
+        ),
+    )
+    .func_with_arg_types()
+    .func_add_ctx();
+
+    // the accumulator is the last value in the loop
+    let check = "(check (Function \"maxf\" in out body)
+                        (= body (If pred inputs thn els))
+                        (= thn (Concat (Single (Bop (Fmax) base (Get loop 2))) rest))
+                        (= loop (DoWhile loop-inputs loop-outputs)))";
+
+    egglog_test(
+        &format!("{build}\n{}", cache.get_unions()),
+        check,
+        vec![build.func_to_program()],
+        tuplev!(intv(3), statev()),
+        tuplev!(Value::Const(Constant::Float(OrderedFloat(2.5))), statev()),
+        vec![],
+    )
+}
+
+#[test]
+fn rec_to_loop_two_way_switch() -> Result {
+    use crate::ast::*;
+
+    // count(n, acc) = switch min(n, 1) { 0 => acc, 1 => count(n - 1, acc + 2) }
+    let (build, cache) = function(
+        "count",
+        tuplet!(intt(), intt()),
+        base(intt()),
+        switch_vec(
+            smin(getat(0), int(1)),
+            arg(),
+            vec![
+                getat(1),
+                call(
+                    "count",
+                    parallel!(sub(getat(0), int(1)), add(getat(1), int(2))),
+                ),
+            ],
+        ),
+    )
+    .func_with_arg_types()
+    .func_add_ctx();
+
+    // the switch became an if, and the recursive call a loop
+    let check = "(check (Function \"count\" in out body)
+                        (= body (Get (If pred inputs loop els) 1))
+                        (= loop (DoWhile loop-inputs loop-outputs)))";
+
+    egglog_test(
+        &format!("{build}\n{}", cache.get_unions()),
+        check,
+        vec![build.func_to_program()],
+        tuplev!(intv(4), intv(0)),
+        intv(8),
+        vec![],
+    )
+}
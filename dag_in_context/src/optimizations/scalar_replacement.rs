@@ -0,0 +1,740 @@
+//! Scalar replacement of allocations that don't escape their function.
+//! An `Alloc` of a small constant number of cells doesn't escape when its pointer
+//! is only offset by constants, loaded from, written to, freed,
+//! or passed through tuples into and out of regions.
+//! Such an allocation is replaced by one value per cell,
+//! carried in every tuple next to the state,
+//! so its loads and writes are no longer ordered on the state edge.
+
+use std::{collections::HashMap, rc::Rc};
+
+use indexmap::{IndexMap, IndexSet};
+
+use crate::{
+    ast::{float, get, int, load, parallel_vec, tfalse, tprint, twrite},
+    config,
+    schema::{BaseType, BinaryOp, Constant, Expr, RcExpr, TernaryOp, TreeProgram, Type},
+    typechecker::TypeCache,
+};
+
+/// What a value is, as far as scalar replacement is concerned
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Shape {
+    Value,
+    /// A pointer into the candidate allocation at index `alloc`
+    Ptr {
+        alloc: usize,
+        offset: i64,
+    },
+}
+
+/// The shape of the argument of the current region.
+/// The function's own argument keeps its type,
+/// while region arguments get new types.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Env {
+    arg: Vec<Shape>,
+    top_level: bool,
+}
+
+// An allocation that might be replaced
+struct Candidate {
+    cells: i64,
+    cell_ty: BaseType,
+}
+
+fn base_types(ty: &Type) -> Vec<BaseType> {
+    match ty {
+        Type::TupleT(tys) => tys.clone(),
+        Type::Base(ty) => vec![ty.clone()],
+        _ => panic!("Expected known type, got {:?}", ty),
+    }
+}
+
+// Finds the shape of each expression in a function
+// and which candidates escape.
+struct Analysis<'a> {
+    types: &'a TypeCache,
+    candidates: &'a IndexMap<*const Expr, Candidate>,
+    shapes: HashMap<(*const Expr, Env), Vec<Shape>>,
+    escaped: IndexSet<usize>,
+}
+
+impl Analysis<'_> {
+    fn escape(&mut self, shapes: &[Shape]) {
+        for shape in shapes {
+            if let Shape::Ptr { alloc, .. } = shape {
+                self.escaped.insert(*alloc);
+            }
+        }
+    }
+
+    // Pointers may only be used at offsets inside their allocation
+    fn check_in_bounds(&mut self, shape: &Shape) {
+        if let Shape::Ptr { alloc, offset } = shape {
+            let cells = self.candidates[*alloc].cells;
+            if *offset < 0 || *offset >= cells {
+                self.escaped.insert(*alloc);
+            }
+        }
+    }
+
+    // Shapes that must match, like the outputs of two branches.
+    // Positions that don't match escape.
+    fn join(&mut self, a: &[Shape], b: &[Shape]) -> Vec<Shape> {
+        a.iter()
+            .zip(b)
+            .map(|(x, y)| {
+                if x == y {
+                    x.clone()
+                } else {
+                    self.escape(&[x.clone(), y.clone()]);
+                    Shape::Value
+                }
+            })
+            .collect()
+    }
+
+    fn values(&self, expr: &RcExpr) -> Vec<Shape> {
+        vec![Shape::Value; base_types(&self.types[&Rc::as_ptr(expr)]).len()]
+    }
+
+    fn shape(&mut self, expr: &RcExpr, env: &Env) -> Vec<Shape> {
+        let key = (Rc::as_ptr(expr), env.clone());
+        if let Some(shape) = self.shapes.get(&key) {
+            return shape.clone();
+        }
+        let shape = match expr.as_ref() {
+            Expr::Arg(_, _) => env.arg.clone(),
+            Expr::Const(_, _, _) => vec![Shape::Value],
+            Expr::Empty(_, _) => vec![],
+            Expr::Get(tuple, i) => vec![self.shape(tuple, env)[*i].clone()],
+            Expr::Single(x) => self.shape(x, env),
+            Expr::Concat(x, y) => {
+                let mut shape = self.shape(x, env);
+                shape.extend(self.shape(y, env));
+                shape
+            }
+            Expr::Alloc(_, amount, state, _) => {
+                self.shape(amount, env);
+                self.shape(state, env);
+                match self.candidates.get_index_of(&Rc::as_ptr(expr)) {
+                    Some(alloc) => vec![Shape::Ptr { alloc, offset: 0 }, Shape::Value],
+                    None => vec![Shape::Value, Shape::Value],
+                }
+            }
+            Expr::Bop(BinaryOp::PtrAdd, ptr, offset) => {
+                let ptr = self.shape(ptr, env)[0].clone();
+                let offset_shape = self.shape(offset, env);
+                self.escape(&offset_shape);
+                match (ptr, offset.as_ref()) {
+                    (Shape::Ptr { alloc, offset }, Expr::Const(Constant::Int(c), _, _)) => {
+                        vec![Shape::Ptr {
+                            alloc,
+                            offset: offset + c,
+                        }]
+                    }
+                    (ptr, _) => {
+                        self.escape(&[ptr]);
+                        vec![Shape::Value]
+                    }
+                }
+            }
+            Expr::Bop(op @ (BinaryOp::Load | BinaryOp::Free), ptr, state) => {
+                let ptr = self.shape(ptr, env)[0].clone();
+                self.shape(state, env);
+                self.check_in_bounds(&ptr);
+                if let (BinaryOp::Free, Shape::Ptr { alloc, offset }) = (op, &ptr) {
+                    if *offset != 0 {
+                        self.escaped.insert(*alloc);
+                    }
+                }
+                self.values(expr)
+            }
+            Expr::Top(TernaryOp::Write, ptr, val, state) => {
+                let ptr = self.shape(ptr, env)[0].clone();
+                let val = self.shape(val, env);
+                self.shape(state, env);
+                self.check_in_bounds(&ptr);
+                self.escape(&val);
+                vec![Shape::Value]
+            }
+            Expr::If(pred, inputs, thn, els) => {
+                self.shape(pred, env);
+                let inner = Env {
+                    arg: self.shape(inputs, env),
+                    top_level: false,
+                };
+                let thn = self.shape(thn, &inner);
+                let els = self.shape(els, &inner);
+                self.join(&thn, &els)
+            }
+            Expr::Switch(pred, inputs, branches) => {
+                self.shape(pred, env);
+                let inner = Env {
+                    arg: self.shape(inputs, env),
+                    top_level: false,
+                };
+                let mut shapes = branches
+                    .iter()
+                    .map(|branch| self.shape(branch, &inner))
+                    .collect::<Vec<_>>();
+                let first = shapes.remove(0);
+                shapes
+                    .iter()
+                    .fold(first, |joined, shape| self.join(&joined, shape))
+            }
+            Expr::DoWhile(inputs, pred_and_body) => {
+                let inputs = self.shape(inputs, env);
+                let inner = Env {
+                    arg: inputs.clone(),
+                    top_level: false,
+                };
+                // the body must pass each pointer on to the next iteration unchanged
+                let outputs = self.shape(pred_and_body, &inner);
+                self.join(&inputs, &outputs[1..])
+            }
+            // Every other use of a pointer escapes
+            _ => {
+                for child in expr.children_exprs() {
+                    let shape = self.shape(&child, env);
+                    self.escape(&shape);
+                }
+                self.values(expr)
+            }
+        };
+        self.shapes.insert(key, shape.clone());
+        shape
+    }
+}
+
+enum Translated {
+    /// A value or tuple, with the new layout
+    Value(RcExpr),
+    /// A state followed by the value of each cell
+    State(Vec<RcExpr>),
+    /// A pointer into a replaced allocation
+    Dropped,
+}
+
+// Rewrites a function so that every state is followed by the cells
+// and pointers into replaced allocations are gone
+struct Replacer<'a> {
+    analysis: Analysis<'a>,
+    // index of the first cell of each candidate
+    first_cell: Vec<usize>,
+    num_cells: usize,
+    translated: HashMap<(*const Expr, Env), Rc<Translated>>,
+}
+
+impl Replacer<'_> {
+    fn type_of(&self, expr: &RcExpr) -> Type {
+        self.analysis.types[&Rc::as_ptr(expr)].clone()
+    }
+
+    // Where element `i` of `tuple` starts in the new layout
+    fn new_index(&mut self, tuple: &RcExpr, env: &Env, i: usize) -> usize {
+        let tys = base_types(&self.type_of(tuple));
+        let shape = self.analysis.shape(tuple, env);
+        (0..i)
+            .map(|j| match (&tys[j], &shape[j]) {
+                (_, Shape::Ptr { .. }) => 0,
+                (BaseType::StateT, _) => 1 + self.num_cells,
+                _ => 1,
+            })
+            .sum()
+    }
+
+    fn default_cells(&self, alloc: usize) -> Vec<RcExpr> {
+        let candidate = &self.analysis.candidates[alloc];
+        let default = match candidate.cell_ty {
+            BaseType::IntT => int(0),
+            BaseType::BoolT => tfalse(),
+            BaseType::FloatT => float(0.0),
+            _ => panic!("Unexpected cell type {:?}", candidate.cell_ty),
+        };
+        vec![default; candidate.cells as usize]
+    }
+
+    fn translate(&mut self, expr: &RcExpr, env: &Env) -> Rc<Translated> {
+        let key = (Rc::as_ptr(expr), env.clone());
+        if let Some(res) = self.translated.get(&key) {
+            return res.clone();
+        }
+        let ty = self.type_of(expr);
+        let shape = self.analysis.shape(expr, env);
+        let res = Rc::new(match (&ty, shape.as_slice()) {
+            (Type::Base(_), [Shape::Ptr { .. }]) => Translated::Dropped,
+            (Type::Base(BaseType::StateT), _) => Translated::State(self.translate_state(expr, env)),
+            _ => Translated::Value(self.translate_value(expr, env)),
+        });
+        self.translated.insert(key, res.clone());
+        res
+    }
+
+    fn value(&mut self, expr: &RcExpr, env: &Env) -> RcExpr {
+        match self.translate(expr, env).as_ref() {
+            Translated::Value(value) => value.clone(),
+            _ => panic!("Expected a value, got {:?}", expr),
+        }
+    }
+
+    fn state(&mut self, expr: &RcExpr, env: &Env) -> Vec<RcExpr> {
+        match self.translate(expr, env).as_ref() {
+            Translated::State(state) => state.clone(),
+            _ => panic!("Expected a state, got {:?}", expr),
+        }
+    }
+
+    fn translate_state(&mut self, expr: &RcExpr, env: &Env) -> Vec<RcExpr> {
+        match expr.as_ref() {
+            Expr::Get(tuple, i) => {
+                let new_tuple = self.value(tuple, env);
+                let start = self.new_index(tuple, env, *i);
+                (0..=self.num_cells)
+                    .map(|k| get(new_tuple.clone(), start + k))
+                    .collect()
+            }
+            Expr::Bop(BinaryOp::Print, val, state) => {
+                let mut state = self.state(state, env);
+                state[0] = tprint(self.value(val, env), state[0].clone());
+                state
+            }
+            Expr::Bop(BinaryOp::Free, ptr, state) => {
+                let mut state = self.state(state, env);
+                if let Translated::Value(ptr) = self.translate(ptr, env).as_ref() {
+                    state[0] = Rc::new(Expr::Bop(BinaryOp::Free, ptr.clone(), state[0].clone()));
+                }
+                state
+            }
+            Expr::Top(TernaryOp::Write, ptr, val, state) => {
+                let mut state = self.state(state, env);
+                let val = self.value(val, env);
+                match self.analysis.shape(ptr, env)[0] {
+                    Shape::Ptr { alloc, offset } => {
+                        state[1 + self.first_cell[alloc] + offset as usize] = val;
+                    }
+                    Shape::Value => {
+                        state[0] = twrite(self.value(ptr, env), val, state[0].clone());
+                    }
+                }
+                state
+            }
+            _ => panic!("Unexpected state {:?}", expr),
+        }
+    }
+
+    fn translate_value(&mut self, expr: &RcExpr, env: &Env) -> RcExpr {
+        match expr.as_ref() {
+            // Region arguments change type, so the type checker fills them in again
+            Expr::Const(c, _, ctx) if !env.top_level => {
+                Rc::new(Expr::Const(c.clone(), Type::Unknown, ctx.clone()))
+            }
+            Expr::Empty(_, ctx) if !env.top_level => {
+                Rc::new(Expr::Empty(Type::Unknown, ctx.clone()))
+            }
+            Expr::Arg(_, ctx) if !env.top_level => Rc::new(Expr::Arg(Type::Unknown, ctx.clone())),
+            // The function keeps its argument, and each state gets the initial cells
+            Expr::Arg(Type::TupleT(tys), _) => {
+                parallel_vec(tys.iter().enumerate().flat_map(|(i, ty)| {
+                    let mut elements = vec![get(expr.clone(), i)];
+                    if *ty == BaseType::StateT {
+                        elements.extend(
+                            (0..self.first_cell.len()).flat_map(|alloc| self.default_cells(alloc)),
+                        );
+                    }
+                    elements
+                }))
+            }
+            Expr::Const(..) | Expr::Empty(..) | Expr::Arg(..) => expr.clone(),
+            Expr::Get(tuple, i) => {
+                let new_tuple = self.value(tuple, env);
+                get(new_tuple, self.new_index(tuple, env, *i))
+            }
+            Expr::Single(x) => match self.translate(x, env).as_ref() {
+                Translated::Value(x) => Rc::new(Expr::Single(x.clone())),
+                Translated::State(state) => parallel_vec(state.clone()),
+                Translated::Dropped => Rc::new(Expr::Empty(Type::Unknown, x.get_ctx().clone())),
+            },
+            Expr::Concat(x, y) => Rc::new(Expr::Concat(self.value(x, env), self.value(y, env))),
+            Expr::Bop(BinaryOp::Load, ptr, state) => {
+                let state = self.state(state, env);
+                match self.analysis.shape(ptr, env)[0] {
+                    Shape::Ptr { alloc, offset } => {
+                        let cell = state[1 + self.first_cell[alloc] + offset as usize].clone();
+                        parallel_vec(std::iter::once(cell).chain(state))
+                    }
+                    Shape::Value => {
+                        let new_load = load(self.value(ptr, env), state[0].clone());
+                        parallel_vec(
+                            [get(new_load.clone(), 0), get(new_load, 1)]
+                                .into_iter()
+                                .chain(state.into_iter().skip(1)),
+                        )
+                    }
+                }
+            }
+            Expr::Bop(op, x, y) => Rc::new(Expr::Bop(
+                op.clone(),
+                self.value(x, env),
+                self.value(y, env),
+            )),
+            Expr::Uop(op, x) => Rc::new(Expr::Uop(op.clone(), self.value(x, env))),
+            Expr::Top(op, x, y, z) => Rc::new(Expr::Top(
+                op.clone(),
+                self.value(x, env),
+                self.value(y, env),
+                self.value(z, env),
+            )),
+            Expr::Alloc(id, amount, state, ty) => {
+                let mut state = self.state(state, env);
+                match self.analysis.candidates.get_index_of(&Rc::as_ptr(expr)) {
+                    Some(alloc) => {
+                        let first = 1 + self.first_cell[alloc];
+                        let defaults = self.default_cells(alloc);
+                        state.splice(first..first + defaults.len(), defaults);
+                        parallel_vec(state)
+                    }
+                    None => {
+                        let new_alloc = Rc::new(Expr::Alloc(
+                            *id,
+                            self.value(amount, env),
+                            state[0].clone(),
+                            ty.clone(),
+                        ));
+                        parallel_vec(
+                            [get(new_alloc.clone(), 0), get(new_alloc, 1)]
+                                .into_iter()
+                                .chain(state.into_iter().skip(1)),
+                        )
+                    }
+                }
+            }
+            Expr::Call(name, args) => {
+                let arg_tys = base_types(&self.type_of(args));
+                let out_tys = base_types(&self.type_of(expr));
+                let new_args = self.value(args, env);
+                if !arg_tys.contains(&BaseType::StateT) {
+                    return Rc::new(Expr::Call(name.clone(), new_args));
+                }
+                // The callee gets the state without the cells,
+                // and the cells are unchanged after the call.
+                let Type::TupleT(_) = self.type_of(args) else {
+                    panic!("Expected tuple arguments to a call with state");
+                };
+                let mut cells = vec![];
+                let mut call_args = vec![];
+                for (i, ty) in arg_tys.iter().enumerate() {
+                    let start = self.new_index(args, env, i);
+                    call_args.push(get(new_args.clone(), start));
+                    if *ty == BaseType::StateT {
+                        cells = (1..=self.num_cells)
+                            .map(|k| get(new_args.clone(), start + k))
+                            .collect();
+                    }
+                }
+                let call = Rc::new(Expr::Call(name.clone(), parallel_vec(call_args)));
+                let Type::TupleT(_) = self.type_of(expr) else {
+                    return call;
+                };
+                parallel_vec(out_tys.iter().enumerate().flat_map(|(i, ty)| {
+                    let mut elements = vec![get(call.clone(), i)];
+                    if *ty == BaseType::StateT {
+                        elements.extend(cells.iter().cloned());
+                    }
+                    elements
+                }))
+            }
+            Expr::If(pred, inputs, thn, els) => {
+                let inner = Env {
+                    arg: self.analysis.shape(inputs, env),
+                    top_level: false,
+                };
+                Rc::new(Expr::If(
+                    self.value(pred, env),
+                    self.value(inputs, env),
+                    self.value(thn, &inner),
+                    self.value(els, &inner),
+                ))
+            }
+            Expr::Switch(pred, inputs, branches) => {
+                let inner = Env {
+                    arg: self.analysis.shape(inputs, env),
+                    top_level: false,
+                };
+                Rc::new(Expr::Switch(
+                    self.value(pred, env),
+                    self.value(inputs, env),
+                    branches
+                        .iter()
+                        .map(|branch| self.value(branch, &inner))
+                        .collect(),
+                ))
+            }
+            Expr::DoWhile(inputs, pred_and_body) => {
+                let inner = Env {
+                    arg: self.analysis.shape(inputs, env),
+                    top_level: false,
+                };
+                Rc::new(Expr::DoWhile(
+                    self.value(inputs, env),
+                    self.value(pred_and_body, &inner),
+                ))
+            }
+            Expr::Function(..) | Expr::Symbolic(_) => panic!("Unexpected {:?}", expr),
+        }
+    }
+}
+
+// The allocations in `body` with a small constant size and a non-pointer cell type
+fn find_candidates(
+    expr: &RcExpr,
+    candidates: &mut IndexMap<*const Expr, Candidate>,
+    seen: &mut IndexSet<*const Expr>,
+) {
+    if !seen.insert(Rc::as_ptr(expr)) {
+        return;
+    }
+    if let Expr::Alloc(_, amount, _, BaseType::PointerT(cell_ty)) = expr.as_ref() {
+        if let Expr::Const(Constant::Int(cells), _, _) = amount.as_ref() {
+            let small = (1..=config::SCALAR_REPLACEMENT_MAX_CELLS).contains(cells);
+            let scalar = matches!(
+                cell_ty.as_ref(),
+                BaseType::IntT | BaseType::BoolT | BaseType::FloatT
+            );
+            if small && scalar {
+                candidates.insert(
+                    Rc::as_ptr(expr),
+                    Candidate {
+                        cells: *cells,
+                        cell_ty: cell_ty.as_ref().clone(),
+                    },
+                );
+            }
+        }
+    }
+    for child in expr.children_exprs() {
+        find_candidates(&child, candidates, seen);
+    }
+}
+
+// Replaces the allocations of `func` that don't escape,
+// or returns None if they all escape
+fn replace_in_function(func: &RcExpr, types: &TypeCache) -> Option<RcExpr> {
+    let Expr::Function(name, in_ty, out_ty, body) = func.as_ref() else {
+        panic!("Expected function, got {:?}", func);
+    };
+    let mut candidates = IndexMap::new();
+    find_candidates(body, &mut candidates, &mut IndexSet::new());
+
+    let top = Env {
+        arg: vec![Shape::Value; base_types(in_ty).len()],
+        top_level: true,
+    };
+    // Removing an escaping candidate changes the shapes of the others,
+    // so analyze again until none escape.
+    loop {
+        if candidates.is_empty() {
+            return None;
+        }
+        let mut analysis = Analysis {
+            types,
+            candidates: &candidates,
+            shapes: HashMap::new(),
+            escaped: IndexSet::new(),
+        };
+        let outputs = analysis.shape(body, &top);
+        analysis.escape(&outputs);
+        let escaped = analysis.escaped;
+        if escaped.is_empty() {
+            break;
+        }
+        candidates = candidates
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| !escaped.contains(i))
+            .map(|(_, candidate)| candidate)
+            .collect();
+    }
+
+    let first_cell = candidates
+        .values()
+        .scan(0, |first, candidate| {
+            let res = *first;
+            *first += candidate.cells as usize;
+            Some(res)
+        })
+        .collect::<Vec<_>>();
+    let num_cells = candidates
+        .values()
+        .map(|candidate| candidate.cells as usize)
+        .sum();
+    let mut replacer = Replacer {
+        analysis: Analysis {
+            types,
+            candidates: &candidates,
+            shapes: HashMap::new(),
+            escaped: IndexSet::new(),
+        },
+        first_cell,
+        num_cells,
+        translated: HashMap::new(),
+    };
+
+    // The function returns its state without the cells
+    let new_body = replacer.value(body, &top);
+    let new_body = match out_ty {
+        Type::TupleT(tys) => parallel_vec(
+            (0..tys.len())
+                .map(|i| get(new_body.clone(), replacer.new_index(body, &top, i)))
+                .collect::<Vec<_>>(),
+        ),
+        _ => new_body,
+    };
+    Some(Rc::new(Expr::Function(
+        name.clone(),
+        in_ty.clone(),
+        out_ty.clone(),
+        new_body,
+    )))
+}
+
+/// Replaces allocations that don't escape their function with scalar values.
+/// Returns None when every allocation escapes.
+/// The result has argument types, but needs context added again.
+pub fn scalar_replace_allocations(program: &TreeProgram) -> Option<TreeProgram> {
+    let (program, types) = program.with_arg_types_and_cache();
+    let mut changed = false;
+    let mut replace = |func: &RcExpr| match replace_in_function(func, &types) {
+        Some(new_func) => {
+            changed = true;
+            new_func
+        }
+        None => func.clone(),
+    };
+    let replaced = TreeProgram {
+        entry: replace(&program.entry),
+        functions: program.functions.iter().map(&mut replace).collect(),
+    };
+    changed.then(|| replaced.with_arg_types())
+}
+
+#[cfg(test)]
+fn has_memory_ops(expr: &RcExpr) -> bool {
+    matches!(
+        expr.as_ref(),
+        Expr::Alloc(..)
+            | Expr::Bop(BinaryOp::Load | BinaryOp::Free, _, _)
+            | Expr::Top(TernaryOp::Write, _, _, _)
+    ) || expr.children_exprs().iter().any(has_memory_ops)
+}
+
+#[test]
+fn test_scalar_replace_across_regions() {
+    use crate::ast::*;
+    use crate::interpreter::interpret_dag_prog;
+
+    // p = alloc 2; p[0] = 10; p[1] = 3
+    let mem = alloc(0, int(2), getat(1), pointert(intt()));
+    let ptr = get(mem.clone(), 0);
+    let state = twrite(
+        ptradd(ptr.clone(), int(1)),
+        int(3),
+        twrite(ptr.clone(), int(10), get(mem, 1)),
+    );
+    // do { p[0] = p[0] + p[1]; i = i + 1 } while (i < n)
+    let load0 = load(getat(1), getat(2));
+    let load1 = load(ptradd(getat(1), int(1)), get(load0.clone(), 1));
+    let loop_ = dowhile(
+        parallel!(int(0), ptr, state, getat(0)),
+        parallel!(
+            less_than(add(getat(0), int(1)), getat(3)),
+            add(getat(0), int(1)),
+            getat(1),
+            twrite(
+                getat(1),
+                add(get(load0, 0), get(load1.clone(), 0)),
+                get(load1, 1)
+            ),
+            getat(3)
+        ),
+    );
+    // if (n < 2) { p[0] = 100 }
+    let branch = tif(
+        less_than(getat(0), int(2)),
+        parallel!(get(loop_.clone(), 1), get(loop_, 2)),
+        parallel!(getat(0), twrite(getat(0), int(100), getat(1))),
+        parallel!(getat(0), getat(1)),
+    );
+    let result = load(get(branch.clone(), 0), get(branch.clone(), 1));
+    let main = function(
+        "main",
+        tuplet!(intt(), statet()),
+        tuplet!(intt(), statet()),
+        parallel!(get(result.clone(), 0), free(get(branch, 0), get(result, 1))),
+    );
+    let program = program!(main,);
+
+    let replaced = scalar_replace_allocations(&program).unwrap();
+    assert!(!has_memory_ops(replaced.entry.func_body().unwrap()));
+
+    for n in [1, 4] {
+        let input = tuplev!(intv(n), statev());
+        assert_eq!(
+            interpret_dag_prog(&replaced, &input),
+            interpret_dag_prog(&program, &input)
+        );
+    }
+}
+
+#[test]
+fn test_no_scalar_replace_when_escaping() {
+    use crate::ast::*;
+
+    // the pointer is passed to another function
+    let mem = alloc(0, int(1), getat(0), pointert(intt()));
+    let use_ptr = call("use_ptr", parallel!(get(mem.clone(), 0), get(mem, 1)));
+    let main = function(
+        "main",
+        tuplet!(statet()),
+        tuplet!(statet()),
+        single(get(use_ptr, 0)),
+    );
+    let use_ptr = function(
+        "use_ptr",
+        tuplet!(pointert(intt()), statet()),
+        tuplet!(statet()),
+        single(free(getat(0), getat(1))),
+    );
+    let program = program!(main, use_ptr);
+
+    assert!(scalar_replace_allocations(&program).is_none());
+}
+
+#[test]
+fn test_no_scalar_replace_without_passes() {
+    use crate::ast::*;
+    use crate::EggccConfig;
+
+    // p = alloc 1; p[0] = 5; return p[0]
+    let mem = alloc(0, int(1), getat(0), pointert(intt()));
+    let ptr = get(mem.clone(), 0);
+    let loaded = load(ptr.clone(), twrite(ptr.clone(), int(5), get(mem, 1)));
+    let main = function(
+        "main",
+        tuplet!(statet()),
+        tuplet!(intt(), statet()),
+        parallel!(get(loaded.clone(), 0), free(ptr, get(loaded, 1))),
+    );
+    let program = program!(main,).with_arg_types();
+    assert!(scalar_replace_allocations(&program).is_some());
+
+    // stopping before the first pass leaves the allocation in place
+    let (program, mut cache) = program.add_context();
+    let config = EggccConfig {
+        stop_after_n_passes: 0,
+        ..Default::default()
+    };
+    let res = crate::optimize(&program, &mut cache, &config).unwrap();
+    assert!(has_memory_ops(res.entry.func_body().unwrap()));
+}
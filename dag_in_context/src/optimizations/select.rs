@@ -0,0 +1,231 @@
+//! If-conversion: an output of an `If` becomes a `Select` of its two arms
+//! when computing both arms and selecting costs no more than branching.
+//! Costs come from the cost model used for extraction,
+//! so the rules are generated for the model in `EggccConfig`.
+//! Since each arm that is converted is itself a `Select`,
+//! nested ifs become select trees, innermost first.
+
+use crate::{
+    greedy_dag_extractor::CostModel,
+    schema::{BaseType, BinaryOp, TernaryOp, Type, UnaryOp},
+};
+use strum::IntoEnumIterator;
+
+/// The types a `Select` can produce, with their names in egglog
+const SCALAR_TYPES: [(&str, BaseType); 3] = [
+    ("IntT", BaseType::IntT),
+    ("BoolT", BaseType::BoolT),
+    ("FloatT", BaseType::FloatT),
+];
+
+// The cost of `op` producing a `ty`, rounded to an integer for egglog.
+// None when the cost model doesn't allow the operator.
+fn op_cost(cost_model: &dyn CostModel, op: &str, ty: &BaseType) -> Option<i64> {
+    let cost = cost_model
+        .get_op_cost(op, Some(&Type::Base(ty.clone())))
+        .into_inner();
+    cost.is_finite().then(|| cost.round() as i64)
+}
+
+// Operators that could be computed even when their branch isn't taken
+fn is_pure_bop(op: &BinaryOp) -> bool {
+    !matches!(op, BinaryOp::Load | BinaryOp::Print | BinaryOp::Free)
+}
+
+fn is_pure_top(op: &TernaryOp) -> bool {
+    !matches!(op, TernaryOp::Write)
+}
+
+fn speculation_cost_rule(pattern: &str, ty: &str, children: &[&str], cost: i64) -> String {
+    let child_costs = children
+        .iter()
+        .map(|child| format!("\n       (= {child}-cost (SpeculationCost {child}))"))
+        .collect::<String>();
+    let total = children.iter().fold(cost.to_string(), |total, child| {
+        format!("(+ {total} {child}-cost)")
+    });
+    format!(
+        "
+(rule ((= e {pattern})
+       (HasType e (Base ({ty}))){child_costs})
+      ((set (SpeculationCost e) {total}))
+      :ruleset always-run)"
+    )
+}
+
+fn if_to_select_rule(ty: &str, select_cost: i64, branch_cost: i64) -> String {
+    format!(
+        "
+; if pred then A else B ~~> (select pred A B)
+; when computing A and B and selecting costs no more than
+; the branch plus the more expensive arm
+(rule ((= if_e (If pred inputs thn els))
+       (ContextOf if_e ctx)
+       (= thn_out (Get thn i))
+       (= els_out (Get els i))
+       (HasType thn_out (Base ({ty})))
+       (= thn_cost (SpeculationCost thn_out))
+       (= els_cost (SpeculationCost els_out))
+       (<= (+ {select_cost} (+ thn_cost els_cost))
+           (+ {branch_cost} (max thn_cost els_cost)))
+       (= (TCPair t1 c1) (ExtractedExpr thn_out))
+       (= (TCPair t2 c2) (ExtractedExpr els_out)))
+      ((union (Get if_e i)
+              (Top (Select) pred (TermSubst ctx inputs t1) (TermSubst ctx inputs t2))))
+      :ruleset select_opt)"
+    )
+}
+
+pub(crate) fn rules(cost_model: &dyn CostModel) -> Vec<String> {
+    let mut rules = vec![
+        "(ruleset select_opt)".to_string(),
+        ";; The cost of computing a pure expression, assuming nothing is shared.
+;; Expressions with regions, calls, or effects don't have one.
+(function SpeculationCost (Expr) i64 :unextractable :merge (min old new))"
+            .to_string(),
+    ];
+    for (ty_name, ty) in SCALAR_TYPES.iter() {
+        let leaves = [
+            ("(Get (Arg arg_ty ctx) i)", "Get"),
+            ("(Const c arg_ty ctx)", "Const"),
+        ];
+        for (pattern, op) in leaves {
+            if let Some(cost) = op_cost(cost_model, op, ty) {
+                rules.push(speculation_cost_rule(pattern, ty_name, &[], cost));
+            }
+        }
+        for op in BinaryOp::iter().filter(is_pure_bop) {
+            if let (Some(cost), Some(node_cost)) = (
+                op_cost(cost_model, op.name(), ty),
+                op_cost(cost_model, "Bop", ty),
+            ) {
+                let pattern = format!("(Bop ({}) a b)", op.name());
+                rules.push(speculation_cost_rule(
+                    &pattern,
+                    ty_name,
+                    &["a", "b"],
+                    cost + node_cost,
+                ));
+            }
+        }
+        for op in UnaryOp::iter() {
+            if let (Some(cost), Some(node_cost)) = (
+                op_cost(cost_model, op.name(), ty),
+                op_cost(cost_model, "Uop", ty),
+            ) {
+                let pattern = format!("(Uop ({}) a)", op.name());
+                rules.push(speculation_cost_rule(
+                    &pattern,
+                    ty_name,
+                    &["a"],
+                    cost + node_cost,
+                ));
+            }
+        }
+        for op in TernaryOp::iter().filter(is_pure_top) {
+            if let (Some(cost), Some(node_cost)) = (
+                op_cost(cost_model, op.name(), ty),
+                op_cost(cost_model, "Top", ty),
+            ) {
+                let pattern = format!("(Top ({}) a b c)", op.name());
+                rules.push(speculation_cost_rule(
+                    &pattern,
+                    ty_name,
+                    &["a", "b", "c"],
+                    cost + node_cost,
+                ));
+            }
+        }
+
+        let select_cost = op_cost(cost_model, "Select", ty)
+            .zip(op_cost(cost_model, "Top", ty))
+            .map(|(select, node)| select + node);
+        let branch_cost = cost_model.get_op_cost("If", None).into_inner();
+        if let (Some(select_cost), true) = (select_cost, branch_cost.is_finite()) {
+            rules.push(if_to_select_rule(
+                ty_name,
+                select_cost,
+                branch_cost.round() as i64,
+            ));
+        }
+    }
+    rules
+}
+
+#[cfg(test)]
+use crate::{egglog_test, greedy_dag_extractor::DefaultCostModel};
+
+#[test]
+fn if_to_select_when_cheaper() -> crate::Result {
+    use crate::ast::*;
+
+    // if x < y then x + 1 else y * 2
+    let ctx_ty = tuplet!(intt(), intt());
+    let prog = get(
+        tif(
+            less_than(getat(0), getat(1)),
+            arg(),
+            single(add(getat(0), int(1))),
+            single(mul(getat(1), int(2))),
+        ),
+        0,
+    )
+    .with_arg_types(ctx_ty.clone(), base(intt()));
+    let (build, build_cache) = prog.add_dummy_ctx();
+    let (check, check_cache) = select(
+        less_than(getat(0), getat(1)),
+        add(getat(0), int(1)),
+        mul(getat(1), int(2)),
+    )
+    .with_arg_types(ctx_ty.clone(), base(intt()))
+    .add_dummy_ctx();
+
+    egglog_test(
+        &format!("(let build_ {build})\n{}", build_cache.get_unions()),
+        &format!(
+            "(let check_ {check})\n{}\n(check (= build_ check_))",
+            check_cache.get_unions()
+        ),
+        vec![prog.to_program(ctx_ty.clone(), base(intt()))],
+        tuplev!(intv(3), intv(5)),
+        intv(4),
+        vec![],
+    )
+}
+
+#[test]
+fn no_select_for_expensive_arms() -> crate::Result {
+    use crate::ast::*;
+
+    // both arms divide twice, which costs more than branching
+    let ctx_ty = tuplet!(intt(), intt());
+    let prog = get(
+        tif(
+            less_than(getat(0), getat(1)),
+            arg(),
+            single(div(div(getat(0), getat(1)), getat(1))),
+            single(div(div(getat(1), getat(0)), getat(0))),
+        ),
+        0,
+    )
+    .with_arg_types(ctx_ty.clone(), base(intt()));
+    let (build, build_cache) = prog.add_dummy_ctx();
+
+    egglog_test(
+        &format!("(let build_ {build})\n{}", build_cache.get_unions()),
+        "(fail (check (= build_ (Top (Select) pred a b))))",
+        vec![prog.to_program(ctx_ty.clone(), base(intt()))],
+        tuplev!(intv(4), intv(2)),
+        intv(0),
+        vec![],
+    )
+}
+
+#[test]
+fn select_rules_follow_cost_model() {
+    // the default cost model allows selects of every scalar type
+    let rules = rules(&DefaultCostModel).join("\n");
+    for (ty_name, _ty) in SCALAR_TYPES.iter() {
+        assert!(rules.contains(&format!("(HasType thn_out (Base ({ty_name})))")));
+    }
+}
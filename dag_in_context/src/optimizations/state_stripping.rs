@@ -0,0 +1,390 @@
+//! Removes the state edge from pure functions.
+//! RVSDG construction threads the state through every function and call,
+//! so calls to pure functions are ordered on the state chain like any other effect.
+//! A function is pure when its body doesn't print, touch memory,
+//! or call a function that isn't pure.
+//! Such a function passes its state through unchanged, so we drop the `StateT`
+//! argument and result from its signature and its body,
+//! and call sites pass their state around the call instead.
+
+use std::{collections::HashMap, rc::Rc};
+
+use indexmap::IndexSet;
+
+use crate::{
+    ast::{get, parallel_vec},
+    optimizations::function_specialization::tuple_elements,
+    schema::{BaseType, BinaryOp, Expr, RcExpr, TernaryOp, TreeProgram, Type},
+    typechecker::TypeCache,
+};
+
+// The index of the only state in a tuple type
+fn state_index(ty: &Type) -> Option<usize> {
+    let Type::TupleT(tys) = ty else {
+        return None;
+    };
+    let mut states = tys
+        .iter()
+        .enumerate()
+        .filter(|(_, ty)| **ty == BaseType::StateT);
+    match (states.next(), states.next()) {
+        (Some((i, _)), None) => Some(i),
+        _ => None,
+    }
+}
+
+fn has_state(ty: &Type) -> bool {
+    match ty {
+        Type::TupleT(tys) => tys.contains(&BaseType::StateT),
+        Type::Base(ty) => *ty == BaseType::StateT,
+        _ => false,
+    }
+}
+
+fn strip_state_type(ty: &Type) -> Type {
+    match ty {
+        Type::TupleT(tys) => Type::TupleT(
+            tys.iter()
+                .filter(|ty| **ty != BaseType::StateT)
+                .cloned()
+                .collect(),
+        ),
+        _ => ty.clone(),
+    }
+}
+
+// Where element `i` of a tuple of type `ty` ends up once states are removed
+fn stripped_index(ty: &Type, i: usize) -> usize {
+    let Type::TupleT(tys) = ty else {
+        panic!("Expected tuple type, got {:?}", ty);
+    };
+    i - tys[..i]
+        .iter()
+        .filter(|ty| **ty == BaseType::StateT)
+        .count()
+}
+
+// Checks that `expr` has no effects, assuming the functions in `pure` have none
+fn is_pure(
+    expr: &RcExpr,
+    program: &TreeProgram,
+    pure: &IndexSet<String>,
+    seen: &mut IndexSet<*const Expr>,
+) -> bool {
+    if !seen.insert(Rc::as_ptr(expr)) {
+        return true;
+    }
+    let pure_here = match expr.as_ref() {
+        Expr::Top(TernaryOp::Write, _, _, _) => false,
+        Expr::Bop(BinaryOp::Print | BinaryOp::Load | BinaryOp::Free, _, _) => false,
+        Expr::Alloc(..) => false,
+        Expr::Call(callee, _) => {
+            pure.contains(callee)
+                || !has_state(
+                    &program
+                        .get_function(callee)
+                        .expect("Callee exists")
+                        .func_input_ty()
+                        .unwrap(),
+                )
+        }
+        _ => true,
+    };
+    pure_here
+        && expr
+            .children_exprs()
+            .iter()
+            .all(|child| is_pure(child, program, pure, seen))
+}
+
+// The functions that can lose their state edge.
+// Starts from every function with one state in and one state out
+// and removes functions with effects until nothing changes,
+// so recursive functions that are otherwise pure stay pure.
+fn pure_functions(program: &TreeProgram) -> IndexSet<String> {
+    let mut pure = program
+        .functions
+        .iter()
+        .filter(|func| {
+            let in_ty = func.func_input_ty().unwrap();
+            let out_ty = func.func_output_ty().unwrap();
+            // Bril functions need a return value once the state is gone
+            state_index(&in_ty).is_some()
+                && state_index(&out_ty).is_some()
+                && strip_state_type(&out_ty) != Type::TupleT(vec![])
+        })
+        .map(|func| func.func_name().unwrap())
+        .collect::<IndexSet<_>>();
+
+    loop {
+        let effectful = pure
+            .iter()
+            .filter(|name| {
+                let body = program.get_function(name).unwrap().func_body().unwrap();
+                !is_pure(body, program, &pure, &mut IndexSet::new())
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        if effectful.is_empty() {
+            return pure;
+        }
+        pure.retain(|name| !effectful.contains(name));
+    }
+}
+
+// In a function that keeps its state, calls to pure functions
+// pass the state around the call:
+// (Call f args) => (args without the state) and the state put back into the results
+fn redirect_state_around_calls(
+    expr: &RcExpr,
+    program: &TreeProgram,
+    pure: &IndexSet<String>,
+    cache: &mut HashMap<*const Expr, RcExpr>,
+) -> RcExpr {
+    if let Some(res) = cache.get(&Rc::as_ptr(expr)) {
+        return res.clone();
+    }
+    let mapped =
+        expr.map_expr_children(|child| redirect_state_around_calls(child, program, pure, cache));
+    let res = match mapped.as_ref() {
+        Expr::Call(callee, args) if pure.contains(callee) => {
+            let func = program.get_function(callee).unwrap();
+            let in_ty = func.func_input_ty().unwrap();
+            let out_ty = func.func_output_ty().unwrap();
+            let (Type::TupleT(in_tys), Type::TupleT(out_tys)) = (&in_ty, &out_ty) else {
+                panic!("Expected tuple types for pure function {callee}");
+            };
+            let state_in = state_index(&in_ty).unwrap();
+            let state_out = state_index(&out_ty).unwrap();
+
+            let mut elements = tuple_elements(args)
+                .unwrap_or_else(|| (0..in_tys.len()).map(|i| get(args.clone(), i)).collect());
+            let state = elements.remove(state_in);
+            let call = Rc::new(Expr::Call(callee.clone(), parallel_vec(elements)));
+            parallel_vec((0..out_tys.len()).map(|i| {
+                if i == state_out {
+                    state.clone()
+                } else {
+                    get(call.clone(), stripped_index(&out_ty, i))
+                }
+            }))
+        }
+        _ => mapped,
+    };
+    cache.insert(Rc::as_ptr(expr), res.clone());
+    res
+}
+
+// Removes every state from a pure function.
+// State values only flow through tuples in a pure function,
+// so each one is dropped from the tuple that holds it.
+struct StateStripper<'a> {
+    types: &'a TypeCache,
+    cache: HashMap<*const Expr, Option<RcExpr>>,
+}
+
+impl StateStripper<'_> {
+    fn type_of(&self, expr: &RcExpr) -> &Type {
+        self.types
+            .get(&Rc::as_ptr(expr))
+            .unwrap_or_else(|| panic!("Expected type for {:?}", expr))
+    }
+
+    fn strip_func(&mut self, func: &RcExpr) -> RcExpr {
+        let Expr::Function(name, in_ty, out_ty, body) = func.as_ref() else {
+            panic!("Expected function, got {:?}", func);
+        };
+        Rc::new(Expr::Function(
+            name.clone(),
+            strip_state_type(in_ty),
+            strip_state_type(out_ty),
+            self.strip_value(body),
+        ))
+    }
+
+    fn strip_value(&mut self, expr: &RcExpr) -> RcExpr {
+        self.strip(expr)
+            .unwrap_or_else(|| panic!("Expected a value, got state {:?}", expr))
+    }
+
+    // Returns None for an expression of type state
+    fn strip(&mut self, expr: &RcExpr) -> Option<RcExpr> {
+        if let Some(res) = self.cache.get(&Rc::as_ptr(expr)) {
+            return res.clone();
+        }
+        let res = if *self.type_of(expr) == Type::Base(BaseType::StateT) {
+            None
+        } else {
+            Some(match expr.as_ref() {
+                Expr::Const(c, ty, ctx) => {
+                    Rc::new(Expr::Const(c.clone(), strip_state_type(ty), ctx.clone()))
+                }
+                Expr::Empty(ty, ctx) => Rc::new(Expr::Empty(strip_state_type(ty), ctx.clone())),
+                Expr::Arg(ty, ctx) => Rc::new(Expr::Arg(strip_state_type(ty), ctx.clone())),
+                Expr::Get(tuple, i) => {
+                    let index = stripped_index(self.type_of(tuple), *i);
+                    Rc::new(Expr::Get(self.strip_value(tuple), index))
+                }
+                Expr::Single(x) => match self.strip(x) {
+                    Some(x) => Rc::new(Expr::Single(x)),
+                    None => Rc::new(Expr::Empty(
+                        strip_state_type(&x.get_arg_type()),
+                        x.get_ctx().clone(),
+                    )),
+                },
+                _ => expr.map_expr_children(|child| self.strip_value(child)),
+            })
+        };
+        self.cache.insert(Rc::as_ptr(expr), res.clone());
+        res
+    }
+}
+
+/// Removes the state argument and result from pure functions
+/// and updates their call sites.
+/// Returns None when no function is pure.
+/// The result has argument types, but needs context added again.
+pub fn strip_state_from_pure_functions(program: &TreeProgram) -> Option<TreeProgram> {
+    let (program, types) = program.with_arg_types_and_cache();
+    let pure = pure_functions(&program);
+    if pure.is_empty() {
+        return None;
+    }
+
+    let mut stripper = StateStripper {
+        types: &types,
+        cache: HashMap::new(),
+    };
+    let mut redirect_cache = HashMap::new();
+    let mut strip = |func: &RcExpr| {
+        if pure.contains(&func.func_name().unwrap()) {
+            stripper.strip_func(func)
+        } else {
+            redirect_state_around_calls(func, &program, &pure, &mut redirect_cache)
+        }
+    };
+    let stripped = TreeProgram {
+        entry: strip(&program.entry),
+        functions: program.functions.iter().map(&mut strip).collect(),
+    };
+    Some(stripped.with_arg_types())
+}
+
+#[test]
+fn test_strip_state_from_pure_functions() {
+    use crate::ast::*;
+    use crate::interpreter::interpret_dag_prog;
+
+    let square = function(
+        "square",
+        tuplet!(intt(), statet()),
+        tuplet!(intt(), statet()),
+        parallel!(mul(getat(0), getat(0)), getat(1)),
+    );
+    // recursive, so only pure once we assume it is
+    let fact_call = call("fact", parallel!(sub(getat(0), int(1)), getat(1)));
+    let fact = function(
+        "fact",
+        tuplet!(intt(), statet()),
+        tuplet!(intt(), statet()),
+        tif(
+            less_eq(getat(0), int(1)),
+            arg(),
+            parallel!(int(1), getat(1)),
+            parallel!(mul(getat(0), get(fact_call.clone(), 0)), get(fact_call, 1)),
+        ),
+    );
+    // the state passes through a loop
+    let sum_loop = dowhile(
+        parallel!(int(0), int(0), getat(0), getat(1)),
+        parallel!(
+            less_than(add(getat(0), int(1)), getat(2)),
+            add(getat(0), int(1)),
+            add(getat(1), getat(0)),
+            getat(2),
+            getat(3)
+        ),
+    );
+    let sum_to = function(
+        "sum_to",
+        tuplet!(intt(), statet()),
+        tuplet!(intt(), statet()),
+        parallel!(get(sum_loop.clone(), 1), get(sum_loop, 3)),
+    );
+    let log = function(
+        "log",
+        tuplet!(intt(), statet()),
+        tuplet!(intt(), statet()),
+        parallel!(getat(0), tprint(getat(0), getat(1))),
+    );
+    // calls an effectful function, so it keeps its state
+    let log_call = call("log", parallel!(getat(0), getat(1)));
+    let log_twice = function(
+        "log_twice",
+        tuplet!(intt(), statet()),
+        tuplet!(intt(), statet()),
+        parallel!(
+            get(log_call.clone(), 0),
+            tprint(get(log_call.clone(), 0), get(log_call, 1))
+        ),
+    );
+
+    let c1 = call("square", parallel!(getat(0), getat(1)));
+    let c2 = call("fact", parallel!(get(c1.clone(), 0), get(c1, 1)));
+    let c3 = call("sum_to", parallel!(getat(0), get(c2.clone(), 1)));
+    let c4 = call(
+        "log_twice",
+        parallel!(add(get(c2, 0), get(c3.clone(), 0)), get(c3, 1)),
+    );
+    let main = function(
+        "main",
+        tuplet!(intt(), statet()),
+        tuplet!(statet()),
+        single(tprint(get(c4.clone(), 0), get(c4, 1))),
+    );
+    let program = program!(main, square, fact, sum_to, log, log_twice);
+
+    let stripped = strip_state_from_pure_functions(&program)
+        .unwrap()
+        .add_context()
+        .0;
+
+    for name in ["square", "fact", "sum_to"] {
+        let func = stripped.get_function(name).unwrap();
+        assert_eq!(func.func_input_ty(), Some(tuplet!(intt())));
+        assert_eq!(func.func_output_ty(), Some(tuplet!(intt())));
+    }
+    for name in ["main", "log", "log_twice"] {
+        let func = stripped.get_function(name).unwrap();
+        let original = program.get_function(name).unwrap();
+        assert_eq!(func.func_input_ty(), original.func_input_ty());
+        assert_eq!(func.func_output_ty(), original.func_output_ty());
+    }
+
+    let input = tuplev!(intv(3), statev());
+    assert_eq!(
+        interpret_dag_prog(&stripped, &input),
+        interpret_dag_prog(&program, &input)
+    );
+}
+
+#[test]
+fn test_no_pure_functions() {
+    use crate::ast::*;
+
+    let log = function(
+        "log",
+        tuplet!(intt(), statet()),
+        tuplet!(statet()),
+        single(tprint(getat(0), getat(1))),
+    );
+    let main = function(
+        "main",
+        tuplet!(intt(), statet()),
+        tuplet!(statet()),
+        call("log", arg()),
+    );
+    let program = program!(main, log);
+
+    assert!(strip_state_from_pure_functions(&program).is_none());
+}
@@ -0,0 +1,121 @@
+/// Facts `(PowerOfTwo c k)` where c is 2^k, for every positive k that fits in an i64.
+fn powers_of_two() -> String {
+    let facts = (1..63)
+        .map(|k| format!("(PowerOfTwo {} {k})", 1_i64 << k))
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!(
+        "
+;;                    c   k
+(relation PowerOfTwo (i64 i64))
+{facts}"
+    )
+}
+
+pub(crate) fn rules() -> Vec<String> {
+    vec![
+        powers_of_two(),
+        include_str!("strength_reduction.egg").to_string(),
+    ]
+}
+
+#[cfg(test)]
+use crate::{ast::*, egglog_test};
+
+#[test]
+fn mul_by_power_of_two() -> crate::Result {
+    let prog = mul(arg(), int(8)).with_arg_types(base(intt()), base(intt()));
+    let shifted = shl(arg(), int(3)).with_arg_types(base(intt()), base(intt()));
+    egglog_test(
+        &format!("{prog}"),
+        &format!("(check (= {prog} {shifted}))"),
+        vec![prog.to_program(base(intt()), base(intt()))],
+        intv(-5),
+        intv(-40),
+        vec![],
+    )
+}
+
+#[test]
+fn mul_by_small_constants() -> crate::Result {
+    let five = mul(int(5), arg()).with_arg_types(base(intt()), base(intt()));
+    let five_reduced = add(shl(arg(), int(2)), arg()).with_arg_types(base(intt()), base(intt()));
+    let seven = mul(arg(), int(7)).with_arg_types(base(intt()), base(intt()));
+    let seven_reduced = sub(shl(arg(), int(3)), arg()).with_arg_types(base(intt()), base(intt()));
+    egglog_test(
+        &format!("{five}\n{seven}"),
+        &format!("(check (= {five} {five_reduced}))\n(check (= {seven} {seven_reduced}))"),
+        vec![
+            five.to_program(base(intt()), base(intt())),
+            seven.to_program(base(intt()), base(intt())),
+        ],
+        intv(3),
+        intv(15),
+        vec![],
+    )
+}
+
+#[test]
+fn mul_by_extreme_constants() -> crate::Result {
+    // i64::MAX + 1 and i64::MIN - 1 wrap around, so neither is reduced
+    let max = mul(arg(), int(i64::MAX)).with_arg_types(base(intt()), base(intt()));
+    let min = mul(arg(), int(i64::MIN)).with_arg_types(base(intt()), base(intt()));
+    let prog =
+        parallel!(max.clone(), min.clone()).with_arg_types(base(intt()), tuplet!(intt(), intt()));
+    egglog_test(
+        &format!("{prog}"),
+        &format!(
+            "
+(fail (check (= {max} (Bop (Sub) (Bop (Shl) x k) x))))
+(fail (check (= {min} (Bop (Add) (Bop (Shl) x k) x))))"
+        ),
+        vec![prog.to_program(base(intt()), tuplet!(intt(), intt()))],
+        intv(3),
+        tuplev!(
+            intv(3_i64.wrapping_mul(i64::MAX)),
+            intv(3_i64.wrapping_mul(i64::MIN))
+        ),
+        vec![],
+    )
+}
+
+#[test]
+fn div_by_power_of_two_rounds_toward_zero() -> crate::Result {
+    let prog = div(arg(), int(4)).with_arg_types(base(intt()), base(intt()));
+    let fixup = select(less_than(arg(), int(0)), int(3), int(0));
+    let reduced = shr(add(arg(), fixup), int(2)).with_arg_types(base(intt()), base(intt()));
+    egglog_test(
+        &format!("{prog}"),
+        &format!("(check (= {prog} {reduced}))"),
+        vec![
+            prog.to_program(base(intt()), base(intt())),
+            reduced.to_program(base(intt()), base(intt())),
+        ],
+        // -7 / 4 is -1, but -7 >> 2 is -2
+        intv(-7),
+        intv(-1),
+        vec![],
+    )
+}
+
+#[test]
+fn div_of_non_negative_by_power_of_two() -> crate::Result {
+    // if (x < 0) { 0 } else { x / 2 }
+    // x is at least 0 in the else branch, so x / 2 is x >> 1
+    let prog = tif(
+        less_than(getat(0), int(0)),
+        single(getat(0)),
+        single(int(0)),
+        single(div(getat(0), int(2))),
+    )
+    .with_arg_types(tuplet!(intt()), tuplet!(intt()));
+    egglog_test(
+        &format!("{prog}"),
+        "(check (= (Bop (Div) x (Const (Int 2) ty ctx))
+                   (Bop (Shr) x (Const (Int 1) ty ctx))))",
+        vec![prog.to_program(tuplet!(intt()), tuplet!(intt()))],
+        tuplev!(intv(9)),
+        tuplev!(intv(4)),
+        vec![],
+    )
+}
@@ -156,3 +156,115 @@ fn single_branch_switch() -> crate::Result {
         vec![],
     )
 }
+
+#[test]
+fn if_chain_to_switch() -> crate::Result {
+    use crate::ast::*;
+
+    // if x == 1 then 10 else if x == 2 then 20 else if x == 3 then 30 else 40
+    let chain = tif(
+        eq(getat(0), int(1)),
+        arg(),
+        int(10),
+        tif(
+            eq(getat(0), int(2)),
+            arg(),
+            int(20),
+            tif(eq(getat(0), int(3)), arg(), int(30), int(40)),
+        ),
+    );
+    let (build, build_cache) = chain
+        .clone()
+        .with_arg_types(tuplet!(intt()), base(intt()))
+        .add_dummy_ctx();
+
+    let pred = select(
+        eq(getat(0), int(1)),
+        int(0),
+        select(eq(getat(0), int(2)), int(1), int(2)),
+    );
+    let pred = select(
+        eq(pred.clone(), int(2)),
+        select(eq(getat(0), int(3)), int(2), int(3)),
+        pred,
+    );
+    let (check, check_cache) = switch!(pred, arg(); int(10), int(20), int(30), int(40))
+        .with_arg_types(tuplet!(intt()), base(intt()))
+        .add_dummy_ctx();
+
+    // 1, 2, 3 are consecutive, so the predicate is also x - 1 when in range
+    let offset = sub(getat(0), int(1));
+    let dense_pred = select(
+        and(
+            less_eq(int(0), offset.clone()),
+            less_than(offset.clone(), int(3)),
+        ),
+        offset,
+        int(3),
+    );
+    let (dense, dense_cache) = switch!(dense_pred, arg(); int(10), int(20), int(30), int(40))
+        .with_arg_types(tuplet!(intt()), base(intt()))
+        .add_dummy_ctx();
+
+    egglog_test(
+        &format!("(let build_ {build})\n{}", build_cache.get_unions()),
+        &format!(
+            "(let check_ {check})\n{}\n(check (= build_ check_))
+(let dense_ {dense})\n{}\n(check (= build_ dense_))",
+            check_cache.get_unions(),
+            dense_cache.get_unions()
+        ),
+        vec![chain.to_program(tuplet!(intt()), base(intt()))],
+        tuplev!(intv(3)),
+        intv(30),
+        vec![],
+    )
+}
+
+#[test]
+fn if_chain_to_switch_sparse() -> crate::Result {
+    use crate::ast::*;
+
+    // if x == 1 then 10 else if x == 2 then 20 else if x == 4 then 30 else 40
+    let chain = tif(
+        eq(getat(0), int(1)),
+        arg(),
+        int(10),
+        tif(
+            eq(getat(0), int(2)),
+            arg(),
+            int(20),
+            tif(eq(getat(0), int(4)), arg(), int(30), int(40)),
+        ),
+    );
+    let (build, build_cache) = chain
+        .clone()
+        .with_arg_types(tuplet!(intt()), base(intt()))
+        .add_dummy_ctx();
+
+    // 3 isn't one of the constants, so x - 1 would pick the wrong branch
+    let offset = sub(getat(0), int(1));
+    let dense_pred = select(
+        and(
+            less_eq(int(0), offset.clone()),
+            less_than(offset.clone(), int(3)),
+        ),
+        offset,
+        int(3),
+    );
+    let (dense, dense_cache) = switch!(dense_pred, arg(); int(10), int(20), int(30), int(40))
+        .with_arg_types(tuplet!(intt()), base(intt()))
+        .add_dummy_ctx();
+
+    egglog_test(
+        &format!("(let build_ {build})\n{}", build_cache.get_unions()),
+        &format!(
+            "(let dense_ {dense})\n{}\n(fail (check (= build_ dense_)))",
+            dense_cache.get_unions()
+        ),
+        vec![chain.to_program(tuplet!(intt()), base(intt()))],
+        tuplev!(intv(3)),
+        intv(40),
+        vec![],
+    )
+}
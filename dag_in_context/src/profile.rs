@@ -0,0 +1,156 @@
+//! Execution profiles collected by running a program in the interpreter.
+//! A profile records how many times each loop iterated and how many times
+//! each call site ran. Profiles are keyed by the `Rc` pointers of the profiled
+//! program, so they are only meaningful for that exact program.
+//! They are used for profile-guided optimization: loop trip counts become
+//! `LoopProfiledIters` facts for the extractor, and call sites are inlined
+//! hottest first, skipping those that never ran.
+
+use std::rc::Rc;
+
+use indexmap::{IndexMap, IndexSet};
+
+use crate::schema::{Expr, RcExpr, TreeProgram};
+
+#[derive(Clone, Debug)]
+pub struct LoopProfile {
+    /// The DoWhile expression that was profiled
+    pub loop_expr: RcExpr,
+    /// How many times the loop was entered
+    pub entries: u64,
+    /// Total number of iterations, summed over all entries
+    pub iterations: u64,
+}
+
+impl LoopProfile {
+    /// The average number of iterations per entry, rounded up.
+    /// Returns None if the loop never ran.
+    pub fn average_iterations(&self) -> Option<i64> {
+        if self.entries == 0 {
+            return None;
+        }
+        let average = self.iterations.div_ceil(self.entries);
+        Some(i64::try_from(average).unwrap_or(i64::MAX))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ProfileData {
+    loops: IndexMap<*const Expr, LoopProfile>,
+    calls: IndexMap<*const Expr, (RcExpr, u64)>,
+}
+
+impl ProfileData {
+    /// Makes an empty profile for `program`, where every loop
+    /// and call site in the program starts with a count of zero.
+    /// This way, code that never ran can be told apart from code
+    /// that is not part of the profiled program.
+    pub(crate) fn new(program: &TreeProgram) -> ProfileData {
+        let mut profile = ProfileData::default();
+        let mut seen: IndexSet<*const Expr> = IndexSet::new();
+        let mut todo = vec![program.entry.clone()];
+        todo.extend(program.functions.iter().cloned());
+        while let Some(expr) = todo.pop() {
+            if !seen.insert(Rc::as_ptr(&expr)) {
+                continue;
+            }
+            match expr.as_ref() {
+                Expr::DoWhile(..) => {
+                    profile.loops.insert(
+                        Rc::as_ptr(&expr),
+                        LoopProfile {
+                            loop_expr: expr.clone(),
+                            entries: 0,
+                            iterations: 0,
+                        },
+                    );
+                }
+                Expr::Call(..) => {
+                    profile.calls.insert(Rc::as_ptr(&expr), (expr.clone(), 0));
+                }
+                _ => (),
+            }
+            todo.extend(expr.children_exprs());
+        }
+        profile
+    }
+
+    /// Records one run of `loop_expr` that iterated `iterations` times.
+    pub(crate) fn record_loop(&mut self, loop_expr: &RcExpr, iterations: u64) {
+        let loop_profile = self
+            .loops
+            .entry(Rc::as_ptr(loop_expr))
+            .or_insert_with(|| LoopProfile {
+                loop_expr: loop_expr.clone(),
+                entries: 0,
+                iterations: 0,
+            });
+        loop_profile.entries += 1;
+        loop_profile.iterations += iterations;
+    }
+
+    /// Records one execution of the call site `call`.
+    pub(crate) fn record_call(&mut self, call: &RcExpr) {
+        self.calls
+            .entry(Rc::as_ptr(call))
+            .or_insert_with(|| (call.clone(), 0))
+            .1 += 1;
+    }
+
+    /// All the loops in the profiled program.
+    pub fn loops(&self) -> impl Iterator<Item = &LoopProfile> {
+        self.loops.values()
+    }
+
+    /// The profile of `loop_expr`, or None if it is not part of the profiled program.
+    pub fn loop_profile(&self, loop_expr: &RcExpr) -> Option<&LoopProfile> {
+        self.loops.get(&Rc::as_ptr(loop_expr))
+    }
+
+    /// How many times the call site `call` ran,
+    /// or None if it is not part of the profiled program.
+    pub fn call_count(&self, call: &RcExpr) -> Option<u64> {
+        self.calls
+            .get(&Rc::as_ptr(call))
+            .map(|(_call, count)| *count)
+    }
+}
+
+#[test]
+fn test_profile_loop_and_call_counts() {
+    use crate::ast::*;
+    use crate::interpreter::{profile_dag_prog, Value};
+    use crate::schema::Constant;
+
+    let hot_call = call("inc", getat(0));
+    let cold_call = call("inc", arg());
+    let my_loop = dowhile(
+        parallel!(arg()),
+        parallel!(less_than(hot_call.clone(), int(10)), hot_call.clone()),
+    );
+    let prog = program!(
+        function(
+            "main",
+            base(intt()),
+            base(intt()),
+            tif(
+                less_than(arg(), int(0)),
+                get(my_loop.clone(), 0),
+                arg(),
+                cold_call.clone(),
+            )
+        ),
+        function("inc", base(intt()), base(intt()), add(arg(), int(1))),
+    );
+
+    let (res, profile) = profile_dag_prog(&prog, &Value::Const(Constant::Int(-5)));
+    assert_eq!(res, Value::Const(Constant::Int(10)));
+
+    let loop_profile = profile.loop_profile(&my_loop).unwrap();
+    assert_eq!(loop_profile.entries, 1);
+    assert_eq!(loop_profile.iterations, 15);
+    assert_eq!(loop_profile.average_iterations(), Some(15));
+    assert_eq!(profile.call_count(&hot_call), Some(15));
+    assert_eq!(profile.call_count(&cold_call), Some(0));
+    assert_eq!(profile.call_count(&call("inc", arg())), None);
+}
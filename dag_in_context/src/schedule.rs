@@ -53,7 +53,8 @@ pub(crate) fn helpers() -> String {
     (saturate canon)
     (saturate interval-analysis)
     (saturate terms)
-    ;; memory-helpers TODO run memory helpers for memory optimizations
+    ;; alias analysis and known pointer contents, used by memory
+    (saturate always-run memory-helpers)
 
     ;; finally, subsume now that helpers are done
     subsume-after-helpers
@@ -62,20 +63,21 @@ pub(crate) fn helpers() -> String {
     boundary-analysis
 
     loop-iters-analysis
+    ;; which sequential loops can be fused, see loop_fusion.egg
+    (saturate loop-fusion-analysis)
 "
     .to_string()
 }
 
 fn cheap_optimizations() -> Vec<String> {
-    // TODO enable loop peeling
-    // currently causes saturation issues, probably by creating dead loops that are allowed to have any value
-
     [
         "loop-simplify",
+        "loop-peel",
         "interval-rewrite",
         "always-switch-rewrite",
-        // "memory",
+        "memory",
         "peepholes",
+        "constant-folding",
     ]
     .iter()
     .map(|opt| opt.to_string())
@@ -86,9 +88,14 @@ fn optimizations() -> Vec<String> {
     [
         "select_opt",
         "loop-unroll",
+        "loop-fusion",
         "switch_rewrite",
         "loop-inv-motion",
+        "conditional-invariant-code-motion",
         "loop-strength-reduction",
+        "loop-closed-form",
+        "loop-interchange",
+        "strength-reduction",
     ]
     .iter()
     .map(|opt| opt.to_string())
@@ -210,3 +217,21 @@ pub fn parallel_schedule() -> Vec<CompilerPass> {
         )),
     ]
 }
+
+/// Reassociates and factors float arithmetic, see `fast_math.egg`.
+/// Only added to the schedule when `EggccConfig::fast_math` is set.
+pub fn fast_math_pass() -> CompilerPass {
+    let helpers = helpers();
+
+    CompilerPass::Schedule(format!(
+        "
+(run-schedule
+    (repeat 2
+        {helpers}
+        fast-math
+    )
+    {helpers}
+)
+"
+    ))
+}
@@ -0,0 +1,52 @@
+use clap::Parser;
+use eggcc::calibrate::{calibrate, CalibrationConfig};
+use eggcc::util::LLVMOptLevel;
+use std::path::PathBuf;
+
+/// Measures how many cycles each operator takes using LLVM-compiled microbenchmarks,
+/// and writes a cost table for `--cost-model table --cost-table <file>`.
+#[derive(Debug, Parser)]
+struct Args {
+  /// Where to write the cost table
+  output: PathBuf,
+  /// How many times the loop of each microbenchmark runs
+  #[clap(long)]
+  iterations: Option<u64>,
+  /// How many copies of the operator are in each loop body
+  #[clap(long)]
+  ops_per_iteration: Option<usize>,
+  /// How many times each microbenchmark is run. The fastest run is used.
+  #[clap(long)]
+  samples: Option<usize>,
+  /// The LLVM optimization level for the microbenchmarks (O0_O0 by default)
+  #[clap(long)]
+  optimize_bril_llvm: Option<LLVMOptLevel>,
+}
+
+fn main() {
+  let args = Args::parse();
+  env_logger::init();
+
+  let default_config = CalibrationConfig::default();
+  let config = CalibrationConfig {
+    iterations: args.iterations.unwrap_or(default_config.iterations),
+    ops_per_iteration: args
+      .ops_per_iteration
+      .unwrap_or(default_config.ops_per_iteration),
+    samples: args.samples.unwrap_or(default_config.samples),
+    llvm_level: args.optimize_bril_llvm.unwrap_or(default_config.llvm_level),
+  };
+
+  let table = match calibrate(&config) {
+    Ok(table) => table,
+    Err(error) => {
+      panic!("{}", error);
+    }
+  };
+
+  let contents = format!(
+    "# Cost table calibrated with {} iterations of {} ops, {} samples, LLVM {:?}\n{table}",
+    config.iterations, config.ops_per_iteration, config.samples, config.llvm_level
+  );
+  std::fs::write(&args.output, contents).expect("failed to write cost table");
+}
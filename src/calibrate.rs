@@ -0,0 +1,299 @@
+//! Calibrates the per-op costs used by `TableCostModel`.
+//!
+//! For each operator we generate a Bril microbenchmark: a loop whose body
+//! runs the operator `ops_per_iteration` times. Each microbenchmark is compiled
+//! through `RunMode::LLVM` with `add_timing`, so the executable reports the cycles
+//! it took using the runtime's `_bril_get_ticks_*` functions.
+//! Subtracting the cycles of a baseline loop with an empty body gives the cycles
+//! per operation, which `CostTable::from_measurements` turns into a cost table.
+
+use std::path::Path;
+
+use dag_in_context::cost_table::CostTable;
+use indexmap::IndexMap;
+
+use crate::{
+    util::{
+        parse_from_string, InterpMode, Interpretable, LLVMOptLevel, ProgWithArguments, Run, RunMode,
+    },
+    EggCCError, Optimizer,
+};
+
+/// Costs are scaled so that this operator matches `DefaultCostModel`.
+const REFERENCE_OP: &str = "Add";
+
+/// A microbenchmark for one egglog operator.
+/// `instr` is a Bril instruction using the variables set up by `microbenchmark`:
+/// ints `a` and `b`, floats `x` and `y`, bools `p` and `q`, and the pointer `ptr`.
+/// Where possible, the instruction writes back to one of its inputs
+/// so that the operations form a dependency chain.
+struct OpBenchmark {
+    op: &'static str,
+    instr: &'static str,
+}
+
+const OP_BENCHMARKS: &[OpBenchmark] = &[
+    OpBenchmark {
+        op: "Add",
+        instr: "a: int = add a b;",
+    },
+    OpBenchmark {
+        op: "Sub",
+        instr: "a: int = sub a b;",
+    },
+    OpBenchmark {
+        op: "Mul",
+        instr: "a: int = mul a b;",
+    },
+    OpBenchmark {
+        op: "Div",
+        instr: "a: int = div a b;",
+    },
+    OpBenchmark {
+        op: "Shl",
+        instr: "a: int = shl a b;",
+    },
+    OpBenchmark {
+        op: "Shr",
+        instr: "a: int = shr a b;",
+    },
+    OpBenchmark {
+        op: "Smax",
+        instr: "a: int = smax a b;",
+    },
+    OpBenchmark {
+        op: "Smin",
+        instr: "a: int = smin a b;",
+    },
+    OpBenchmark {
+        op: "And",
+        instr: "p: bool = and p q;",
+    },
+    OpBenchmark {
+        op: "Or",
+        instr: "p: bool = or p q;",
+    },
+    OpBenchmark {
+        op: "Not",
+        instr: "p: bool = not p;",
+    },
+    OpBenchmark {
+        op: "Eq",
+        instr: "p: bool = eq a b;",
+    },
+    OpBenchmark {
+        op: "LessThan",
+        instr: "p: bool = lt a b;",
+    },
+    OpBenchmark {
+        op: "GreaterThan",
+        instr: "p: bool = gt a b;",
+    },
+    OpBenchmark {
+        op: "LessEq",
+        instr: "p: bool = le a b;",
+    },
+    OpBenchmark {
+        op: "GreaterEq",
+        instr: "p: bool = ge a b;",
+    },
+    OpBenchmark {
+        op: "Select",
+        instr: "a: int = select p a b;",
+    },
+    OpBenchmark {
+        op: "FAdd",
+        instr: "x: float = fadd x y;",
+    },
+    OpBenchmark {
+        op: "FSub",
+        instr: "x: float = fsub x y;",
+    },
+    OpBenchmark {
+        op: "FMul",
+        instr: "x: float = fmul x y;",
+    },
+    OpBenchmark {
+        op: "FDiv",
+        instr: "x: float = fdiv x y;",
+    },
+    OpBenchmark {
+        op: "Fmax",
+        instr: "x: float = fmax x y;",
+    },
+    OpBenchmark {
+        op: "Fmin",
+        instr: "x: float = fmin x y;",
+    },
+    OpBenchmark {
+        op: "FEq",
+        instr: "p: bool = feq x y;",
+    },
+    OpBenchmark {
+        op: "FLessThan",
+        instr: "p: bool = flt x y;",
+    },
+    OpBenchmark {
+        op: "FGreaterThan",
+        instr: "p: bool = fgt x y;",
+    },
+    OpBenchmark {
+        op: "FLessEq",
+        instr: "p: bool = fle x y;",
+    },
+    OpBenchmark {
+        op: "FGreaterEq",
+        instr: "p: bool = fge x y;",
+    },
+    OpBenchmark {
+        op: "Load",
+        instr: "a: int = load ptr;",
+    },
+    OpBenchmark {
+        op: "Write",
+        instr: "store ptr a;",
+    },
+    OpBenchmark {
+        op: "PtrAdd",
+        instr: "ptr: ptr<int> = ptradd ptr zero;",
+    },
+];
+
+#[derive(Clone, Debug)]
+pub struct CalibrationConfig {
+    /// How many times the loop of each microbenchmark runs
+    pub iterations: u64,
+    /// How many copies of the operator are in the loop body
+    pub ops_per_iteration: usize,
+    /// How many times each microbenchmark is run.
+    /// The fastest run is used, since noise only makes runs slower.
+    pub samples: usize,
+    /// Microbenchmarks are compiled at O0 by default so that LLVM
+    /// doesn't fold or hoist the operations being measured.
+    pub llvm_level: LLVMOptLevel,
+}
+
+impl Default for CalibrationConfig {
+    fn default() -> Self {
+        CalibrationConfig {
+            iterations: 1_000_000,
+            ops_per_iteration: 16,
+            samples: 5,
+            llvm_level: LLVMOptLevel::O0_O0,
+        }
+    }
+}
+
+/// Generates a microbenchmark that runs `instr` `ops_per_iteration` times
+/// per loop iteration, or a baseline loop when `instr` is None.
+/// The loop runs as many times as the program's argument.
+fn microbenchmark(instr: Option<&str>, ops_per_iteration: usize) -> String {
+    let body = instr
+        .map(|instr| format!("  {instr}\n").repeat(ops_per_iteration))
+        .unwrap_or_default();
+    format!(
+        "@main(n: int) {{
+  a: int = const 7;
+  b: int = const 1;
+  zero: int = const 0;
+  one: int = const 1;
+  x: float = const 7.5;
+  y: float = const 1.0;
+  p: bool = const true;
+  q: bool = const false;
+  ptr: ptr<int> = alloc one;
+  store ptr a;
+  i: int = const 0;
+.loop:
+{body}  i: int = add i one;
+  c: bool = lt i n;
+  br c .loop .done;
+.done:
+  print a x p;
+  free ptr;
+}}
+"
+    )
+}
+
+/// Compiles the microbenchmark with timing enabled and returns
+/// the fewest cycles it took over `config.samples` runs.
+fn measure_cycles(
+    name: &str,
+    program: &str,
+    config: &CalibrationConfig,
+    output_dir: &Path,
+) -> Result<u64, EggCCError> {
+    let args = vec![config.iterations.to_string()];
+    let executable = output_dir.join(name).to_str().unwrap().to_string();
+    let mut run = Run::new(
+        ProgWithArguments::new(parse_from_string(program), name.to_string(), args.clone()),
+        RunMode::LLVM,
+    );
+    run.interp = InterpMode::Interp;
+    run.optimize_egglog = Some(false);
+    run.optimize_bril_llvm = Some(config.llvm_level);
+    run.add_timing = true;
+    run.output_path = Some(executable.clone());
+
+    let mut fewest_cycles = run
+        .run()?
+        .cycles_taken
+        .expect("Timed executables report the cycles they took");
+    let interpretable = Interpretable::CycleMeasuringExecutable { executable };
+    for _ in 1..config.samples {
+        let (_output, cycles) = Optimizer::interp(&interpretable, args.clone(), None);
+        fewest_cycles = fewest_cycles.min(cycles.unwrap());
+    }
+    Ok(fewest_cycles)
+}
+
+/// Runs every microbenchmark and fits a cost table to the results.
+pub fn calibrate(config: &CalibrationConfig) -> Result<CostTable, EggCCError> {
+    let output_dir = tempfile::tempdir()
+        .map_err(|err| EggCCError::Calibration(format!("Couldn't create temp dir: {err}")))?;
+    let total_ops = (config.iterations * config.ops_per_iteration as u64) as f64;
+
+    let baseline = measure_cycles(
+        "calibrate_baseline",
+        &microbenchmark(None, config.ops_per_iteration),
+        config,
+        output_dir.path(),
+    )?;
+    log::info!("Baseline loop took {baseline} cycles");
+
+    let mut measurements = IndexMap::new();
+    for benchmark in OP_BENCHMARKS {
+        let cycles = measure_cycles(
+            &format!("calibrate_{}", benchmark.op),
+            &microbenchmark(Some(benchmark.instr), config.ops_per_iteration),
+            config,
+            output_dir.path(),
+        )?;
+        let cycles_per_op = (cycles as f64 - baseline as f64) / total_ops;
+        log::info!("{} took {cycles_per_op} cycles per operation", benchmark.op);
+        measurements.insert(benchmark.op.to_string(), cycles_per_op);
+    }
+
+    CostTable::from_measurements(&measurements, REFERENCE_OP)
+        .map_err(|err| EggCCError::Calibration(format!("Failed to fit cost table: {err}")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{microbenchmark, OP_BENCHMARKS};
+    use crate::{util::parse_from_string, Optimizer};
+
+    /// Every microbenchmark should be a valid Bril program.
+    /// Running them in the interpreter doesn't need LLVM.
+    #[test]
+    fn test_microbenchmarks_run() {
+        let baseline = parse_from_string(&microbenchmark(None, 2));
+        Optimizer::interp_bril(&baseline, vec!["3".to_string()], None);
+
+        for benchmark in OP_BENCHMARKS {
+            let program = parse_from_string(&microbenchmark(Some(benchmark.instr), 2));
+            Optimizer::interp_bril(&program, vec!["3".to_string()], None);
+        }
+    }
+}
@@ -13,6 +13,7 @@ use util::Interpretable;
 
 use thiserror::Error;
 
+pub mod calibrate;
 pub mod canonicalize_names;
 pub(crate) mod cfg;
 mod conversions;
@@ -36,6 +37,10 @@ pub enum EggCCError {
     RvsdgError(RvsdgError),
     #[error("Uninitialized variable {0} used in function {1}")]
     UninitializedVariable(String, String),
+    #[error("Calibration error: {0}")]
+    Calibration(String),
+    #[error("Autotuning error: {0}")]
+    Autotune(String),
 }
 
 pub struct Optimizer {
@@ -76,7 +81,7 @@ impl Optimizer {
     }
 
     /// Produces a vector of values, one per string argument to the program
-    fn parse_arguments(args: Vec<String>) -> Vec<Value> {
+    pub fn parse_arguments(args: Vec<String>) -> Vec<Value> {
         args.into_iter()
             .map(|arg| {
                 if let Ok(int) = arg.parse::<i64>() {
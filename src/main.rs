@@ -1,10 +1,13 @@
 use clap::Parser;
-use dag_in_context::{EggccConfig, Schedule};
+use dag_in_context::cost_table::CostTable;
+use dag_in_context::{CostModelKind, EggccConfig, ExtractionMode, Schedule};
 use eggcc::util::{visualize, InterpMode, LLVMOptLevel, Run, RunMode, TestProgram};
+use eggcc::Optimizer;
 use std::{ffi::OsStr, path::PathBuf};
 use colored::Colorize;
 use std::str::FromStr;
 use std::io::Write;
+use std::time::Duration;
 
 
 #[derive(Debug, Parser)]
@@ -70,6 +73,39 @@ struct Args {
   /// WARNING: Produces unsound results!
   #[clap(long)]
   no_linearity: bool,
+
+  /// Run the program in the interpreter on its arguments before each pass,
+  /// and use the observed loop trip counts and call counts to guide
+  /// extraction and inlining.
+  #[clap(long)]
+  profile_guided: bool,
+
+  /// Choose between greedy extraction and exact extraction,
+  /// which searches for a cheaper program starting from the greedy one.
+  #[clap(long)]
+  extraction_mode: Option<ExtractionMode>,
+  /// How many seconds exact extraction may search for each function.
+  #[clap(long)]
+  exact_extraction_time_limit: Option<u64>,
+  /// Choose the cost model for extraction: `default` optimizes for speed,
+  /// `size` optimizes for code size, and `table` optimizes for speed
+  /// using the costs in `--cost-table`.
+  #[clap(long)]
+  cost_model: Option<CostModelKind>,
+  /// A cost table for the `table` cost model, as written by `calibrate_costs`.
+  #[clap(long)]
+  cost_table: Option<PathBuf>,
+  /// Write a report of the e-nodes that extraction skipped to maintain linearity
+  /// to this file. Useful for seeing why a memory optimization didn't fire.
+  #[clap(long)]
+  linearity_report: Option<PathBuf>,
+  /// How many candidate programs `--run-mode autotune` compiles and times.
+  #[clap(long)]
+  autotune_candidates: Option<usize>,
+  /// Allow reassociating and factoring float arithmetic,
+  /// which can slightly change the printed results.
+  #[clap(long)]
+  fast_math: bool,
 }
 
 
@@ -127,6 +163,11 @@ fn main() {
     return;
   }
 
+  if args.cost_model == Some(CostModelKind::Table) && args.cost_table.is_none() {
+    eprintln!("The table cost model requires a cost table, pass one with --cost-table.");
+    return;
+  }
+
   let file = match args.file.extension().and_then(OsStr::to_str) {
     Some("rs") => TestProgram::RustFile(args.file.clone()),
     Some("bril") => TestProgram::BrilFile(args.file.clone()),
@@ -134,8 +175,13 @@ fn main() {
     None => panic!("could not parse file extension"),
   };
 
+  let prog_with_args = file.read_program();
+  let profile_args = args
+    .profile_guided
+    .then(|| Optimizer::parse_arguments(prog_with_args.args.clone()));
+
   let run = Run {
-    prog_with_args: file.read_program(),
+    prog_with_args,
     test_type: args.run_mode,
     interp: if args.interp {
       InterpMode::Interp
@@ -153,6 +199,22 @@ fn main() {
       schedule: args.eggcc_schedule.unwrap_or(Schedule::default()),
       stop_after_n_passes: args.stop_after_n_passes.unwrap_or(i64::MAX),
       linearity: !args.no_linearity,
+      profile_args,
+      extraction_mode: args.extraction_mode.unwrap_or_default(),
+      exact_extraction_time_limit: args
+        .exact_extraction_time_limit
+        .map(Duration::from_secs)
+        .unwrap_or(EggccConfig::default().exact_extraction_time_limit),
+      cost_model: args.cost_model.unwrap_or_default(),
+      cost_table: args.cost_table.map(|path| match CostTable::load(&path) {
+        Ok(table) => table,
+        Err(error) => panic!("{}", error),
+      }),
+      linearity_report: args.linearity_report,
+      autotune_candidates: args
+        .autotune_candidates
+        .unwrap_or(EggccConfig::default().autotune_candidates),
+      fast_math: args.fast_math,
     },
   };
 
@@ -1,9 +1,10 @@
 use crate::canonicalize_names::canonicalize_bril;
 use crate::rvsdg::from_dag::dag_to_rvsdg;
 use crate::{EggCCError, Optimizer};
-use bril_rs::Program;
+use bril_rs::{Code, Instruction, Program, Type};
 use clap::ValueEnum;
 use dag_in_context::dag2svg::tree_to_svg;
+use dag_in_context::interpreter::{profile_dag_prog, Value};
 use dag_in_context::schedule::{self};
 use dag_in_context::{build_program, check_roundtrip_egraph, EggccConfig, Schedule};
 
@@ -140,6 +141,51 @@ where
     }
 }
 
+/// How far apart two printed floats may be when `EggccConfig::fast_math`
+/// is on, relative to their magnitude.
+const FAST_MATH_TOLERANCE: f64 = 1e-6;
+
+/// Whether the output of an optimized program matches the expected output.
+/// With fast math, printed floats only need to be close to the expected ones,
+/// since reassociating float arithmetic changes how it rounds.
+pub fn outputs_match(expected: &str, got: &str, fast_math: bool) -> bool {
+    if !fast_math {
+        return expected == got;
+    }
+    let expected_tokens = expected.split_whitespace().collect::<Vec<_>>();
+    let got_tokens = got.split_whitespace().collect::<Vec<_>>();
+    expected_tokens.len() == got_tokens.len()
+        && expected_tokens
+            .iter()
+            .zip(got_tokens)
+            .all(|(expected, got)| {
+                // integers and booleans still have to match exactly
+                if *expected == got || !expected.contains('.') {
+                    return *expected == got;
+                }
+                match (expected.parse::<f64>(), got.parse::<f64>()) {
+                    (Ok(expected), Ok(got)) => {
+                        (expected - got).abs()
+                            <= FAST_MATH_TOLERANCE * expected.abs().max(got.abs()).max(1.0)
+                    }
+                    _ => false,
+                }
+            })
+}
+
+// Whether the program does any float arithmetic
+fn uses_floats(program: &Program) -> bool {
+    program.functions.iter().any(|func| {
+        func.instrs.iter().any(|code| match code {
+            Code::Instruction(Instruction::Constant { const_type, .. }) => {
+                *const_type == Type::Float
+            }
+            Code::Instruction(Instruction::Value { op_type, .. }) => *op_type == Type::Float,
+            _ => false,
+        })
+    })
+}
+
 // Get the eggcc repo root directory. Set by $EGGCC_ROOT, defaults to current
 // directory.
 fn get_eggcc_root() -> String {
@@ -249,6 +295,11 @@ pub enum RunMode {
     /// Converts to an executable using brillvm.
     /// `optimize_egglog` and `optimize_bril_llvm` must be set.
     LLVM,
+    /// Extracts several candidate programs from the e-graph, compiles each
+    /// with brillvm, and keeps the one that runs in the fewest cycles
+    /// on the program's arguments.
+    /// `optimize_bril_llvm` must be set.
+    Autotune,
     /// Tests a benchmark by running several different configurations of CompileBrilLLVM
     /// and comparing the results.
     /// The different configurations are with and without egglog optimization, and with and without
@@ -279,7 +330,8 @@ impl RunMode {
             | RunMode::DagConversion
             | RunMode::DagOptimize
             | RunMode::Cranelift
-            | RunMode::LLVM => true,
+            | RunMode::LLVM
+            | RunMode::Autotune => true,
             RunMode::RvsdgConversion
             | RunMode::RvsdgToCfg
             | RunMode::Egglog
@@ -301,10 +353,18 @@ impl RunMode {
 pub struct ProgWithArguments {
     pub program: Program,
     name: String,
-    args: Vec<String>,
+    pub args: Vec<String>,
 }
 
 impl ProgWithArguments {
+    pub(crate) fn new(program: Program, name: String, args: Vec<String>) -> ProgWithArguments {
+        ProgWithArguments {
+            program,
+            name,
+            args,
+        }
+    }
+
     pub(crate) fn to_viz(&self) -> Visualization {
         Visualization {
             result: "# ARGS: ".to_string()
@@ -469,13 +529,29 @@ impl Run {
         let (dag, mut cache) = rvsdg.to_dag_encoding(true);
         let optimized =
             dag_in_context::optimize(&dag, &mut cache, config).map_err(EggCCError::EggLog)?;
-        let rvsdg2 = dag_to_rvsdg(&optimized);
+        Ok(Run::optimized_dag_to_bril(&optimized))
+    }
+
+    /// Like `optimize_bril`, but returns up to `config.autotune_candidates`
+    /// distinct optimized programs, cheapest first.
+    fn optimize_bril_top_k(
+        program: &Program,
+        config: &EggccConfig,
+    ) -> Result<Vec<Program>, EggCCError> {
+        let rvsdg = Optimizer::program_to_rvsdg(program)?;
+        let (dag, mut cache) = rvsdg.to_dag_encoding(true);
+        let candidates =
+            dag_in_context::optimize_top_k(&dag, &mut cache, config, config.autotune_candidates)
+                .map_err(EggCCError::EggLog)?;
+        Ok(candidates.iter().map(Run::optimized_dag_to_bril).collect())
+    }
+
+    fn optimized_dag_to_bril(optimized: &TreeProgram) -> Program {
+        let rvsdg2 = dag_to_rvsdg(optimized);
         let cfg = rvsdg2.to_cfg();
         let bril = cfg.to_bril();
         // re-name variables in the bril, hiding our nondeterminism bug ):
-        let bril = canonicalize_bril(&bril);
-
-        Ok(bril)
+        canonicalize_bril(&bril)
     }
 
     pub fn compile_brilift_config(
@@ -547,6 +623,17 @@ impl Run {
                 res.push(default);
             }
         }
+        // float programs are also tested with fast math,
+        // comparing their output with a tolerance
+        if uses_floats(&prog.program) {
+            let mut fast_math = Run {
+                interp: InterpMode::Interp,
+                ..Run::new(prog.clone(), RunMode::DagOptimize)
+            };
+            fast_math.eggcc_config.fast_math = true;
+            res.push(fast_math);
+        }
+
         // // also test the sequential schedule
         // let mut seq = Run::new(prog.clone(), RunMode::Optimize);
         // seq.eggcc_config.schedule = Schedule::Sequential;
@@ -592,6 +679,9 @@ impl Run {
                 true => "-O3",
             };
         }
+        if self.test_type == RunMode::Autotune {
+            name += &format!("-{}", self.optimize_bril_llvm.as_ref().unwrap());
+        }
         if self.test_type == RunMode::LLVM {
             let end = match self.optimize_egglog.unwrap() {
                 false => format!("-{}", self.optimize_bril_llvm.as_ref().unwrap()),
@@ -605,6 +695,9 @@ impl Run {
             Schedule::Parallel => "",
             Schedule::Sequential => "-sequential",
         };
+        if self.eggcc_config.fast_math {
+            name += "-fast-math";
+        }
 
         name
     }
@@ -771,7 +864,15 @@ impl Run {
                 let rvsdg =
                     crate::Optimizer::program_to_rvsdg(&self.prog_with_args.program).unwrap();
                 let (tree, mut cache) = rvsdg.to_dag_encoding(true);
-                let unfolded_program = build_program(&tree, None, &tree.fns(), &mut cache, "");
+                let unfolded_program = build_program(
+                    &tree,
+                    None,
+                    &tree.fns(),
+                    &mut cache,
+                    "",
+                    None,
+                    &EggccConfig::default(),
+                );
                 let folded_program = tree.pretty_print_to_egglog();
                 let program =
                     format!("{unfolded_program} \n {folded_program} \n (check (= PROG_PP PROG))");
@@ -825,7 +926,7 @@ impl Run {
             RunMode::Egglog => {
                 let rvsdg = Optimizer::program_to_rvsdg(&self.prog_with_args.program)?;
                 let (dag, mut cache) = rvsdg.to_dag_encoding(true);
-                let schedules = self.eggcc_config.schedule.get_schedule_list();
+                let schedules = self.eggcc_config.get_schedule_list();
 
                 // how many actual passes to run
                 let cutoff = self.eggcc_config.get_normalized_cutoff(schedules.len());
@@ -845,12 +946,20 @@ impl Run {
                     schedule::CompilerPass::InlineWithSchedule(_) => Some(&optimized),
                 };
 
+                let profile = self.eggcc_config.profile_args.as_ref().map(|args| {
+                    let mut args = args.clone();
+                    args.push(Value::StateV);
+                    profile_dag_prog(&optimized, &Value::Tuple(args)).1
+                });
+
                 let egglog = build_program(
                     &optimized,
                     inline_program,
                     &dag.fns(),
                     &mut cache,
                     last_schedule_step.egglog_schedule(),
+                    profile.as_ref(),
+                    &self.eggcc_config,
                 );
                 (
                     vec![Visualization {
@@ -920,6 +1029,15 @@ impl Run {
                 )?;
                 (vec![], Some(interpretable))
             }
+            RunMode::Autotune => {
+                let optimize_brillvm = self
+                    .optimize_bril_llvm
+                    .expect("optimize_bril_llvm is a required flag when running RunMode::Autotune");
+                let fastest = self.fastest_candidate(optimize_brillvm)?;
+                let interpretable =
+                    self.run_bril_llvm(fastest, false, optimize_brillvm, self.add_timing)?;
+                (vec![], Some(interpretable))
+            }
             RunMode::TestBenchmark => {
                 // optimize_egglog and optimize_brilift should not be set
                 assert!(self.optimize_egglog.is_none());
@@ -953,7 +1071,11 @@ impl Run {
                             self.prog_with_args.args.clone(),
                             None,
                         );
-                        if interpreted != new_interpreted {
+                        if !outputs_match(
+                            &interpreted.0,
+                            &new_interpreted.0,
+                            self.eggcc_config.fast_math,
+                        ) {
                             panic!(
                                     "Interpreted outputs differ for {} with optimize_egglog={} and optimize_llvm={}.",
                                     self.name(), optimize_egglog, optimize_llvm
@@ -1066,6 +1188,63 @@ impl Run {
         Ok(Interpretable::Executable { executable })
     }
 
+    /// Compiles each top-k candidate with timing enabled, runs it on
+    /// the program's arguments, and returns the candidate that took the fewest cycles.
+    /// Every candidate must print the same output.
+    fn fastest_candidate(&self, llvm_level: LLVMOptLevel) -> Result<Program, EggCCError> {
+        let candidates =
+            Run::optimize_bril_top_k(&self.prog_with_args.program, &self.eggcc_config)?;
+        let dir = tempdir().expect("couldn't create temp dir");
+
+        // candidates are checked against the unoptimized program
+        let expected_output = Optimizer::interp_bril(
+            &self.prog_with_args.program,
+            self.prog_with_args.args.clone(),
+            None,
+        );
+        let mut fastest: Option<(u64, Program)> = None;
+        for (i, candidate) in candidates.into_iter().enumerate() {
+            let candidate_run = Run {
+                output_path: Some(
+                    dir.path()
+                        .join(format!("candidate-{i}"))
+                        .to_str()
+                        .unwrap()
+                        .to_string(),
+                ),
+                ..self.clone()
+            };
+            let executable =
+                candidate_run.run_bril_llvm(candidate.clone(), false, llvm_level, true)?;
+            let (output, cycles) =
+                Optimizer::interp(&executable, self.prog_with_args.args.clone(), None);
+            let cycles = cycles.expect("Timed executables report the cycles they took");
+            if output != expected_output {
+                log::warn!(
+                    "Dropping autotune candidate {i}, it printed {output:?} instead of {expected_output:?}"
+                );
+                continue;
+            }
+            log::info!("Autotune candidate {i} took {cycles} cycles");
+
+            let is_fastest = match &fastest {
+                Some((fewest_cycles, _)) => cycles < *fewest_cycles,
+                None => true,
+            };
+            if is_fastest {
+                fastest = Some((cycles, candidate));
+            }
+        }
+
+        match fastest {
+            Some((_cycles, program)) => Ok(program),
+            None => Err(EggCCError::Autotune(
+                "No autotune candidate printed the same result as the unoptimized program"
+                    .to_string(),
+            )),
+        }
+    }
+
     fn run_bril_llvm(
         &self,
         input_prog: Program,
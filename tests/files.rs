@@ -1,6 +1,6 @@
 use std::{collections::HashSet, ffi::OsStr};
 
-use eggcc::util::{Run, RunMode, TestProgram};
+use eggcc::util::{outputs_match, Run, RunMode, TestProgram};
 use insta::assert_snapshot;
 use libtest_mimic::Trial;
 
@@ -21,8 +21,11 @@ fn generate_tests(glob: &str, slow_test: bool) -> Vec<Trial> {
                 Ok(res) => res,
             };
             if run.interp.should_interp()
-                && result.original_interpreted.as_ref().unwrap()
-                    != result.result_interpreted.as_ref().unwrap()
+                && !outputs_match(
+                    result.original_interpreted.as_ref().unwrap(),
+                    result.result_interpreted.as_ref().unwrap(),
+                    run.eggcc_config.fast_math,
+                )
             {
                 panic!(
                     "Interpreted result does not match expected:\nExpected: {}\nGot: {}",